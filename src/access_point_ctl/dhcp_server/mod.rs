@@ -83,6 +83,9 @@ pub trait DhcpServerCtl {
     /// dnsmasq.stop().unwrap();
     /// ```
     fn stop(&mut self) -> Result<()>;
+
+    /// Checks whether the DHCP server is still running.
+    fn is_running(&mut self) -> bool;
 }
 
 /// Struct to control the dnsmasq process.
@@ -170,6 +173,11 @@ impl<T: ProcessHdlOps> DhcpServerCtl for DnsmasqProc<T> {
         self.process.kill()?;
         Ok(())
     }
+
+    /// Checks whether the dnsmasq process is still running.
+    fn is_running(&mut self) -> bool {
+        self.process.is_running()
+    }
 }
 
 #[cfg(test)]
@@ -288,4 +296,16 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(result.unwrap_err().to_string(), "Invalid interface name");
     }
+
+    #[test]
+    fn test_dnsmasq_is_running() {
+        init_logger();
+        let mut mock_process = MockProcessHdlOps::new();
+
+        mock_process.expect_is_running().times(1).returning(|| true);
+
+        let mut dnsmasq_ctl = DnsmasqProc::new(mock_process);
+
+        assert!(dnsmasq_ctl.is_running());
+    }
 }
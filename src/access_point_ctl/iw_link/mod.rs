@@ -36,6 +36,13 @@ pub trait IwLinkHandler {
     ///
     /// A string slice that holds the name of the interface.
     fn get_if_name(&self) -> &str;
+
+    /// Deletes the wireless link, the link is unusable after this call.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the link could not be deleted.
+    fn delete_link(&mut self) -> Result<()>;
 }
 
 /// Struct representing a wireless link.
@@ -44,6 +51,7 @@ pub struct IwLink<T: WirelessDriver> {
     if_name: String,
     current_addr: Option<String>,
     if_idx: InterfaceIndex,
+    is_deleted: bool,
 }
 
 impl<T: WirelessDriver> IwLink<T> {
@@ -89,6 +97,7 @@ impl<T: WirelessDriver> IwLink<T> {
             if_name: if_name.to_owned(),
             current_addr: None,
             if_idx,
+            is_deleted: false,
         })
     }
 }
@@ -110,13 +119,30 @@ impl<T: WirelessDriver> IwLinkHandler for IwLink<T> {
     fn get_if_name(&self) -> &str {
         &self.if_name
     }
+
+    fn delete_link(&mut self) -> Result<()> {
+        if self.is_deleted {
+            warn!("Link with index: {} already deleted", self.if_idx);
+            return Ok(());
+        }
+
+        info!("Deleting link with index: {}", self.if_idx);
+        self.driver.delete_link(self.if_idx)?;
+        self.is_deleted = true;
+
+        Ok(())
+    }
 }
 
 impl<T: WirelessDriver> Drop for IwLink<T> {
-    /// Deletes the wireless link when the `IwLink` object is dropped.
+    /// Deletes the wireless link when the `IwLink` object is dropped, if it
+    /// was not already deleted.
     fn drop(&mut self) {
-        info!("Deleting link with index: {}", self.if_idx);
-        if let Err(error) = self.driver.delete_link(self.if_idx) {
+        if self.is_deleted {
+            return;
+        }
+
+        if let Err(error) = self.delete_link() {
             error!(
                 "Failed to delete link with index: {}, error: {}",
                 self.if_idx, error
@@ -253,6 +279,7 @@ mod tests {
             current_addr: None,
             if_idx: InterfaceIndex(1),
             if_name: "test".to_string(),
+            is_deleted: false,
         };
 
         let result = iw_link.add_ipv4_addr("192.168.1.1");
@@ -283,6 +310,7 @@ mod tests {
             current_addr: None,
             if_idx: InterfaceIndex(1),
             if_name: "test".to_string(),
+            is_deleted: false,
         };
 
         let result = iw_link.add_ipv4_addr("192.168.1.1");
@@ -308,6 +336,7 @@ mod tests {
             if_name: "test".to_string(),
             current_addr: None,
             if_idx: InterfaceIndex(1),
+            is_deleted: false,
         };
 
         drop(iw_link); // Explicitly drop to test the Drop implementation
@@ -331,6 +360,7 @@ mod tests {
             if_name: "test".to_string(),
             current_addr: None,
             if_idx: InterfaceIndex(1),
+            is_deleted: false,
         };
 
         drop(iw_link); // Explicitly drop to test the Drop implementation
@@ -354,6 +384,7 @@ mod tests {
             if_name: "test".to_string(),
             current_addr: Some("192.168.1.1".to_string()),
             if_idx: InterfaceIndex(1),
+            is_deleted: false,
         };
 
         let result = iw_link.add_ipv4_addr("192.168.1.2");
@@ -379,7 +410,35 @@ mod tests {
             if_name: "test".to_string(),
             current_addr: Some("192.168.1.1".to_string()),
             if_idx: InterfaceIndex(1),
+            is_deleted: false,
         };
         assert_eq!(iw_link.get_if_name(), "test");
     }
+
+    #[test]
+    fn test_delete_link_only_once() -> Result<()> {
+        init_logger();
+        let mut mock_driver = MockWirelessDriver::new();
+
+        mock_driver
+            .expect_delete_link()
+            .with(eq(InterfaceIndex(1)))
+            .returning(|_| Ok(()))
+            .times(1);
+
+        let mut iw_link = IwLink {
+            driver: mock_driver,
+            if_name: "test".to_string(),
+            current_addr: None,
+            if_idx: InterfaceIndex(1),
+            is_deleted: false,
+        };
+
+        assert!(iw_link.delete_link().is_ok());
+        assert!(iw_link.delete_link().is_ok());
+
+        drop(iw_link); // The link is not deleted again on drop
+
+        Ok(())
+    }
 }
@@ -10,12 +10,19 @@ use dhcp_server::DhcpIpRange;
 use dhcp_server::DhcpServerCtl;
 use iw_link::IwLinkHandler;
 use log::{error, info};
+use std::sync::{Arc, Mutex};
 use wifi_manager::WifiCredentials;
 use wifi_manager::WifiManagerCtl;
 
 use crate::error::Result;
+use anyhow::anyhow;
+use uuid::Uuid;
+
+#[cfg(test)]
+use mockall::automock;
 
 /// Trait defining the control operations for an access point.
+#[cfg_attr(test, automock)]
 pub trait AccessPointCtl {
     /// Starts the WiFi broadcast.
     ///
@@ -59,8 +66,35 @@ pub trait AccessPointCtl {
     ///
     /// * `Option<WifiCredentials>` - Current WiFi credentials if set.
     fn get_creds(&mut self) -> Option<WifiCredentials>;
+
+    /// Replaces the WiFi password with a new random one, keeping the SSID.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<WifiCredentials>` - The new WiFi credentials.
+    fn rotate_creds(&mut self) -> Result<WifiCredentials>;
+
+    /// Tears down the access point, stopping hostapd and the DHCP server and
+    /// deleting the wireless link, in that order.
+    ///
+    /// Every step is attempted even if a previous one fails.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Result indicating success or failure of all the steps.
+    fn shutdown(&mut self) -> Result<()>;
+
+    /// Checks that the hostapd and DHCP server processes are still running.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if both processes are alive.
+    fn is_healthy(&mut self) -> bool;
 }
 
+/// Access point controller shared between the daemon tasks.
+pub type SharedAp = Arc<Mutex<dyn AccessPointCtl + Send>>;
+
 /// Struct representing the access point controller.
 pub struct ApController<I, D, W>
 where
@@ -91,6 +125,20 @@ impl<I: IwLinkHandler, D: DhcpServerCtl, W: WifiManagerCtl>
     pub fn new(iw_link: I, dhcp_server: D, wifi_manager: W) -> Self {
         Self { iw_link, wifi_manager, dhcp_server, creds: None }
     }
+
+    /// Records the credentials the WiFi manager was started with.
+    ///
+    /// # Arguments
+    ///
+    /// * `creds` - Current WiFi credentials.
+    ///
+    /// # Returns
+    ///
+    /// * `Self` - The `ApController` with the credentials set.
+    pub fn with_creds(mut self, creds: WifiCredentials) -> Self {
+        self.creds = Some(creds);
+        self
+    }
 }
 
 impl<I: IwLinkHandler, D: DhcpServerCtl, W: WifiManagerCtl> AccessPointCtl
@@ -130,6 +178,22 @@ impl<I: IwLinkHandler, D: DhcpServerCtl, W: WifiManagerCtl> AccessPointCtl
         self.creds.clone()
     }
 
+    fn rotate_creds(&mut self) -> Result<WifiCredentials> {
+        let ssid = match &self.creds {
+            Some(creds) => creds.ssid.clone(),
+            None => return Err(anyhow!("WiFi credentials are not set")),
+        };
+
+        //16 hex chars, a valid WPA2 passphrase
+        let mut password = Uuid::new_v4().simple().to_string();
+        password.truncate(16);
+
+        let creds = WifiCredentials { ssid, password };
+        self.set_creds(creds.clone())?;
+
+        Ok(creds)
+    }
+
     fn start_dhcp_server(&mut self, ip_range: DhcpIpRange) -> Result<()> {
         info!("Starting DHCP server with IP range {:?}", ip_range);
 
@@ -145,12 +209,58 @@ impl<I: IwLinkHandler, D: DhcpServerCtl, W: WifiManagerCtl> AccessPointCtl
 
         Ok(())
     }
+
+    fn shutdown(&mut self) -> Result<()> {
+        info!("Shutting down the access point");
+        let mut failed = Vec::new();
+
+        if let Err(error) = self.wifi_manager.turnoff() {
+            error!("Failed to stop the wifi manager, error {}", error);
+            failed.push("wifi manager");
+        }
+
+        if let Err(error) = self.dhcp_server.stop() {
+            error!("Failed to stop DHCP server, error {}", error);
+            failed.push("dhcp server");
+        }
+
+        if let Err(error) = self.iw_link.delete_link() {
+            error!("Failed to delete the wireless link, error {}", error);
+            failed.push("wireless link");
+        }
+
+        if !failed.is_empty() {
+            return Err(anyhow!(
+                "Failed to shut down the access point: {}",
+                failed.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn is_healthy(&mut self) -> bool {
+        let mut healthy = true;
+
+        if !self.wifi_manager.is_running() {
+            error!("The wifi manager process is not running");
+            healthy = false;
+        }
+
+        if !self.dhcp_server.is_running() {
+            error!("The DHCP server process is not running");
+            healthy = false;
+        }
+
+        healthy
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use dhcp_server::MockDhcpServerCtl;
     use iw_link::MockIwLinkHandler;
+    use mockall::Sequence;
     use wifi_manager::MockWifiManagerCtl;
 
     use super::*;
@@ -249,6 +359,49 @@ mod tests {
         assert_eq!(result, Some(creds));
     }
 
+    #[test]
+    fn test_rotate_creds() {
+        init_logger();
+        let mock_iw_link = MockIwLinkHandler::new();
+        let mock_dhcp_server = MockDhcpServerCtl::new();
+        let mut mock_wifi_manager = MockWifiManagerCtl::new();
+
+        mock_wifi_manager
+            .expect_change_creds()
+            .withf(|creds| {
+                creds.ssid == "test_ssid" && creds.password != "test_password"
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut controller = ApController::new(
+            mock_iw_link,
+            mock_dhcp_server,
+            mock_wifi_manager,
+        )
+        .with_creds(WifiCredentials {
+            ssid: "test_ssid".to_string(),
+            password: "test_password".to_string(),
+        });
+
+        let creds = controller.rotate_creds().unwrap();
+        assert_eq!(creds.ssid, "test_ssid");
+        assert_eq!(creds.password.len(), 16);
+        assert_eq!(controller.get_creds(), Some(creds));
+    }
+
+    #[test]
+    fn test_rotate_creds_without_creds() {
+        init_logger();
+        let mut controller = ApController::new(
+            MockIwLinkHandler::new(),
+            MockDhcpServerCtl::new(),
+            MockWifiManagerCtl::new(),
+        );
+
+        assert!(controller.rotate_creds().is_err());
+    }
+
     #[test]
     fn test_start_dhcp_server_success() {
         init_logger();
@@ -281,4 +434,103 @@ mod tests {
         let result = controller.start_dhcp_server(ip_range);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_shutdown_success() {
+        init_logger();
+        let mut mock_iw_link = MockIwLinkHandler::new();
+        let mut mock_dhcp_server = MockDhcpServerCtl::new();
+        let mut mock_wifi_manager = MockWifiManagerCtl::new();
+        let mut seq = Sequence::new();
+
+        mock_wifi_manager
+            .expect_turnoff()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Ok(()));
+        mock_dhcp_server
+            .expect_stop()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Ok(()));
+        mock_iw_link
+            .expect_delete_link()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|| Ok(()));
+
+        let mut controller = ApController::new(
+            mock_iw_link,
+            mock_dhcp_server,
+            mock_wifi_manager,
+        );
+
+        assert!(controller.shutdown().is_ok());
+    }
+
+    #[test]
+    fn test_shutdown_continues_after_failure() {
+        init_logger();
+        let mut mock_iw_link = MockIwLinkHandler::new();
+        let mut mock_dhcp_server = MockDhcpServerCtl::new();
+        let mut mock_wifi_manager = MockWifiManagerCtl::new();
+
+        mock_wifi_manager
+            .expect_turnoff()
+            .times(1)
+            .returning(|| Err(anyhow!("Failed to kill hostapd")));
+        mock_dhcp_server.expect_stop().times(1).returning(|| Ok(()));
+        mock_iw_link.expect_delete_link().times(1).returning(|| Ok(()));
+
+        let mut controller = ApController::new(
+            mock_iw_link,
+            mock_dhcp_server,
+            mock_wifi_manager,
+        );
+
+        let result = controller.shutdown();
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Failed to shut down the access point: wifi manager"
+        );
+    }
+
+    #[test]
+    fn test_is_healthy() {
+        init_logger();
+        let mock_iw_link = MockIwLinkHandler::new();
+        let mut mock_dhcp_server = MockDhcpServerCtl::new();
+        let mut mock_wifi_manager = MockWifiManagerCtl::new();
+
+        mock_wifi_manager.expect_is_running().returning(|| true);
+        mock_dhcp_server.expect_is_running().returning(|| true);
+
+        let mut controller = ApController::new(
+            mock_iw_link,
+            mock_dhcp_server,
+            mock_wifi_manager,
+        );
+
+        assert!(controller.is_healthy());
+    }
+
+    #[test]
+    fn test_is_healthy_dhcp_server_dead() {
+        init_logger();
+        let mock_iw_link = MockIwLinkHandler::new();
+        let mut mock_dhcp_server = MockDhcpServerCtl::new();
+        let mut mock_wifi_manager = MockWifiManagerCtl::new();
+
+        mock_wifi_manager.expect_is_running().returning(|| true);
+        mock_dhcp_server.expect_is_running().returning(|| false);
+
+        let mut controller = ApController::new(
+            mock_iw_link,
+            mock_dhcp_server,
+            mock_wifi_manager,
+        );
+
+        assert!(!controller.is_healthy());
+    }
 }
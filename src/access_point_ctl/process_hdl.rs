@@ -29,6 +29,13 @@ pub trait ProcessHdlOps {
     ///
     /// Returns an error if no process is associated with the handler or if the process fails to be killed.
     fn kill(&mut self) -> Result<()>;
+
+    /// Checks whether the associated process is still running.
+    ///
+    /// # Returns
+    ///
+    /// `true` if a process is associated with the handler and has not exited.
+    fn is_running(&mut self) -> bool;
 }
 
 /// Struct to handle process operations.
@@ -81,6 +88,26 @@ impl ProcessHdlOps for ProcessHdl {
         }
         Ok(())
     }
+
+    /// Checks whether the associated process is still running.
+    ///
+    /// # Returns
+    ///
+    /// `true` if a process is associated with the handler and has not exited.
+    fn is_running(&mut self) -> bool {
+        match self.child_process.as_mut().map(|process| process.try_wait()) {
+            Some(Ok(None)) => true,
+            Some(Ok(Some(status))) => {
+                warn!("Process exited with status: {}", status);
+                false
+            }
+            Some(Err(e)) => {
+                error!("Failed to get the process status, error: {}", e);
+                false
+            }
+            None => false,
+        }
+    }
 }
 
 impl Drop for ProcessHdl {
@@ -150,6 +177,33 @@ mod tests {
         assert!(process_hdl.child_process.is_none());
     }
 
+    /// Tests that a spawned process is reported as running until it is killed.
+    #[test]
+    fn test_is_running() {
+        let mut process_hdl = ProcessHdl::handler();
+        assert!(!process_hdl.is_running());
+
+        let mut cmd = Command::new("sleep");
+        cmd.arg("1");
+        process_hdl.spawn(&mut cmd).unwrap();
+        assert!(process_hdl.is_running());
+
+        process_hdl.kill().unwrap();
+        assert!(!process_hdl.is_running());
+    }
+
+    /// Tests that a process that exited on its own is not reported as running.
+    #[test]
+    fn test_is_running_exited_process() {
+        let mut process_hdl = ProcessHdl::handler();
+        let mut cmd = Command::new("true");
+        process_hdl.spawn(&mut cmd).unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        assert!(!process_hdl.is_running());
+    }
+
     /// Tests that calling `kill` when no process is associated does not cause an error.
     #[test]
     fn test_kill_no_process() {
@@ -53,8 +53,8 @@ impl FileHdl {
     /// # Errors
     ///
     /// This function will return an error if the file cannot be created or opened.
-    pub fn from_path(path: &str) -> Self {
-        Self { path: path.into(), file: None }
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        Self { path: path.as_ref().to_path_buf(), file: None }
     }
 
     /// Gets the file object or returns an error.
@@ -5,6 +5,7 @@ use super::super::process_hdl::ProcessHdlOps;
 use super::file_hdl::FileHdlOps;
 use crate::error::Result;
 use log::{info, warn};
+use serde::Serialize;
 use std::process::Command;
 
 #[cfg(test)]
@@ -15,7 +16,7 @@ use mockall::automock;
 ///
 /// * `ssid` - The SSID (name) of the WiFi network.
 /// * `password` - The password for the WiFi network.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct WifiCredentials {
     pub ssid: String,
     pub password: String,
@@ -47,6 +48,13 @@ pub trait HostapdProcCtl {
     ///
     /// * `Result<()>` - Returns Ok(()) if the process stops successfully, otherwise returns an error.
     fn stop(&mut self) -> Result<()>;
+
+    /// Check whether the Hostapd process is still running.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns true if the process is running.
+    fn is_running(&mut self) -> bool;
 }
 
 /// Structure to manage the Hostapd process
@@ -163,6 +171,15 @@ wmm_enabled=1
         self.process.kill()?;
         Ok(())
     }
+
+    /// Check whether the Hostapd process is still running.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Returns true if the process is running.
+    fn is_running(&mut self) -> bool {
+        self.process.is_running()
+    }
 }
 
 #[cfg(test)]
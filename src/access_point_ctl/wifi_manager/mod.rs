@@ -53,6 +53,9 @@ pub trait WifiManagerCtl {
     ///
     /// Returns an error if turning off fails.
     fn turnoff(&mut self) -> Result<()>;
+
+    /// Checks whether the process serving the WiFi is still running.
+    fn is_running(&mut self) -> bool;
 }
 
 /// Struct representing the WiFi manager.
@@ -136,6 +139,10 @@ impl<P: HostapdProcCtl, C: WpaCtlClientOps> WifiManagerCtl
         self.wpa_ctl.disconnect()?;
         Ok(())
     }
+
+    fn is_running(&mut self) -> bool {
+        self.hostapd.is_running()
+    }
 }
 
 #[cfg(test)]
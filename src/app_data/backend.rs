@@ -0,0 +1,343 @@
+//! Storage backends of the persistent database.
+//!
+//! The records are stored by sled (`DiskBasedDb`) or SQLite (`SqliteDb`),
+//! selected in the config. `DiskDb` wraps the selected one so `AppData` and
+//! `EncryptedDb` don't depend on the choice.
+
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::codec::Codec;
+use super::kv_db::{DiskBasedDb, KvDbOps, SchemaType, LEGACY_MIGRATED_KEY};
+use super::quarantine::QuarantinedRecord;
+use super::schemas::unix_timestamp;
+use super::sqlite_db::SqliteDb;
+use super::transaction::Transaction;
+use super::watch::KvWatcher;
+use crate::error::Result;
+
+/// Engine storing the database.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Sled,
+    Sqlite,
+}
+
+/// The persistent database, stored by the selected backend.
+pub enum DiskDb {
+    Sled(DiskBasedDb),
+    Sqlite(SqliteDb),
+}
+
+impl DiskDb {
+    /// Opens the database of the given directory with the selected backend.
+    ///
+    /// A SQLite database takes over the records of a sled database left in
+    /// the same directory, the sled files are removed once they are copied.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database can't be opened, or if the sled
+    /// database can't be migrated.
+    pub fn open_from<P: AsRef<Path>>(
+        path: P, backend: StorageBackend, codec: Codec,
+    ) -> Result<Self> {
+        match backend {
+            StorageBackend::Sled => {
+                Ok(Self::Sled(DiskBasedDb::open_from(path, codec)?))
+            }
+            StorageBackend::Sqlite => {
+                let db = SqliteDb::open_from(&path, codec)?;
+                db.migrate_from_sled(&path)?;
+                Ok(Self::Sqlite(db))
+            }
+        }
+    }
+
+    /// Copies the legacy sled database at `legacy_path` into this one, see
+    /// `DiskBasedDb::migrate_from`.
+    ///
+    /// The legacy database is looked for the first time the database is
+    /// opened, it is never looked for again once that is recorded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the legacy files belong to another user or can be
+    /// written by other users, or if they can't be copied.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the legacy database was migrated.
+    pub fn migrate_from<P: AsRef<Path>>(&self, legacy_path: P) -> Result<bool> {
+        if self.metadata(LEGACY_MIGRATED_KEY)?.is_some() {
+            return Ok(false);
+        }
+
+        let migrated = match self {
+            Self::Sled(db) => db.migrate_from(legacy_path),
+            Self::Sqlite(db) => db.migrate_from_sled(legacy_path),
+        }?;
+
+        self.set_metadata(LEGACY_MIGRATED_KEY, &unix_timestamp().to_string())?;
+        Ok(migrated)
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<String>> {
+        match self {
+            Self::Sled(db) => db.metadata(key),
+            Self::Sqlite(db) => db.metadata(key),
+        }
+    }
+
+    fn set_metadata(&self, key: &str, value: &str) -> Result<()> {
+        match self {
+            Self::Sled(db) => db.set_metadata(key, value),
+            Self::Sqlite(db) => db.set_metadata(key, value),
+        }
+    }
+
+    /// Upgrades every record of a keyspace to the current version of its
+    /// schema.
+    ///
+    /// # Returns
+    ///
+    /// The number of upgraded records.
+    pub fn upgrade_keyspace<ItemType>(&self) -> Result<usize>
+    where
+        ItemType: DeserializeOwned + SchemaType,
+    {
+        match self {
+            Self::Sled(db) => db.upgrade_keyspace::<ItemType>(),
+            Self::Sqlite(db) => db.upgrade_keyspace::<ItemType>(),
+        }
+    }
+}
+
+impl KvDbOps for DiskDb {
+    fn add<ItemType>(&self, key: &str, data: &ItemType) -> Result<()>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.add(key, data),
+            Self::Sqlite(db) => db.add(key, data),
+        }
+    }
+
+    fn read<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.read(key),
+            Self::Sqlite(db) => db.read(key),
+        }
+    }
+
+    fn peek<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.peek(key),
+            Self::Sqlite(db) => db.peek(key),
+        }
+    }
+
+    fn update<ItemType>(&self, key: &str, data: &ItemType) -> Result<()>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.update(key, data),
+            Self::Sqlite(db) => db.update(key, data),
+        }
+    }
+
+    fn delete<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.delete(key),
+            Self::Sqlite(db) => db.delete(key),
+        }
+    }
+
+    fn list<ItemType>(&self) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.list(),
+            Self::Sqlite(db) => db.list(),
+        }
+    }
+
+    fn scan_prefix<ItemType>(
+        &self, prefix: &str,
+    ) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.scan_prefix(prefix),
+            Self::Sqlite(db) => db.scan_prefix(prefix),
+        }
+    }
+
+    fn keys<ItemType>(&self) -> Result<Vec<String>>
+    where
+        ItemType: SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.keys::<ItemType>(),
+            Self::Sqlite(db) => db.keys::<ItemType>(),
+        }
+    }
+
+    fn count<ItemType>(&self) -> Result<usize>
+    where
+        ItemType: SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.count::<ItemType>(),
+            Self::Sqlite(db) => db.count::<ItemType>(),
+        }
+    }
+
+    fn commit(&self, tx: Transaction) -> Result<()> {
+        match self {
+            Self::Sled(db) => db.commit(tx),
+            Self::Sqlite(db) => db.commit(tx),
+        }
+    }
+
+    fn watch<ItemType>(&self) -> Result<KvWatcher<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.watch(),
+            Self::Sqlite(db) => db.watch(),
+        }
+    }
+
+    fn quarantined(&self) -> Result<Vec<QuarantinedRecord>> {
+        match self {
+            Self::Sled(db) => db.quarantined(),
+            Self::Sqlite(db) => db.quarantined(),
+        }
+    }
+
+    fn quarantine_item<ItemType>(
+        &self, key: &str, data: &ItemType, error: anyhow::Error,
+    ) -> Result<bool>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        match self {
+            Self::Sled(db) => db.quarantine_item(key, data, error),
+            Self::Sqlite(db) => db.quarantine_item(key, data, error),
+        }
+    }
+
+    fn drop_quarantined(&self, keyspace: &str, key: &str) -> Result<bool> {
+        match self {
+            Self::Sled(db) => db.drop_quarantined(keyspace, key),
+            Self::Sqlite(db) => db.drop_quarantined(keyspace, key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::app_data::{
+        ConnectionType, EncryptedDb, HostSchema, MobileSchema,
+    };
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "wcd-backend-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_legacy_migration_is_recorded() {
+        init_logger();
+
+        for backend in [StorageBackend::Sled, StorageBackend::Sqlite] {
+            let dir = temp_dir(&format!("{:?}-recorded", backend));
+            let legacy_dir = temp_dir(&format!("{:?}-legacy", backend));
+
+            let db = DiskDb::open_from(&dir, backend, Codec::Bincode).unwrap();
+            assert!(!db.migrate_from(&legacy_dir).unwrap());
+
+            //a legacy database showing up later is never looked at
+            sled::open(&legacy_dir).unwrap().flush().unwrap();
+            assert!(!db.migrate_from(&legacy_dir).unwrap());
+            assert!(legacy_dir.join("db").exists());
+
+            drop(db);
+            fs::remove_dir_all(&dir).unwrap();
+            fs::remove_dir_all(&legacy_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_seal_plain_database() {
+        init_logger();
+
+        for backend in [StorageBackend::Sled, StorageBackend::Sqlite] {
+            let dir = temp_dir(&format!("{:?}", backend));
+            let key_path = dir.with_extension("key");
+            let host = HostSchema {
+                id: "host_1".to_string(),
+                name: "Host".to_string(),
+                connection_type: ConnectionType::AP,
+                registered_mobiles: vec!["mobile_1".to_string()],
+            };
+            let mobile = MobileSchema {
+                id: "mobile_1".to_string(),
+                ..Default::default()
+            };
+
+            let db = DiskDb::open_from(&dir, backend, Codec::Bincode).unwrap();
+            db.add("host_info", &host).unwrap();
+            db.add("mobile_1", &mobile).unwrap();
+
+            //encryption is turned on for the plain database
+            let db = EncryptedDb::open(db, &key_path).unwrap();
+            assert_eq!(db.seal_keyspace::<HostSchema>().unwrap(), 1);
+            assert_eq!(db.seal_keyspace::<MobileSchema>().unwrap(), 1);
+
+            assert!(db.quarantined().unwrap().is_empty());
+            let read = db.read::<HostSchema>("host_info").unwrap().unwrap();
+            assert_eq!(read.registered_mobiles, host.registered_mobiles);
+            assert_eq!(
+                db.read::<MobileSchema>("mobile_1").unwrap(),
+                Some(mobile)
+            );
+
+            drop(db);
+            fs::remove_dir_all(&dir).unwrap();
+            fs::remove_file(&key_path).unwrap();
+        }
+    }
+}
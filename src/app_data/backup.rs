@@ -0,0 +1,244 @@
+//! Export and import of the pairing data.
+//!
+//! The host record, including the host id advertised to the mobiles, and every
+//! registered mobile are written to a versioned JSON document. Importing it on
+//! a new install restores the pairings, so the mobiles don't have to be
+//! provisioned again.
+
+use std::io::{Read, Write};
+
+use anyhow::anyhow;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    AppData, HostSchema, KvDbOps, MobileActivity, MobileSchema, Transaction,
+};
+use crate::error::Result;
+
+/// Version of the backup document written by `AppData::export_to`.
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+/// The pairing data of a host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairingBackup {
+    pub format_version: u32,
+    pub host: HostSchema,
+    pub mobiles: Vec<MobileSchema>,
+}
+
+/// How the imported data is combined with the stored one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Keeps the stored mobiles, the imported ones overwrite those with the
+    /// same id. The stored mobiles must belong to the imported host.
+    Merge,
+    /// Removes every stored mobile before importing.
+    Replace,
+}
+
+impl<Db> AppData<Db>
+where
+    Db: KvDbOps,
+{
+    /// Collects the host record and every registered mobile.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the host information is not found in the data store.
+    pub fn export(&self) -> Result<PairingBackup> {
+        let mobiles = self.data_db.list::<MobileSchema>()?;
+
+        Ok(PairingBackup {
+            format_version: BACKUP_FORMAT_VERSION,
+            host: self.get_host()?,
+            mobiles: mobiles.into_iter().map(|(_, mobile)| mobile).collect(),
+        })
+    }
+
+    /// Writes the pairing data as a JSON document.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data can't be read or written.
+    pub fn export_to(&self, writer: impl Write) -> Result<()> {
+        let backup = self.export()?;
+        serde_json::to_writer_pretty(writer, &backup)?;
+        info!(
+            "Exported host {} with {} mobiles",
+            backup.host.id,
+            backup.mobiles.len()
+        );
+        Ok(())
+    }
+
+    /// Restores the pairing data of a backup in a single transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backup has a newer format, if merging it would
+    /// mix mobiles paired with different hosts, or if the data store fails.
+    pub fn import(
+        &mut self, backup: PairingBackup, mode: ImportMode,
+    ) -> Result<HostSchema> {
+        if backup.format_version > BACKUP_FORMAT_VERSION {
+            return Err(anyhow!(
+                "Unsupported backup format version {}, expected at most {}",
+                backup.format_version,
+                BACKUP_FORMAT_VERSION
+            ));
+        }
+
+        let stored_ids = self.data_db.keys::<MobileSchema>()?;
+        let mut tx = Transaction::new();
+
+        let mut registered_mobiles = match mode {
+            ImportMode::Replace => {
+                for id in &stored_ids {
+                    tx.remove::<MobileSchema>(id).remove::<MobileActivity>(id);
+                }
+                Vec::new()
+            }
+            ImportMode::Merge => {
+                let stored_host =
+                    self.data_db.read::<HostSchema>("host_info")?;
+                if let Some(stored_host) = stored_host {
+                    // The stored mobiles only know the stored host id
+                    if stored_host.id != backup.host.id
+                        && !stored_ids.is_empty()
+                    {
+                        return Err(anyhow!(
+                            "Backup of host {} can't be merged with the \
+                             mobiles of host {}, use the replace mode",
+                            backup.host.id,
+                            stored_host.id
+                        ));
+                    }
+                }
+                stored_ids
+            }
+        };
+
+        for mobile in &backup.mobiles {
+            tx.put(&mobile.id, mobile)?;
+            registered_mobiles.push(mobile.id.clone());
+        }
+
+        // Same order as the mobiles keyspace
+        registered_mobiles.sort();
+        registered_mobiles.dedup();
+
+        let host = HostSchema { registered_mobiles, ..backup.host };
+        tx.put("host_info", &host)?;
+        self.data_db.commit(tx)?;
+
+        info!(
+            "Imported host {} with {} mobiles, {} registered",
+            host.id,
+            backup.mobiles.len(),
+            host.registered_mobiles.len()
+        );
+
+        Ok(host)
+    }
+
+    /// Reads a JSON document written by `export_to` and imports it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the document is not valid or the import fails.
+    pub fn import_from(
+        &mut self, reader: impl Read, mode: ImportMode,
+    ) -> Result<HostSchema> {
+        let backup = serde_json::from_reader(reader)
+            .map_err(|e| anyhow!("Invalid backup document, error: {}", e))?;
+        self.import(backup, mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::{ConnectionType, HostInfo, InMemoryDb};
+    use crate::ble::AppDataStore;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn app_data(name: &str) -> AppData<InMemoryDb> {
+        let host_info = HostInfo {
+            name: name.to_string(),
+            connection_type: ConnectionType::AP,
+        };
+        AppData::new(InMemoryDb::new(), host_info).unwrap()
+    }
+
+    fn mobile(id: &str) -> MobileSchema {
+        MobileSchema {
+            id: id.to_string(),
+            name: format!("name-{}", id),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_export_import_restores_host_id() {
+        init_logger();
+        let mut old = app_data("OldHost");
+        old.add_mobile(&mobile("mobile_1")).unwrap();
+        old.add_mobile(&mobile("mobile_2")).unwrap();
+
+        let mut document = Vec::new();
+        old.export_to(&mut document).unwrap();
+
+        let mut new = app_data("NewHost");
+        let host =
+            new.import_from(document.as_slice(), ImportMode::Replace).unwrap();
+
+        let old_host = old.get_host().unwrap();
+        assert_eq!(host.id, old_host.id);
+        assert_eq!(new.get_host().unwrap().id, old_host.id);
+        assert_eq!(
+            new.get_host().unwrap().registered_mobiles,
+            ["mobile_1", "mobile_2"]
+        );
+        assert_eq!(new.get_mobile("mobile_2").unwrap().name, "name-mobile_2");
+    }
+
+    #[test]
+    fn test_import_merge_and_replace() {
+        init_logger();
+        let mut app_data = app_data("Host");
+        app_data.add_mobile(&mobile("mobile_1")).unwrap();
+
+        let mut backup = app_data.export().unwrap();
+        backup.mobiles = vec![mobile("mobile_2")];
+
+        let host = app_data.import(backup.clone(), ImportMode::Merge).unwrap();
+        assert_eq!(host.registered_mobiles, ["mobile_1", "mobile_2"]);
+
+        let host =
+            app_data.import(backup.clone(), ImportMode::Replace).unwrap();
+        assert_eq!(host.registered_mobiles, ["mobile_2"]);
+        assert!(app_data.get_mobile("mobile_1").is_err());
+
+        //the stored mobiles are paired with another host id
+        backup.host.id = "other_host".to_string();
+        assert!(app_data.import(backup.clone(), ImportMode::Merge).is_err());
+        assert_eq!(app_data.list_mobiles().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_import_rejects_newer_format() {
+        init_logger();
+        let mut app_data = app_data("Host");
+        let mut backup = app_data.export().unwrap();
+        backup.format_version = BACKUP_FORMAT_VERSION + 1;
+
+        assert!(app_data.import(backup, ImportMode::Replace).is_err());
+        assert!(app_data
+            .import_from("{\"host\": 1}".as_bytes(), ImportMode::Merge)
+            .is_err());
+    }
+}
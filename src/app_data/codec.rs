@@ -0,0 +1,135 @@
+//! Encodings of the record payloads.
+//!
+//! The codec is chosen when a database is created and recorded in it, see
+//! `DiskBasedDb::open_from`. Bincode is the compact default, JSON makes
+//! the records readable with standard tools and CBOR is a compact
+//! self-describing alternative.
+
+use std::fmt;
+
+use anyhow::anyhow;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::error::Result;
+
+/// Encoding of the payload of the records.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Bincode,
+    Json,
+    Cbor,
+}
+
+impl Codec {
+    /// Returns the name recorded in the database.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Bincode => "bincode",
+            Codec::Json => "json",
+            Codec::Cbor => "cbor",
+        }
+    }
+
+    /// Returns the codec recorded with the given name.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name is not a known codec.
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "bincode" => Ok(Codec::Bincode),
+            "json" => Ok(Codec::Json),
+            "cbor" => Ok(Codec::Cbor),
+            _ => Err(anyhow!("Unknown codec: {}", name)),
+        }
+    }
+
+    /// Checks that a database encoded with `stored` can be opened with this
+    /// codec.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the codecs differ.
+    pub fn check_stored(&self, stored: Codec) -> Result<()> {
+        if stored != *self {
+            return Err(anyhow!(
+                "Database is encoded with {}, it can't be opened with {}",
+                stored,
+                self
+            ));
+        }
+        Ok(())
+    }
+
+    /// Encodes an item.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the item can't be serialized.
+    pub fn serialize<T: Serialize>(&self, item: &T) -> Result<Vec<u8>> {
+        match self {
+            Codec::Bincode => Ok(bincode::serialize(item)?),
+            Codec::Json => Ok(serde_json::to_vec(item)?),
+            Codec::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::into_writer(item, &mut payload)
+                    .map_err(|e| anyhow!("CBOR encoding failed: {}", e))?;
+                Ok(payload)
+            }
+        }
+    }
+
+    /// Decodes an item.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the payload is not a valid encoding of `T`.
+    pub fn deserialize<T: DeserializeOwned>(
+        &self, payload: &[u8],
+    ) -> Result<T> {
+        match self {
+            Codec::Bincode => Ok(bincode::deserialize(payload)?),
+            Codec::Json => Ok(serde_json::from_slice(payload)?),
+            Codec::Cbor => ciborium::from_reader(payload)
+                .map_err(|e| anyhow!("CBOR decoding failed: {}", e)),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::{ConnectionType, HostSchema};
+
+    #[test]
+    fn test_round_trip() {
+        let host = HostSchema {
+            id: "host_id".to_string(),
+            name: "Host".to_string(),
+            connection_type: ConnectionType::AP,
+            registered_mobiles: vec!["mobile_1".to_string()],
+        };
+
+        for codec in [Codec::Bincode, Codec::Json, Codec::Cbor] {
+            let payload = codec.serialize(&host).unwrap();
+            let decoded: HostSchema = codec.deserialize(&payload).unwrap();
+            assert_eq!(decoded.registered_mobiles, host.registered_mobiles);
+            assert_eq!(Codec::from_name(codec.name()).unwrap(), codec);
+        }
+
+        let payload = Codec::Json.serialize(&host).unwrap();
+        assert!(String::from_utf8(payload).unwrap().contains("\"host_id\""));
+        assert!(Codec::Cbor.deserialize::<HostSchema>(b"{}").is_err());
+        assert!(Codec::from_name("xml").is_err());
+    }
+}
@@ -0,0 +1,648 @@
+//! Encryption at rest of the records of any `KvDbOps`.
+//!
+//! `EncryptedDb` seals every record with XChaCha20-Poly1305 before it reaches
+//! the inner database. The keyspace and the key of the record are bound as
+//! associated data, so a record that is modified or moved to another key fails
+//! to open on `read` and is quarantined like the records the inner database
+//! can't decode. The key is kept in a file readable only by its owner,
+//! outside of the database directory.
+//!
+//! The plain records of a database without encryption are sealed once, when
+//! the encryption is turned on. A marker is then sealed in the database, so
+//! the plain records written later are quarantined instead of trusted.
+//!
+//! The sealed record stores the versioned record of the item, so outdated
+//! records are still upgraded when read, see the `record` module. The sealed
+//! records are always bincode, the codec of the inner database only encodes
+//! the nonce and the ciphertext.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use anyhow::anyhow;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::codec::Codec;
+use super::kv_db::{KvDbOps, SchemaType};
+use super::quarantine::QuarantinedRecord;
+use super::record::{self, Decoded};
+use super::schemas::unix_timestamp;
+use super::transaction::{Transaction, TxOp, TX_CODEC};
+use super::watch::KvWatcher;
+use crate::error::Result;
+
+/// Codec of the sealed records, the one of the transactions so their records
+/// are sealed as they are.
+const PLAINTEXT_CODEC: Codec = TX_CODEC;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+/// Key of the marker recording that the plain records were sealed.
+const SEALED_MARKER_KEY: &str = "plain_records_sealed";
+
+/// Records when the plain records were sealed, see `EncryptedDb::mark_sealed`.
+#[derive(Debug, Serialize, Deserialize)]
+struct SealedMarker {
+    /// Seconds since the unix epoch.
+    sealed_at: u64,
+}
+
+impl SchemaType for SealedMarker {
+    const KEYSPACE_NAME: &'static str = "encryption";
+}
+
+/// A record encrypted with the key of the database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SealedRecord {
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+//encodes the sealed records of a transaction, they are written to the
+//keyspace of the sealed item so the name is never used
+impl SchemaType for SealedRecord {
+    const KEYSPACE_NAME: &'static str = "sealed_records";
+}
+
+/// A sealed record stored in the keyspace of `ItemType`.
+#[derive(Serialize, Deserialize)]
+#[serde(transparent, bound = "")]
+struct Sealed<ItemType> {
+    record: SealedRecord,
+    #[serde(skip)]
+    _item: PhantomData<fn() -> ItemType>,
+}
+
+impl<ItemType> Sealed<ItemType> {
+    fn new(record: SealedRecord) -> Self {
+        Self { record, _item: PhantomData }
+    }
+}
+
+impl<ItemType: SchemaType> SchemaType for Sealed<ItemType> {
+    const KEYSPACE_NAME: &'static str = ItemType::KEYSPACE_NAME;
+}
+
+/// A struct wrapping a database to encrypt its records.
+pub struct EncryptedDb<Db> {
+    inner: Db,
+    cipher: XChaCha20Poly1305,
+}
+
+impl<Db: KvDbOps> EncryptedDb<Db> {
+    /// Wraps `inner` with the key stored at `key_path`, a new key is created
+    /// if the file doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the key file can be accessed by other users or if
+    /// it can't be read or created.
+    pub fn open<P: AsRef<Path>>(inner: Db, key_path: P) -> Result<Self> {
+        let key = load_or_create_key(key_path.as_ref())?;
+        Ok(Self::with_key(inner, &key))
+    }
+
+    fn with_key(inner: Db, key: &Key) -> Self {
+        Self { inner, cipher: XChaCha20Poly1305::new(key) }
+    }
+
+    /// Encrypts the plain records left by a database without encryption and
+    /// upgrades the outdated sealed records. Once the database is marked as
+    /// sealed, the plain records are quarantined instead.
+    ///
+    /// Returns the number of records encrypted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a record is neither a valid sealed record nor a
+    /// plain one, or if the inner database fails.
+    pub fn seal_keyspace<ItemType>(&self) -> Result<usize>
+    where
+        ItemType: Serialize + DeserializeOwned + SchemaType + 'static,
+    {
+        if self.is_sealed()? {
+            //reading the records upgrades them and quarantines the plain ones
+            for key in self.inner.keys::<ItemType>()? {
+                self.read::<ItemType>(&key)?;
+            }
+            return Ok(0);
+        }
+
+        let mut sealed = 0;
+
+        for key in self.inner.keys::<ItemType>()? {
+            //peeking doesn't quarantine the plain records, a plain record
+            //may decode as a sealed one so only an authenticated record is
+            //sealed, reading it also upgrades it
+            if self.peek::<ItemType>(&key).is_ok() {
+                self.read::<ItemType>(&key)?;
+                continue;
+            }
+
+            let Some(item) =
+                self.inner.peek::<ItemType>(&key).map_err(|e| {
+                    anyhow!(
+                        "Record {} of {} can't be opened, error: {}",
+                        key,
+                        ItemType::KEYSPACE_NAME,
+                        e
+                    )
+                })?
+            else {
+                continue;
+            };
+
+            self.add(&key, &item)?;
+            sealed += 1;
+        }
+
+        if sealed > 0 {
+            info!(
+                "Encrypted {} plain records in keyspace: {}",
+                sealed,
+                ItemType::KEYSPACE_NAME
+            );
+        }
+
+        Ok(sealed)
+    }
+
+    /// Records that the plain records were sealed, `seal_keyspace` doesn't
+    /// seal the plain records anymore.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the marker can't be read or written.
+    pub fn mark_sealed(&self) -> Result<()> {
+        if self.is_sealed()? {
+            return Ok(());
+        }
+
+        self.add(
+            SEALED_MARKER_KEY,
+            &SealedMarker { sealed_at: unix_timestamp() },
+        )?;
+        info!("Database sealed, plain records won't be trusted anymore");
+        Ok(())
+    }
+
+    fn is_sealed(&self) -> Result<bool> {
+        Ok(self.read::<SealedMarker>(SEALED_MARKER_KEY)?.is_some())
+    }
+
+    fn seal(
+        &self, keyspace: &str, key: &str, plaintext: &[u8],
+    ) -> Result<SealedRecord> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(keyspace, key);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad: &aad })
+            .map_err(|_| anyhow!("Failed to encrypt record {}", key))?;
+
+        Ok(SealedRecord { nonce: nonce.into(), ciphertext })
+    }
+
+    //opens a sealed record, sealing it again if it was upgraded, the record
+    //is quarantined if it fails authentication or can't be decoded
+    fn open_item<ItemType>(
+        &self, key: &str, sealed: &Sealed<ItemType>,
+    ) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let plaintext = match decrypt(&self.cipher, key, sealed) {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                self.inner.quarantine_item(key, sealed, e)?;
+                return Ok(None);
+            }
+        };
+
+        let decoded =
+            match record::decode::<ItemType>(PLAINTEXT_CODEC, &plaintext) {
+                Ok(decoded) => decoded,
+                Err(e) if !record::is_newer::<ItemType>(&plaintext) => {
+                    self.inner.quarantine_item(key, sealed, e)?;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            };
+
+        if let Some(upgraded) = decoded.upgraded {
+            let record = self.seal(ItemType::KEYSPACE_NAME, key, &upgraded)?;
+            self.inner.update(key, &Sealed::<ItemType>::new(record))?;
+        }
+        Ok(Some(decoded.item))
+    }
+}
+
+fn unseal<ItemType>(
+    cipher: &XChaCha20Poly1305, key: &str, sealed: &Sealed<ItemType>,
+) -> Result<Decoded<ItemType>>
+where
+    ItemType: DeserializeOwned + SchemaType,
+{
+    record::decode(PLAINTEXT_CODEC, &decrypt(cipher, key, sealed)?)
+}
+
+fn decrypt<ItemType: SchemaType>(
+    cipher: &XChaCha20Poly1305, key: &str, sealed: &Sealed<ItemType>,
+) -> Result<Vec<u8>> {
+    let aad = associated_data(ItemType::KEYSPACE_NAME, key);
+    cipher
+        .decrypt(
+            XNonce::from_slice(&sealed.record.nonce),
+            Payload { msg: &sealed.record.ciphertext, aad: &aad },
+        )
+        .map_err(|_| {
+            anyhow!(
+                "Record {} of {} failed authentication, it was tampered with \
+                 or sealed with another key",
+                key,
+                ItemType::KEYSPACE_NAME
+            )
+        })
+}
+
+//binds the record to its place in the database
+fn associated_data(keyspace: &str, key: &str) -> Vec<u8> {
+    [keyspace.as_bytes(), b"\0", key.as_bytes()].concat()
+}
+
+fn load_or_create_key(path: &Path) -> Result<Key> {
+    if path.exists() {
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(anyhow!(
+                "Key file {:?} must only be accessible by its owner, mode {:o}",
+                path,
+                mode & 0o777
+            ));
+        }
+
+        let key = fs::read(path)?;
+        if key.len() != KEY_LEN {
+            return Err(anyhow!("Key file {:?} is not a valid key", path));
+        }
+        return Ok(*Key::from_slice(&key));
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+
+    let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(&key)?;
+    info!("Created database key file {:?}", path);
+
+    Ok(key)
+}
+
+impl<Db: KvDbOps> KvDbOps for EncryptedDb<Db> {
+    fn add<ItemType>(&self, key: &str, data: &ItemType) -> Result<()>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        let record = self.seal(
+            ItemType::KEYSPACE_NAME,
+            key,
+            &record::encode(PLAINTEXT_CODEC, data)?,
+        )?;
+        self.inner.add(key, &Sealed::<ItemType>::new(record))
+    }
+
+    fn read<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        match self.inner.read::<Sealed<ItemType>>(key)? {
+            Some(sealed) => self.open_item(key, &sealed),
+            None => Ok(None),
+        }
+    }
+
+    fn peek<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        match self.inner.peek::<Sealed<ItemType>>(key)? {
+            Some(sealed) => Ok(Some(unseal(&self.cipher, key, &sealed)?.item)),
+            None => Ok(None),
+        }
+    }
+
+    fn update<ItemType>(&self, key: &str, data: &ItemType) -> Result<()>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        self.add(key, data)
+    }
+
+    fn delete<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let Some(sealed) = self.inner.delete::<Sealed<ItemType>>(key)? else {
+            return Ok(None);
+        };
+
+        //the record is already deleted, a record that can't be opened is
+        //only reported
+        match unseal(&self.cipher, key, &sealed) {
+            Ok(decoded) => Ok(Some(decoded.item)),
+            Err(e) => {
+                warn!("Deleted record that can't be opened, error: {:?}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    fn list<ItemType>(&self) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        self.scan_prefix("")
+    }
+
+    fn scan_prefix<ItemType>(
+        &self, prefix: &str,
+    ) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        self.inner
+            .scan_prefix::<Sealed<ItemType>>(prefix)?
+            .into_iter()
+            .filter_map(|(key, sealed)| {
+                self.open_item(&key, &sealed)
+                    .map(|item| item.map(|item| (key, item)))
+                    .transpose()
+            })
+            .collect()
+    }
+
+    fn keys<ItemType>(&self) -> Result<Vec<String>>
+    where
+        ItemType: SchemaType + 'static,
+    {
+        self.inner.keys::<ItemType>()
+    }
+
+    fn count<ItemType>(&self) -> Result<usize>
+    where
+        ItemType: SchemaType + 'static,
+    {
+        self.inner.count::<ItemType>()
+    }
+
+    fn commit(&self, tx: Transaction) -> Result<()> {
+        let mut sealed_tx = Transaction::new();
+
+        for op in tx.into_ops() {
+            let op = match op {
+                TxOp::Put { keyspace, key, record, .. } => {
+                    let sealed = self.seal(keyspace, &key, &record)?;
+                    TxOp::put(keyspace, &key, &sealed)?
+                }
+                remove => remove,
+            };
+            sealed_tx.push(op);
+        }
+
+        self.inner.commit(sealed_tx)
+    }
+
+    fn watch<ItemType>(&self) -> Result<KvWatcher<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let cipher = self.cipher.clone();
+        Ok(self
+            .inner
+            .watch::<Sealed<ItemType>>()?
+            .map(move |key, sealed| Ok(unseal(&cipher, key, &sealed)?.item)))
+    }
+
+    //the item is sealed again with a new nonce, so the record is only
+    //quarantined if its plaintext is the one of the item
+    fn quarantine_item<ItemType>(
+        &self, key: &str, data: &ItemType, error: anyhow::Error,
+    ) -> Result<bool>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        let Some(sealed) = self.inner.peek::<Sealed<ItemType>>(key)? else {
+            return Ok(false);
+        };
+        let plaintext = decrypt(&self.cipher, key, &sealed)?;
+        if plaintext != record::encode(PLAINTEXT_CODEC, data)? {
+            return Ok(false);
+        }
+        self.inner.quarantine_item(key, &sealed, error)
+    }
+
+    fn quarantined(&self) -> Result<Vec<QuarantinedRecord>> {
+        self.inner.quarantined()
+    }
+
+    fn drop_quarantined(&self, keyspace: &str, key: &str) -> Result<bool> {
+        self.inner.drop_quarantined(keyspace, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::watch::KvEvent;
+    use crate::app_data::{HostSchema, InMemoryDb, MobileSchema};
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn encrypted_db() -> EncryptedDb<InMemoryDb> {
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        EncryptedDb::with_key(InMemoryDb::new(), &key)
+    }
+
+    fn mobile(id: &str) -> MobileSchema {
+        MobileSchema {
+            id: id.to_string(),
+            name: "SecretPhoneName".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_records_are_encrypted() {
+        init_logger();
+        let db = encrypted_db();
+
+        db.add("mobile_1", &mobile("mobile_1")).unwrap();
+        let mut tx = Transaction::new();
+        tx.put("mobile_2", &mobile("mobile_2"))
+            .unwrap()
+            .remove::<MobileSchema>("mobile_1");
+        db.commit(tx).unwrap();
+
+        let sealed =
+            db.inner.read::<Sealed<MobileSchema>>("mobile_2").unwrap().unwrap();
+        let name = b"SecretPhoneName";
+        assert!(!sealed
+            .record
+            .ciphertext
+            .windows(name.len())
+            .any(|w| w == name));
+
+        assert_eq!(db.keys::<MobileSchema>().unwrap(), ["mobile_2"]);
+        assert_eq!(
+            db.list::<MobileSchema>().unwrap()[0].1.name,
+            "SecretPhoneName"
+        );
+        assert!(db.delete::<MobileSchema>("mobile_2").unwrap().is_some());
+        assert_eq!(db.count::<MobileSchema>().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        init_logger();
+        let db = encrypted_db();
+        db.add("mobile_1", &mobile("mobile_1")).unwrap();
+        db.add("mobile_2", &mobile("mobile_2")).unwrap();
+
+        let mut sealed =
+            db.inner.read::<Sealed<MobileSchema>>("mobile_1").unwrap().unwrap();
+
+        //a record moved to another key
+        db.inner.update("mobile_2", &sealed).unwrap();
+        assert!(db.read::<MobileSchema>("mobile_2").unwrap().is_none());
+
+        //a modified record
+        sealed.record.ciphertext[0] ^= 1;
+        db.inner.update("mobile_1", &sealed).unwrap();
+        db.add("mobile_3", &mobile("mobile_3")).unwrap();
+        let listed = db.list::<MobileSchema>().unwrap();
+        assert_eq!(listed, [("mobile_3".to_string(), mobile("mobile_3"))]);
+
+        //an authenticated record that can't be decoded
+        let record = db.seal(MobileSchema::KEYSPACE_NAME, "mobile_4", b"bad");
+        let garbage = Sealed::<MobileSchema>::new(record.unwrap());
+        db.inner.add("mobile_4", &garbage).unwrap();
+        assert!(db.read::<MobileSchema>("mobile_4").unwrap().is_none());
+
+        let quarantined = db.quarantined().unwrap();
+        let keys: Vec<&str> =
+            quarantined.iter().map(|record| record.key.as_str()).collect();
+        assert_eq!(keys, ["mobile_1", "mobile_2", "mobile_4"]);
+        assert_eq!(
+            quarantined[0].record,
+            record::encode(TX_CODEC, &sealed).unwrap()
+        );
+        assert!(quarantined[0].error.contains("failed authentication"));
+        assert_eq!(db.keys::<MobileSchema>().unwrap(), ["mobile_3"]);
+
+        //a modified record is still deleted
+        db.inner.add("mobile_5", &sealed).unwrap();
+        assert!(db.delete::<MobileSchema>("mobile_5").unwrap().is_none());
+        assert_eq!(db.keys::<MobileSchema>().unwrap(), ["mobile_3"]);
+    }
+
+    #[tokio::test]
+    async fn test_watch_unseals_records() {
+        init_logger();
+        let db = encrypted_db();
+        let mut events = db.watch::<MobileSchema>().unwrap();
+
+        //a record that fails authentication is skipped
+        let sealed = Sealed::<MobileSchema>::new(SealedRecord {
+            nonce: [0; NONCE_LEN],
+            ciphertext: vec![0; 32],
+        });
+        db.inner.add("forged", &sealed).unwrap();
+        db.add("mobile_1", &mobile("mobile_1")).unwrap();
+
+        assert_eq!(
+            events.recv().await,
+            Some(KvEvent::Put("mobile_1".to_string(), mobile("mobile_1")))
+        );
+    }
+
+    #[test]
+    fn test_seal_plain_records() {
+        init_logger();
+        let db = encrypted_db();
+        db.inner.add("mobile_1", &mobile("mobile_1")).unwrap();
+        db.add("mobile_2", &mobile("mobile_2")).unwrap();
+
+        assert_eq!(db.seal_keyspace::<MobileSchema>().unwrap(), 1);
+        assert_eq!(db.seal_keyspace::<MobileSchema>().unwrap(), 0);
+        assert_eq!(db.list::<MobileSchema>().unwrap().len(), 2);
+        assert_eq!(db.seal_keyspace::<HostSchema>().unwrap(), 0);
+        assert!(db.quarantined().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_plain_records_after_sealing_are_quarantined() {
+        init_logger();
+        let db = encrypted_db();
+        db.inner.add("mobile_1", &mobile("mobile_1")).unwrap();
+        assert_eq!(db.seal_keyspace::<MobileSchema>().unwrap(), 1);
+        db.mark_sealed().unwrap();
+        db.mark_sealed().unwrap();
+
+        //a plain record planted once the database is sealed
+        db.inner.add("mobile_2", &mobile("mobile_2")).unwrap();
+        assert_eq!(db.seal_keyspace::<MobileSchema>().unwrap(), 0);
+
+        let quarantined = db.quarantined().unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].key, "mobile_2");
+        assert_eq!(db.keys::<MobileSchema>().unwrap(), ["mobile_1"]);
+    }
+
+    #[test]
+    fn test_key_file() {
+        init_logger();
+        let dir = std::env::temp_dir()
+            .join(format!("wcd-encrypted-db-{}", std::process::id()));
+        let key_path = dir.join("db.key");
+
+        let db = EncryptedDb::open(InMemoryDb::new(), &key_path).unwrap();
+        assert_eq!(
+            fs::metadata(&key_path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        db.add("mobile_1", &mobile("mobile_1")).unwrap();
+
+        //the same key opens the records
+        let db = EncryptedDb::open(db.inner, &key_path).unwrap();
+        assert!(db.read::<MobileSchema>("mobile_1").unwrap().is_some());
+
+        //another key can't
+        let other_key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let db = EncryptedDb::with_key(db.inner, &other_key);
+        assert!(db.read::<MobileSchema>("mobile_1").unwrap().is_none());
+        assert_eq!(db.quarantined().unwrap().len(), 1);
+
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o644))
+            .unwrap();
+        assert!(EncryptedDb::open(InMemoryDb::new(), &key_path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,7 +3,8 @@
 //! It defines traits for schema types and database operations, and implements these
 //! traits for a disk-based key-value database. The database operations include adding,
 //! reading, updating, and deleting items, with support for serialization and deserialization
-//! using `bincode`.
+//! using the `Codec` of the database, `bincode` by default. Records carry the version of
+//! their schema and outdated records are upgraded when read, see the `record` module.
 //!
 //! # Traits
 //!
@@ -18,6 +19,7 @@
 //!
 //! ```rust
 //! use crate::app_data::kv_db::{DiskBasedDb, KvDbOps, SchemaType};
+//! use crate::app_data::Codec;
 //! use serde::{Serialize, Deserialize};
 //!
 //! #[derive(Serialize, Deserialize)]
@@ -31,7 +33,7 @@
 //! }
 //!
 //! fn main() -> Result<(), Box<dyn std::error::Error>> {
-//!     let db = DiskBasedDb::open_from("my_db_path")?;
+//!     let db = DiskBasedDb::open_from("my_db_path", Codec::Bincode)?;
 //!     let data = MyData {
 //!         field1: "value".to_string(),
 //!         field2: 42,
@@ -44,13 +46,39 @@
 //! }
 //! ```
 
+use super::codec::Codec;
+use super::quarantine::{QuarantinedRecord, QUARANTINE_KEYSPACE};
+use super::record::{self, MigrationStep};
+use super::transaction::{Transaction, TxOp};
+use super::watch::{KvWatcher, RawEvent};
 use crate::error::Result;
-use bincode;
-use log::info;
+use anyhow::anyhow;
+use log::{error, info};
 use serde::{de::DeserializeOwned, Serialize};
-use sled;
+use sled::{
+    self,
+    transaction::{ConflictableTransactionResult, Transactional},
+};
+use std::fs::{self, File, TryLockError};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
 use std::path::Path;
 
+/// Name of the lock file inside the database directory.
+pub(super) const LOCK_FILE_NAME: &str = "webcam-direct.lock";
+
+/// Name of the default tree of sled, it is never used by the application.
+pub(super) const SLED_DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// Name of the tree describing the database itself.
+pub(super) const METADATA_TREE: &[u8] = b"__webcam_direct_metadata";
+
+/// Key of the codec of the records in the metadata tree.
+pub(super) const CODEC_KEY: &str = "codec";
+
+/// Key recording when the legacy database was looked for, in the metadata
+/// tree.
+pub(super) const LEGACY_MIGRATED_KEY: &str = "legacy_migrated";
+
 #[cfg(test)]
 use mockall::automock;
 
@@ -58,6 +86,14 @@ use mockall::automock;
 /// extend to add more schema metadata
 pub trait SchemaType {
     const KEYSPACE_NAME: &'static str;
+
+    /// Version of the layout of the records written by this build, bump it
+    /// with every change of the layout and add the step to `MIGRATIONS`.
+    const VERSION: u32 = 1;
+
+    /// Steps to upgrade the records to `VERSION`, the step at index `i`
+    /// upgrades a record from version `i + 1` to `i + 2`.
+    const MIGRATIONS: &'static [MigrationStep] = &[];
 }
 
 /// A trait defining operations for a key-value database.
@@ -86,6 +122,21 @@ pub trait KvDbOps: Send + Sync + 'static {
     where
         ItemType: DeserializeOwned + SchemaType + 'static;
 
+    /// Reads an item as it is stored, the record is neither upgraded nor
+    /// quarantined.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A string slice that holds the key.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing the item if found, or an error if the record
+    /// can't be decoded as `ItemType`.
+    fn peek<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static;
+
     /// Updates an item in the database.
     ///
     /// # Arguments
@@ -108,28 +159,479 @@ pub trait KvDbOps: Send + Sync + 'static {
     fn delete<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
     where
         ItemType: DeserializeOwned + SchemaType + 'static;
+
+    /// Lists every item of the keyspace of `ItemType`, ordered by key.
+    ///
+    /// # Returns
+    ///
+    /// A `Vec` with the key and the item of every entry.
+    fn list<ItemType>(&self) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static;
+
+    /// Lists the items whose key starts with the given prefix, ordered by key.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - A string slice that holds the key prefix.
+    ///
+    /// # Returns
+    ///
+    /// A `Vec` with the key and the item of every matching entry.
+    fn scan_prefix<ItemType>(
+        &self, prefix: &str,
+    ) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static;
+
+    /// Lists the keys of the keyspace of `ItemType` without decoding them.
+    fn keys<ItemType>(&self) -> Result<Vec<String>>
+    where
+        ItemType: SchemaType + 'static;
+
+    /// Returns the number of items in the keyspace of `ItemType`.
+    fn count<ItemType>(&self) -> Result<usize>
+    where
+        ItemType: SchemaType + 'static;
+
+    /// Commits the writes of a transaction, either all of them are applied or
+    /// none is.
+    ///
+    /// # Arguments
+    ///
+    /// * `tx` - The transaction to commit.
+    fn commit(&self, tx: Transaction) -> Result<()>;
+
+    /// Watches the writes of the keyspace of `ItemType` made from now on,
+    /// including those of committed transactions.
+    ///
+    /// # Returns
+    ///
+    /// A `KvWatcher` yielding the writes, it ends when the database is
+    /// dropped.
+    fn watch<ItemType>(&self) -> Result<KvWatcher<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static;
+
+    /// Moves a record to quarantine, for the records that are decoded but
+    /// can't be opened by a wrapper of the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A string slice that holds the key.
+    /// * `data` - The record as it was read, it is left in place if it was
+    ///   overwritten since.
+    /// * `error` - The reason the record can't be opened.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the record was quarantined.
+    fn quarantine_item<ItemType>(
+        &self, key: &str, data: &ItemType, error: anyhow::Error,
+    ) -> Result<bool>
+    where
+        ItemType: Serialize + SchemaType + 'static;
+
+    /// Lists the records moved to quarantine because they couldn't be
+    /// decoded, see the `quarantine` module.
+    fn quarantined(&self) -> Result<Vec<QuarantinedRecord>>;
+
+    /// Drops a quarantined record.
+    ///
+    /// # Arguments
+    ///
+    /// * `keyspace` - The keyspace the record was moved from.
+    /// * `key` - The key of the record in its keyspace.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the record was quarantined.
+    fn drop_quarantined(&self, keyspace: &str, key: &str) -> Result<bool>;
 }
 
 /// A struct representing a disk-based key-value database.
 pub struct DiskBasedDb {
     db: sled::Db,
+    codec: Codec,
+    //released after the database is closed
+    _lock: File,
 }
 
 impl DiskBasedDb {
     /// Opens a disk-based database from the given path.
     ///
+    /// The directory is created if needed and only accessible by the owner, a
+    /// lock file inside prevents two processes from opening the same database.
+    ///
+    /// A new database records the codec of its records, an existing one must
+    /// have been created with the same codec. The databases created before
+    /// the codec was recorded are bincode.
+    ///
     /// # Arguments
     ///
     /// * `path` - A reference to the path where the database is located.
+    /// * `codec` - The encoding of the records.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `DiskBasedDb` instance if successful, or an
+    /// error if the database was created with another codec.
+    pub fn open_from<P: AsRef<Path>>(
+        path: P, codec: Codec,
+    ) -> Result<DiskBasedDb> {
+        let path = path.as_ref();
+        let lock = lock_db_dir(path)?;
+
+        let db = DiskBasedDb { db: sled::open(path)?, codec, _lock: lock };
+        db.check_codec()?;
+        info!("Database opened, codec: {}", codec);
+        Ok(db)
+    }
+
+    //records the codec of a new database, or checks the recorded one
+    fn check_codec(&self) -> Result<()> {
+        let stored = match recorded_codec(&self.db)? {
+            Some(codec) => codec,
+            None if self.is_empty()? => self.codec,
+            //written before the codec was recorded
+            None => Codec::Bincode,
+        };
+        self.codec.check_stored(stored)?;
+
+        self.db
+            .open_tree(METADATA_TREE)?
+            .insert(CODEC_KEY, self.codec.name())?;
+        Ok(())
+    }
+
+    /// Copies every keyspace of the legacy database at `legacy_path` into this
+    /// one and removes the legacy files.
+    ///
+    /// Nothing is done if there is no legacy database or if this database
+    /// already has data, so the migration runs only once.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the legacy database was migrated.
+    pub fn migrate_from<P: AsRef<Path>>(&self, legacy_path: P) -> Result<bool> {
+        let legacy_path = legacy_path.as_ref();
+
+        if !legacy_path.join("conf").is_file()
+            || !legacy_path.join("db").is_file()
+        {
+            return Ok(false);
+        }
+
+        if !self.is_empty()? {
+            info!("Database has data, skipping migration of {:?}", legacy_path);
+            return Ok(false);
+        }
+
+        //the legacy records are copied as they are
+        if self.codec != Codec::Bincode {
+            return Err(anyhow!(
+                "Legacy database {:?} is encoded with bincode, migrate it \
+                 before changing the codec",
+                legacy_path
+            ));
+        }
+
+        check_sled_files(legacy_path)?;
+        info!("Migrating legacy database from {:?}", legacy_path);
+
+        let legacy = sled::open(legacy_path)?;
+        let mut migrated = 0;
+
+        for name in legacy.tree_names() {
+            if name == SLED_DEFAULT_TREE || name == METADATA_TREE {
+                continue;
+            }
+
+            let legacy_tree = legacy.open_tree(&name)?;
+            let tree = self.db.open_tree(&name)?;
+            for item in legacy_tree.iter() {
+                let (key, value) = item?;
+                tree.insert(key, value)?;
+                migrated += 1;
+            }
+        }
+
+        self.db.flush()?;
+        drop(legacy);
+
+        info!("Migrated {} items, removing the legacy database", migrated);
+        remove_sled_files(legacy_path)?;
+
+        Ok(true)
+    }
+
+    /// Upgrades every record of a keyspace to the current version of its
+    /// schema, so the upgrade doesn't wait for the records to be read.
     ///
     /// # Returns
     ///
-    /// A `Result` containing the `DiskBasedDb` instance if successful.
-    pub fn open_from<P: AsRef<Path>>(path: P) -> Result<DiskBasedDb> {
-        let db = sled::open(path)?;
-        info!("Database opened");
-        Ok(DiskBasedDb { db })
+    /// The number of upgraded records.
+    pub fn upgrade_keyspace<ItemType>(&self) -> Result<usize>
+    where
+        ItemType: DeserializeOwned + SchemaType,
+    {
+        let tree = self.db.open_tree(ItemType::KEYSPACE_NAME)?;
+        let mut upgraded = 0;
+
+        for item in tree.iter() {
+            let (key, data) = item?;
+            let decoded = match record::decode::<ItemType>(self.codec, &data) {
+                Ok(decoded) => decoded,
+                Err(e) if !record::is_newer::<ItemType>(&data) => {
+                    self.quarantine(
+                        &tree,
+                        ItemType::KEYSPACE_NAME,
+                        &key,
+                        data,
+                        e,
+                    )?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(record) = decoded.upgraded {
+                let _ = tree.compare_and_swap(key, Some(data), Some(record))?;
+                upgraded += 1;
+            }
+        }
+
+        if upgraded > 0 {
+            info!(
+                "Upgraded {} items in keyspace: {} to version {}",
+                upgraded,
+                ItemType::KEYSPACE_NAME,
+                ItemType::VERSION
+            );
+        }
+
+        Ok(upgraded)
+    }
+
+    //decodes a record of the tree, storing it back if it was upgraded, the
+    //record is quarantined if it can't be decoded
+    fn decode_item<ItemType>(
+        &self, tree: &sled::Tree, key: &[u8], data: sled::IVec,
+    ) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType,
+    {
+        let decoded = match record::decode::<ItemType>(self.codec, &data) {
+            Ok(decoded) => decoded,
+            Err(e) if !record::is_newer::<ItemType>(&data) => {
+                self.quarantine(tree, ItemType::KEYSPACE_NAME, key, data, e)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let Some(upgraded) = decoded.upgraded {
+            info!(
+                "Upgraded item with key: {} in keyspace: {} to version {}",
+                String::from_utf8_lossy(key),
+                ItemType::KEYSPACE_NAME,
+                ItemType::VERSION
+            );
+            //a concurrent write already stored a newer record
+            let _ = tree.compare_and_swap(key, Some(data), Some(upgraded))?;
+        }
+        Ok(Some(decoded.item))
+    }
+
+    //moves an undecodable record to the quarantine tree, unless it was
+    //overwritten in the meantime
+    fn quarantine(
+        &self, tree: &sled::Tree, keyspace: &str, key: &[u8], data: sled::IVec,
+        e: anyhow::Error,
+    ) -> Result<bool> {
+        let name = String::from_utf8_lossy(key).into_owned();
+        error!(
+            "Quarantining item with key: {} in keyspace: {}, error: {:?}",
+            name, keyspace, e
+        );
+
+        let quarantined = QuarantinedRecord::new(keyspace, &name, &data, &e);
+        let quarantine_key = QuarantinedRecord::key_of(keyspace, &name);
+        let quarantined = quarantined.encode()?;
+        let quarantine = self.db.open_tree(QUARANTINE_KEYSPACE)?;
+
+        (tree, &quarantine)
+            .transaction(
+                |(tree, quarantine)| -> ConflictableTransactionResult<bool> {
+                    if tree.get(key)?.as_ref() != Some(&data) {
+                        return Ok(false);
+                    }
+                    tree.remove(key)?;
+                    quarantine.insert(
+                        quarantine_key.as_bytes(),
+                        quarantined.as_slice(),
+                    )?;
+                    Ok(true)
+                },
+            )
+            .map_err(|e| anyhow!("Failed to quarantine {}: {:?}", name, e))
+    }
+
+    /// Reads a value of the metadata tree.
+    pub(super) fn metadata(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .db
+            .open_tree(METADATA_TREE)?
+            .get(key)?
+            .map(|value| String::from_utf8_lossy(&value).into_owned()))
     }
+
+    /// Writes a value of the metadata tree.
+    pub(super) fn set_metadata(&self, key: &str, value: &str) -> Result<()> {
+        self.db.open_tree(METADATA_TREE)?.insert(key, value)?;
+        Ok(())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        for name in self.db.tree_names() {
+            if name != SLED_DEFAULT_TREE
+                && name != METADATA_TREE
+                && !self.db.open_tree(&name)?.is_empty()
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Returns the codec recorded in a sled database, if any.
+pub(super) fn recorded_codec(db: &sled::Db) -> Result<Option<Codec>> {
+    match db.open_tree(METADATA_TREE)?.get(CODEC_KEY)? {
+        Some(name) => {
+            Ok(Some(Codec::from_name(&String::from_utf8_lossy(&name))?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Creates the database directory if needed, only accessible by the owner,
+/// and locks it so two processes can't open the same database.
+///
+/// An existing directory is never changed, it must be owned by the user
+/// running the process and only accessible by them.
+///
+/// The directory stays locked until the returned file is dropped.
+pub(super) fn lock_db_dir(path: &Path) -> Result<File> {
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(path)?;
+
+    let metadata = fs::metadata(path)?;
+    if metadata.mode() & 0o1000 != 0 {
+        return Err(anyhow!(
+            "Database path {:?} is a shared directory, use a dedicated one",
+            path
+        ));
+    }
+    let euid = effective_uid();
+    if metadata.uid() != euid {
+        return Err(anyhow!(
+            "Database path {:?} must be owned by uid {}, it is owned by uid {}",
+            path,
+            euid,
+            metadata.uid()
+        ));
+    }
+    if metadata.mode() & 0o777 != 0o700 {
+        return Err(anyhow!(
+            "Database path {:?} must only be accessible by its owner, mode \
+             {:o}, restrict it with chmod 700",
+            path,
+            metadata.mode() & 0o777
+        ));
+    }
+
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path.join(LOCK_FILE_NAME))?;
+
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(anyhow!(
+                "Database {:?} is in use by another process",
+                path
+            ));
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+
+    Ok(lock)
+}
+
+pub(super) fn key_to_string(key: &[u8]) -> Result<String> {
+    String::from_utf8(key.to_vec()).map_err(|e| anyhow!("Invalid key: {}", e))
+}
+
+fn effective_uid() -> u32 {
+    // SAFETY: geteuid has no preconditions and never fails
+    unsafe { libc::geteuid() }
+}
+
+//the files created by sled, the directory may be shared
+fn is_sled_file(name: &str) -> bool {
+    name == "blobs"
+        || name == "conf"
+        || name == "db"
+        || name.starts_with("snap.")
+}
+
+/// Checks that the sled files at `path` belong to the user running the
+/// process and can't be written by other users, so a database planted in a
+/// shared directory such as /tmp is neither copied nor removed.
+pub(super) fn check_sled_files(path: &Path) -> Result<()> {
+    let euid = effective_uid();
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if !is_sled_file(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+
+        //the link itself, sled would follow it
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if metadata.file_type().is_symlink() {
+            return Err(anyhow!("Sled file {:?} is a link", entry.path()));
+        }
+        if metadata.uid() != euid || metadata.mode() & 0o022 != 0 {
+            return Err(anyhow!(
+                "Sled file {:?} must be owned by uid {} and only writable by \
+                 its owner, uid {} mode {:o}",
+                entry.path(),
+                euid,
+                metadata.uid(),
+                metadata.mode() & 0o777
+            ));
+        }
+    }
+    Ok(())
+}
+
+//removes only the files created by sled, the directory may be shared
+pub(super) fn remove_sled_files(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        if name == "blobs" {
+            fs::remove_dir_all(entry.path())?;
+        } else if is_sled_file(&name) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
 }
 
 impl KvDbOps for DiskBasedDb {
@@ -138,8 +640,7 @@ impl KvDbOps for DiskBasedDb {
         ItemType: Serialize + SchemaType,
     {
         let tree = self.db.open_tree(ItemType::KEYSPACE_NAME)?;
-        let serialized = bincode::serialize(data)?;
-        tree.insert(key, serialized)?;
+        tree.insert(key, record::encode(self.codec, data)?)?;
         info!(
             "Added item with key: {} to keyspace: {}",
             key,
@@ -154,7 +655,10 @@ impl KvDbOps for DiskBasedDb {
     {
         let tree = self.db.open_tree(ItemType::KEYSPACE_NAME)?;
         if let Some(data) = tree.get(key)? {
-            let item: ItemType = bincode::deserialize(&data)?;
+            let Some(item) = self.decode_item(&tree, key.as_bytes(), data)?
+            else {
+                return Ok(None);
+            };
             info!(
                 "Read item with key: {} from keyspace: {}",
                 key,
@@ -170,13 +674,22 @@ impl KvDbOps for DiskBasedDb {
         Ok(None)
     }
 
+    fn peek<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType,
+    {
+        let tree = self.db.open_tree(ItemType::KEYSPACE_NAME)?;
+        tree.get(key)?
+            .map(|data| Ok(record::decode(self.codec, &data)?.item))
+            .transpose()
+    }
+
     fn update<ItemType>(&self, key: &str, data: &ItemType) -> Result<()>
     where
         ItemType: Serialize + SchemaType,
     {
         let tree = self.db.open_tree(ItemType::KEYSPACE_NAME)?;
-        let serialized = bincode::serialize(&data)?;
-        tree.insert(key, serialized)?;
+        tree.insert(key, record::encode(self.codec, data)?)?;
         info!(
             "Updated item with key: {} in keyspace: {}",
             key,
@@ -191,13 +704,19 @@ impl KvDbOps for DiskBasedDb {
     {
         let tree = self.db.open_tree(ItemType::KEYSPACE_NAME)?;
         if let Some(data) = tree.remove(key)? {
-            let item: ItemType = bincode::deserialize(&data)?;
             info!(
                 "Deleted item with key: {} from keyspace: {}",
                 key,
                 ItemType::KEYSPACE_NAME
             );
-            return Ok(Some(item));
+            //the record is removed as asked even if it can't be decoded
+            return match record::decode::<ItemType>(self.codec, &data) {
+                Ok(decoded) => Ok(Some(decoded.item)),
+                Err(e) => {
+                    error!("Deleted item {} can't be decoded: {:?}", key, e);
+                    Ok(None)
+                }
+            };
         }
         info!(
             "Item with key: {} not found in keyspace: {}",
@@ -206,4 +725,546 @@ impl KvDbOps for DiskBasedDb {
         );
         Ok(None)
     }
+
+    fn list<ItemType>(&self) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        self.scan_prefix("")
+    }
+
+    fn scan_prefix<ItemType>(
+        &self, prefix: &str,
+    ) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType,
+    {
+        let tree = self.db.open_tree(ItemType::KEYSPACE_NAME)?;
+        let mut items = Vec::new();
+
+        for entry in tree.scan_prefix(prefix) {
+            let (key, data) = entry?;
+            if let Some(item) = self.decode_item(&tree, &key, data)? {
+                items.push((key_to_string(&key)?, item));
+            }
+        }
+
+        info!(
+            "Scanned {} items with prefix: {:?} in keyspace: {}",
+            items.len(),
+            prefix,
+            ItemType::KEYSPACE_NAME
+        );
+        Ok(items)
+    }
+
+    fn keys<ItemType>(&self) -> Result<Vec<String>>
+    where
+        ItemType: SchemaType,
+    {
+        let tree = self.db.open_tree(ItemType::KEYSPACE_NAME)?;
+        tree.iter().keys().map(|key| key_to_string(&key?)).collect()
+    }
+
+    fn count<ItemType>(&self) -> Result<usize>
+    where
+        ItemType: SchemaType,
+    {
+        Ok(self.db.open_tree(ItemType::KEYSPACE_NAME)?.len())
+    }
+
+    fn commit(&self, tx: Transaction) -> Result<()> {
+        if tx.is_empty() {
+            return Ok(());
+        }
+        let tx = tx.encoded_with(self.codec)?;
+
+        let mut keyspaces: Vec<&str> =
+            tx.ops().iter().map(TxOp::keyspace).collect();
+        keyspaces.sort_unstable();
+        keyspaces.dedup();
+
+        let trees = keyspaces
+            .iter()
+            .map(|keyspace| self.db.open_tree(keyspace))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        trees
+            .as_slice()
+            .transaction(|trees| -> ConflictableTransactionResult<(), ()> {
+                for op in tx.ops() {
+                    let tree = &trees[keyspaces
+                        .binary_search(&op.keyspace())
+                        .expect("keyspace opened for the transaction")];
+                    match op {
+                        TxOp::Put { key, record, .. } => {
+                            tree.insert(key.as_bytes(), record.as_slice())?;
+                        }
+                        TxOp::Remove { key, .. } => {
+                            tree.remove(key.as_bytes())?;
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|e| anyhow!("Failed to commit transaction: {:?}", e))?;
+
+        info!(
+            "Committed transaction with {} writes in keyspaces: {}",
+            tx.ops().len(),
+            keyspaces.join(", ")
+        );
+        Ok(())
+    }
+
+    fn watch<ItemType>(&self) -> Result<KvWatcher<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|e| anyhow!("Watching needs a tokio runtime: {}", e))?;
+        let tree = self.db.open_tree(ItemType::KEYSPACE_NAME)?;
+        let mut subscriber = tree.watch_prefix(vec![]);
+        let (tx, watcher) = KvWatcher::channel(self.codec);
+
+        //the task ends when the watcher or the database is dropped
+        runtime.spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = &mut subscriber => event,
+                    _ = tx.closed() => break,
+                };
+                let Some(event) = event else {
+                    break;
+                };
+
+                let key = String::from_utf8_lossy(event.key()).into_owned();
+                let record = match event {
+                    sled::Event::Insert { value, .. } => Some(value.to_vec()),
+                    sled::Event::Remove { .. } => None,
+                };
+                if tx.send(RawEvent { key, record }).is_err() {
+                    break;
+                }
+            }
+        });
+
+        info!("Watching keyspace: {}", ItemType::KEYSPACE_NAME);
+        Ok(watcher)
+    }
+
+    fn quarantine_item<ItemType>(
+        &self, key: &str, data: &ItemType, error: anyhow::Error,
+    ) -> Result<bool>
+    where
+        ItemType: Serialize + SchemaType,
+    {
+        let tree = self.db.open_tree(ItemType::KEYSPACE_NAME)?;
+        let data = record::encode(self.codec, data)?;
+        self.quarantine(
+            &tree,
+            ItemType::KEYSPACE_NAME,
+            key.as_bytes(),
+            data.into(),
+            error,
+        )
+    }
+
+    fn quarantined(&self) -> Result<Vec<QuarantinedRecord>> {
+        self.db
+            .open_tree(QUARANTINE_KEYSPACE)?
+            .iter()
+            .values()
+            .map(|data| QuarantinedRecord::decode(&data?))
+            .collect()
+    }
+
+    fn drop_quarantined(&self, keyspace: &str, key: &str) -> Result<bool> {
+        let quarantine_key = QuarantinedRecord::key_of(keyspace, key);
+        let dropped = self
+            .db
+            .open_tree(QUARANTINE_KEYSPACE)?
+            .remove(quarantine_key)?
+            .is_some();
+        if dropped {
+            info!("Dropped quarantined item {} of {}", key, keyspace);
+        }
+        Ok(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::watch::KvEvent;
+    use serde::Deserialize;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestSchema {
+        value: String,
+    }
+
+    impl SchemaType for TestSchema {
+        const KEYSPACE_NAME: &'static str = "test_schema";
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct OtherSchema {
+        value: u32,
+    }
+
+    impl SchemaType for OtherSchema {
+        const KEYSPACE_NAME: &'static str = "other_schema";
+    }
+
+    //version 2 of the test schema, in the same keyspace
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestSchemaV2 {
+        value: String,
+        count: u32,
+    }
+
+    fn test_schema_v1_to_v2(codec: Codec, payload: &[u8]) -> Result<Vec<u8>> {
+        let old: TestSchema = codec.deserialize(payload)?;
+        codec.serialize(&TestSchemaV2 { value: old.value, count: 0 })
+    }
+
+    impl SchemaType for TestSchemaV2 {
+        const KEYSPACE_NAME: &'static str = "test_schema";
+        const VERSION: u32 = 2;
+        const MIGRATIONS: &'static [MigrationStep] = &[test_schema_v1_to_v2];
+    }
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "wcd-kv-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_open_from_permissions_and_lock() {
+        init_logger();
+        let dir = temp_dir("lock");
+
+        let db = DiskBasedDb::open_from(&dir, Codec::Bincode).unwrap();
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        //a second process can't open the same database
+        assert!(DiskBasedDb::open_from(&dir, Codec::Bincode).is_err());
+
+        drop(db);
+        assert!(DiskBasedDb::open_from(&dir, Codec::Bincode).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_from_accessible_dir() {
+        init_logger();
+        let dir = temp_dir("accessible");
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o750)).unwrap();
+
+        //the directory of the operator is left as it is
+        let e = DiskBasedDb::open_from(&dir, Codec::Bincode).err().unwrap();
+        assert!(e.to_string().contains("chmod 700"), "{}", e);
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_from_shared_dir() {
+        init_logger();
+        let dir = temp_dir("shared");
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o1777)).unwrap();
+
+        assert!(DiskBasedDb::open_from(&dir, Codec::Bincode).is_err());
+        assert!(!dir.join(LOCK_FILE_NAME).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_from_legacy() {
+        init_logger();
+        let legacy_dir = temp_dir("legacy");
+        let dir = temp_dir("migrated");
+
+        {
+            let legacy = sled::open(&legacy_dir).unwrap();
+            let tree = legacy.open_tree(TestSchema::KEYSPACE_NAME).unwrap();
+            let value = TestSchema { value: "paired".to_string() };
+            tree.insert("key", bincode::serialize(&value).unwrap()).unwrap();
+            legacy.flush().unwrap();
+        }
+
+        let db = DiskBasedDb::open_from(&dir, Codec::Bincode).unwrap();
+        assert!(db.migrate_from(&legacy_dir).unwrap());
+        assert_eq!(
+            db.read::<TestSchema>("key").unwrap(),
+            Some(TestSchema { value: "paired".to_string() })
+        );
+        assert!(!legacy_dir.join("db").exists());
+
+        //the migration only runs once
+        assert!(!db.migrate_from(&legacy_dir).unwrap());
+
+        fs::remove_dir_all(&legacy_dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_from_writable_legacy() {
+        init_logger();
+        let legacy_dir = temp_dir("writable-legacy");
+        let dir = temp_dir("writable-migrated");
+
+        {
+            let legacy = sled::open(&legacy_dir).unwrap();
+            let tree = legacy.open_tree(TestSchema::KEYSPACE_NAME).unwrap();
+            let value = TestSchema { value: "planted".to_string() };
+            tree.insert("key", bincode::serialize(&value).unwrap()).unwrap();
+            legacy.flush().unwrap();
+        }
+        let db_file = legacy_dir.join("db");
+        fs::set_permissions(&db_file, fs::Permissions::from_mode(0o666))
+            .unwrap();
+
+        //another user could have written the records
+        let db = DiskBasedDb::open_from(&dir, Codec::Bincode).unwrap();
+        let e = db.migrate_from(&legacy_dir).unwrap_err();
+        assert!(e.to_string().contains("only writable by"), "{}", e);
+        assert!(db.read::<TestSchema>("key").unwrap().is_none());
+        assert!(db_file.exists());
+
+        drop(db);
+        fs::remove_dir_all(&legacy_dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_undecodable_records_are_quarantined() {
+        init_logger();
+        let dir = temp_dir("quarantine");
+        let db = DiskBasedDb::open_from(&dir, Codec::Bincode).unwrap();
+
+        db.add("good", &TestSchema { value: "good".to_string() }).unwrap();
+        let tree = db.db.open_tree(TestSchema::KEYSPACE_NAME).unwrap();
+        tree.insert("bad", b"\xff\xff".as_slice()).unwrap();
+
+        //the other records are still served
+        let items = db.list::<TestSchema>().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].0, "good");
+        assert!(db.read::<TestSchema>("bad").unwrap().is_none());
+        assert_eq!(db.keys::<TestSchema>().unwrap(), ["good"]);
+
+        let quarantined = db.quarantined().unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].keyspace, TestSchema::KEYSPACE_NAME);
+        assert_eq!(quarantined[0].key, "bad");
+        assert_eq!(quarantined[0].record, b"\xff\xff");
+        assert!(!quarantined[0].error.is_empty());
+
+        assert!(db.drop_quarantined(TestSchema::KEYSPACE_NAME, "bad").unwrap());
+        assert!(!db
+            .drop_quarantined(TestSchema::KEYSPACE_NAME, "bad")
+            .unwrap());
+        assert!(db.quarantined().unwrap().is_empty());
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_keyspace() {
+        init_logger();
+        let dir = temp_dir("upgrade");
+        let db = DiskBasedDb::open_from(&dir, Codec::Bincode).unwrap();
+
+        db.add("key", &TestSchema { value: "v1".to_string() }).unwrap();
+
+        assert_eq!(db.upgrade_keyspace::<TestSchemaV2>().unwrap(), 1);
+        assert_eq!(db.upgrade_keyspace::<TestSchemaV2>().unwrap(), 0);
+        assert_eq!(
+            db.read::<TestSchemaV2>("key").unwrap(),
+            Some(TestSchemaV2 { value: "v1".to_string(), count: 0 })
+        );
+
+        //the upgraded record can't be read with the old schema
+        assert!(db.read::<TestSchema>("key").is_err());
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_iteration() {
+        init_logger();
+        let dir = temp_dir("iter");
+        let db = DiskBasedDb::open_from(&dir, Codec::Bincode).unwrap();
+
+        for key in ["mobile:b", "mobile:a", "other"] {
+            db.add(key, &TestSchema { value: key.to_string() }).unwrap();
+        }
+
+        assert_eq!(db.count::<TestSchema>().unwrap(), 3);
+        assert_eq!(
+            db.keys::<TestSchema>().unwrap(),
+            ["mobile:a", "mobile:b", "other"]
+        );
+
+        let items = db.list::<TestSchema>().unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(
+            items[2],
+            ("other".to_string(), TestSchema { value: "other".to_string() })
+        );
+
+        let items = db.scan_prefix::<TestSchema>("mobile:").unwrap();
+        let keys: Vec<_> = items.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["mobile:a", "mobile:b"]);
+
+        db.delete::<TestSchema>("other").unwrap();
+        assert_eq!(db.count::<TestSchema>().unwrap(), 2);
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_commit() {
+        init_logger();
+        let dir = temp_dir("commit");
+        let db = DiskBasedDb::open_from(&dir, Codec::Bincode).unwrap();
+
+        db.add("old", &TestSchema { value: "old".to_string() }).unwrap();
+
+        let mut tx = Transaction::new();
+        tx.put("new", &TestSchema { value: "new".to_string() })
+            .unwrap()
+            .put("other", &OtherSchema { value: 7 })
+            .unwrap()
+            .remove::<TestSchema>("old");
+
+        //nothing is written before the commit
+        assert!(db.read::<TestSchema>("new").unwrap().is_none());
+
+        db.commit(tx).unwrap();
+
+        assert_eq!(db.keys::<TestSchema>().unwrap(), ["new"]);
+        assert_eq!(
+            db.read::<OtherSchema>("other").unwrap(),
+            Some(OtherSchema { value: 7 })
+        );
+        assert!(db.commit(Transaction::new()).is_ok());
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_codec_is_recorded() {
+        init_logger();
+        let dir = temp_dir("codec");
+        let db = DiskBasedDb::open_from(&dir, Codec::Json).unwrap();
+
+        db.add("key", &TestSchema { value: "a".to_string() }).unwrap();
+        let mut tx = Transaction::new();
+        tx.put("other", &OtherSchema { value: 7 }).unwrap();
+        db.commit(tx).unwrap();
+
+        //the records are readable json after the header
+        let raw = db.db.open_tree("other_schema").unwrap().get("other");
+        assert_eq!(&raw.unwrap().unwrap()[8..], b"{\"value\":7}");
+
+        //reopening sled right away may find its files still locked
+        let other = DiskBasedDb {
+            db: db.db.clone(),
+            codec: Codec::Bincode,
+            _lock: File::open(dir.join(LOCK_FILE_NAME)).unwrap(),
+        };
+        let e = other.check_codec().unwrap_err();
+        assert!(e.to_string().contains("encoded with json"), "{}", e);
+
+        assert_eq!(
+            db.read::<TestSchema>("key").unwrap(),
+            Some(TestSchema { value: "a".to_string() })
+        );
+        assert_eq!(
+            db.read::<OtherSchema>("other").unwrap(),
+            Some(OtherSchema { value: 7 })
+        );
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        init_logger();
+        let dir = temp_dir("watch");
+        let db = DiskBasedDb::open_from(&dir, Codec::Bincode).unwrap();
+        let mut events = db.watch::<TestSchema>().unwrap();
+
+        db.add("key", &TestSchema { value: "a".to_string() }).unwrap();
+        db.add("other", &OtherSchema { value: 7 }).unwrap();
+        let mut tx = Transaction::new();
+        tx.remove::<TestSchema>("key");
+        db.commit(tx).unwrap();
+
+        assert_eq!(
+            events.recv().await,
+            Some(KvEvent::Put(
+                "key".to_string(),
+                TestSchema { value: "a".to_string() }
+            ))
+        );
+        assert_eq!(
+            events.recv().await,
+            Some(KvEvent::Removed("key".to_string()))
+        );
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch_ends_with_the_watcher() {
+        init_logger();
+        let dir = temp_dir("watch-end");
+        let db = DiskBasedDb::open_from(&dir, Codec::Bincode).unwrap();
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let tasks = metrics.num_alive_tasks();
+
+        let events = db.watch::<TestSchema>().unwrap();
+        assert_eq!(metrics.num_alive_tasks(), tasks + 1);
+
+        //the task ends without waiting for another write
+        drop(events);
+        let ended = async {
+            while metrics.num_alive_tasks() > tasks {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(1), ended)
+            .await
+            .unwrap();
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
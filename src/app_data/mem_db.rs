@@ -0,0 +1,379 @@
+//! This module provides an in-memory key-value database.
+//!
+//! `InMemoryDb` implements `KvDbOps` with the same keyspace semantics and record
+//! encoding as `DiskBasedDb`, but nothing is persisted. It backs the ephemeral
+//! run mode and lets the tests run `AppData` end to end.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::anyhow;
+use log::{error, info};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::codec::Codec;
+use super::kv_db::{KvDbOps, SchemaType};
+use super::quarantine::QuarantinedRecord;
+use super::record;
+use super::transaction::{Transaction, TxOp, TX_CODEC};
+use super::watch::{KvWatcher, Watchers};
+use crate::error::Result;
+
+type Keyspace = BTreeMap<String, Vec<u8>>;
+
+/// Codec of the records, the one of the transactions so they are stored as
+/// they are.
+const CODEC: Codec = TX_CODEC;
+
+/// A struct representing an in-memory key-value database.
+#[derive(Default)]
+pub struct InMemoryDb {
+    keyspaces: Mutex<HashMap<&'static str, Keyspace>>,
+    quarantine: Mutex<BTreeMap<String, QuarantinedRecord>>,
+    watchers: Watchers,
+}
+
+impl InMemoryDb {
+    /// Creates an empty database.
+    pub fn new() -> Self {
+        info!("In memory database opened, nothing will be persisted");
+        Self::default()
+    }
+
+    fn keyspaces(
+        &self,
+    ) -> Result<MutexGuard<'_, HashMap<&'static str, Keyspace>>> {
+        self.keyspaces
+            .lock()
+            .map_err(|_| anyhow!("In memory database poisoned"))
+    }
+
+    fn quarantine(
+        &self,
+    ) -> Result<MutexGuard<'_, BTreeMap<String, QuarantinedRecord>>> {
+        self.quarantine
+            .lock()
+            .map_err(|_| anyhow!("In memory database poisoned"))
+    }
+
+    //decodes a record of the keyspace, storing it back if it was upgraded,
+    //the record is quarantined if it can't be decoded
+    fn decode_item<ItemType>(
+        &self, keyspace: &mut Keyspace, key: &str,
+    ) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType,
+    {
+        let data = &keyspace[key];
+        let decoded = match record::decode::<ItemType>(CODEC, data) {
+            Ok(decoded) => decoded,
+            Err(e) if !record::is_newer::<ItemType>(data) => {
+                let data = keyspace.remove(key).unwrap_or_default();
+                self.move_to_quarantine(
+                    ItemType::KEYSPACE_NAME,
+                    key,
+                    &data,
+                    e,
+                )?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let Some(upgraded) = decoded.upgraded {
+            keyspace.insert(key.to_string(), upgraded);
+        }
+        Ok(Some(decoded.item))
+    }
+
+    //stores a record removed from its keyspace in the quarantine
+    fn move_to_quarantine(
+        &self, keyspace: &str, key: &str, data: &[u8], e: anyhow::Error,
+    ) -> Result<()> {
+        error!(
+            "Quarantining item with key: {} in keyspace: {}, error: {:?}",
+            key, keyspace, e
+        );
+        self.quarantine()?.insert(
+            QuarantinedRecord::key_of(keyspace, key),
+            QuarantinedRecord::new(keyspace, key, data, &e),
+        );
+        self.watchers.notify(keyspace, key, None);
+        Ok(())
+    }
+}
+
+impl KvDbOps for InMemoryDb {
+    fn add<ItemType>(&self, key: &str, data: &ItemType) -> Result<()>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        let record = record::encode(CODEC, data)?;
+        self.keyspaces()?
+            .entry(ItemType::KEYSPACE_NAME)
+            .or_default()
+            .insert(key.to_string(), record.clone());
+        self.watchers.notify(ItemType::KEYSPACE_NAME, key, Some(&record));
+        Ok(())
+    }
+
+    fn read<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let mut keyspaces = self.keyspaces()?;
+        match keyspaces.get_mut(ItemType::KEYSPACE_NAME) {
+            Some(keyspace) if keyspace.contains_key(key) => {
+                self.decode_item(keyspace, key)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn peek<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        self.keyspaces()?
+            .get(ItemType::KEYSPACE_NAME)
+            .and_then(|keyspace| keyspace.get(key))
+            .map(|data| Ok(record::decode(CODEC, data)?.item))
+            .transpose()
+    }
+
+    fn update<ItemType>(&self, key: &str, data: &ItemType) -> Result<()>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        self.add(key, data)
+    }
+
+    fn delete<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let removed = self
+            .keyspaces()?
+            .get_mut(ItemType::KEYSPACE_NAME)
+            .and_then(|keyspace| keyspace.remove(key));
+
+        match removed {
+            Some(data) => {
+                self.watchers.notify(ItemType::KEYSPACE_NAME, key, None);
+                Ok(Some(record::decode(CODEC, &data)?.item))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn list<ItemType>(&self) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        self.scan_prefix("")
+    }
+
+    fn scan_prefix<ItemType>(
+        &self, prefix: &str,
+    ) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let mut keyspaces = self.keyspaces()?;
+        let Some(keyspace) = keyspaces.get_mut(ItemType::KEYSPACE_NAME) else {
+            return Ok(Vec::new());
+        };
+
+        let keys: Vec<String> = keyspace
+            .range(prefix.to_string()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|key| {
+                self.decode_item(keyspace, &key)
+                    .map(|item| item.map(|item| (key, item)))
+                    .transpose()
+            })
+            .collect()
+    }
+
+    fn keys<ItemType>(&self) -> Result<Vec<String>>
+    where
+        ItemType: SchemaType + 'static,
+    {
+        Ok(self
+            .keyspaces()?
+            .get(ItemType::KEYSPACE_NAME)
+            .map(|keyspace| keyspace.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn count<ItemType>(&self) -> Result<usize>
+    where
+        ItemType: SchemaType + 'static,
+    {
+        Ok(self
+            .keyspaces()?
+            .get(ItemType::KEYSPACE_NAME)
+            .map_or(0, |keyspace| keyspace.len()))
+    }
+
+    fn commit(&self, tx: Transaction) -> Result<()> {
+        //the lock is held for the whole transaction
+        let mut keyspaces = self.keyspaces()?;
+
+        for op in tx.ops() {
+            let keyspace = keyspaces.entry(op.keyspace()).or_default();
+            match op {
+                TxOp::Put { key, record, .. } => {
+                    keyspace.insert(key.clone(), record.clone());
+                }
+                TxOp::Remove { key, .. } => {
+                    keyspace.remove(key);
+                }
+            }
+        }
+        drop(keyspaces);
+
+        for op in tx.ops() {
+            match op {
+                TxOp::Put { key, record, .. } => {
+                    self.watchers.notify(op.keyspace(), key, Some(record))
+                }
+                TxOp::Remove { key, .. } => {
+                    self.watchers.notify(op.keyspace(), key, None)
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn watch<ItemType>(&self) -> Result<KvWatcher<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        self.watchers.add(CODEC)
+    }
+
+    fn quarantine_item<ItemType>(
+        &self, key: &str, data: &ItemType, error: anyhow::Error,
+    ) -> Result<bool>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        let keyspace = ItemType::KEYSPACE_NAME;
+        let record = record::encode(CODEC, data)?;
+        let mut keyspaces = self.keyspaces()?;
+        let Some(records) = keyspaces.get_mut(keyspace) else {
+            return Ok(false);
+        };
+        if records.get(key) != Some(&record) {
+            return Ok(false);
+        }
+        records.remove(key);
+        drop(keyspaces);
+
+        self.move_to_quarantine(keyspace, key, &record, error)?;
+        Ok(true)
+    }
+
+    fn quarantined(&self) -> Result<Vec<QuarantinedRecord>> {
+        Ok(self.quarantine()?.values().cloned().collect())
+    }
+
+    fn drop_quarantined(&self, keyspace: &str, key: &str) -> Result<bool> {
+        let quarantine_key = QuarantinedRecord::key_of(keyspace, key);
+        let dropped = self.quarantine()?.remove(&quarantine_key).is_some();
+        if dropped {
+            info!("Dropped quarantined item {} of {}", key, keyspace);
+        }
+        Ok(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::{HostSchema, MobileSchema};
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn mobile(id: &str) -> MobileSchema {
+        MobileSchema {
+            id: id.to_string(),
+            name: format!("name-{}", id),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_crud() {
+        init_logger();
+        let db = InMemoryDb::new();
+
+        db.add("mobile_1", &mobile("mobile_1")).unwrap();
+        assert_eq!(
+            db.read::<MobileSchema>("mobile_1").unwrap().unwrap().name,
+            "name-mobile_1"
+        );
+
+        //keyspaces are independent
+        assert!(db.read::<HostSchema>("mobile_1").unwrap().is_none());
+
+        db.update("mobile_1", &MobileSchema::default()).unwrap();
+        assert_eq!(
+            db.read::<MobileSchema>("mobile_1").unwrap().unwrap().name,
+            ""
+        );
+
+        assert!(db.delete::<MobileSchema>("mobile_1").unwrap().is_some());
+        assert!(db.delete::<MobileSchema>("mobile_1").unwrap().is_none());
+        assert!(db.read::<MobileSchema>("mobile_1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_iteration() {
+        init_logger();
+        let db = InMemoryDb::new();
+
+        for id in ["mobile:b", "mobile:a", "other"] {
+            db.add(id, &mobile(id)).unwrap();
+        }
+
+        assert_eq!(db.count::<MobileSchema>().unwrap(), 3);
+        assert_eq!(db.count::<HostSchema>().unwrap(), 0);
+        assert_eq!(
+            db.keys::<MobileSchema>().unwrap(),
+            ["mobile:a", "mobile:b", "other"]
+        );
+
+        let items = db.scan_prefix::<MobileSchema>("mobile:").unwrap();
+        let keys: Vec<_> = items.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["mobile:a", "mobile:b"]);
+        assert_eq!(db.list::<MobileSchema>().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_commit() {
+        init_logger();
+        let db = InMemoryDb::new();
+        db.add("old", &mobile("old")).unwrap();
+
+        let mut tx = Transaction::new();
+        tx.put("new", &mobile("new"))
+            .unwrap()
+            .put("host_info", &HostSchema::default())
+            .unwrap()
+            .remove::<MobileSchema>("old");
+        db.commit(tx).unwrap();
+
+        assert_eq!(db.keys::<MobileSchema>().unwrap(), ["new"]);
+        assert!(db.read::<HostSchema>("host_info").unwrap().is_some());
+    }
+}
@@ -2,18 +2,40 @@
 //! methods to interact with the application's data store. It includes functionality to
 //! get host information and add mobile devices to the store.
 
+mod backend;
+mod backup;
+mod codec;
+mod encrypted_db;
 mod kv_db;
+mod mem_db;
+mod quarantine;
+mod record;
+mod retention;
 mod schemas;
+mod sqlite_db;
+mod transaction;
+mod watch;
 
 use anyhow::anyhow;
-pub use kv_db::DiskBasedDb;
+pub use backend::{DiskDb, StorageBackend};
+pub use backup::ImportMode;
+pub use codec::Codec;
+pub use encrypted_db::EncryptedDb;
 pub use kv_db::KvDbOps;
 use log::error;
 use log::info;
+pub use mem_db::InMemoryDb;
+pub use quarantine::QuarantinedRecord;
+pub use retention::{ExpiredMobile, RetentionPolicy};
+pub use schemas::unix_timestamp;
 pub use schemas::ConnectionType;
 pub use schemas::HostSchema;
+pub use schemas::MobileActivity;
+pub use schemas::MobileId;
 pub use schemas::MobileSchema;
+pub use transaction::Transaction;
 use uuid::Uuid;
+pub use watch::{AppDataWatcher, AppEvent};
 
 use crate::ble::AppDataStore;
 use crate::ble::HostProvInfo;
@@ -44,7 +66,23 @@ where
     /// Returns an error if there is an issue reading from or writing to the data store.
     pub fn new(data_db: Db, host_info: HostInfo) -> Result<Self> {
         // If host_info is not present in the db, add it
-        if let None = data_db.read::<HostSchema>("host_info")? {
+        if let Some(mut host) = data_db.read::<HostSchema>("host_info")? {
+            info!(
+                "Host info already exists, {} mobiles registered.",
+                data_db.count::<MobileSchema>()?
+            );
+
+            // The mobiles tree is the source of truth, fix drifted ids
+            // The ids are kept in registration order, the keys are sorted
+            let mobile_ids = data_db.keys::<MobileSchema>()?;
+            let mut registered = host.registered_mobiles.clone();
+            registered.sort();
+            if registered != mobile_ids {
+                info!("Registered mobile ids out of sync, updating host info.");
+                host.registered_mobiles = mobile_ids;
+                data_db.update("host_info", &host)?;
+            }
+        } else {
             info!("Host info not found in the database. Adding new host info.");
             let host_info = HostSchema {
                 id: Uuid::new_v4().to_string(),
@@ -53,12 +91,62 @@ where
                 registered_mobiles: Vec::new(),
             };
             data_db.add("host_info", &host_info)?;
-        } else {
-            info!("Host info already exists in the database.");
         }
 
         Ok(AppData { data_db })
     }
+
+    /// Opens the data store as it is, without adding the host information.
+    ///
+    /// Used to inspect or repair the data store while the daemon is stopped.
+    pub fn open(data_db: Db) -> Self {
+        AppData { data_db }
+    }
+
+    /// Retrieves the host record, including the registered mobile ids.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the host information is not found in the data store.
+    pub fn get_host(&self) -> Result<HostSchema> {
+        self.data_db
+            .read::<HostSchema>("host_info")?
+            .ok_or_else(|| anyhow!("Host info not found"))
+    }
+
+    /// Replaces the host record with a new one with a new id, removing every
+    /// registered mobile since their pairing is bound to the old host id.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is an issue reading from or writing to the data store.
+    pub fn reset_host(&mut self, name: &str) -> Result<HostSchema> {
+        let mut tx = Transaction::new();
+        for id in self.data_db.keys::<MobileSchema>()? {
+            tx.remove::<MobileSchema>(&id);
+        }
+        for id in self.data_db.keys::<MobileActivity>()? {
+            tx.remove::<MobileActivity>(&id);
+        }
+
+        let connection_type = self
+            .data_db
+            .read::<HostSchema>("host_info")?
+            .map(|host| host.connection_type)
+            .unwrap_or_default();
+
+        let host = HostSchema {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            connection_type,
+            registered_mobiles: Vec::new(),
+        };
+        tx.put("host_info", &host)?;
+        self.data_db.commit(tx)?;
+        info!("Host info reset, new host id {}", host.id);
+
+        Ok(host)
+    }
 }
 
 impl<Db> AppDataStore for AppData<Db>
@@ -83,13 +171,39 @@ where
         Err(anyhow!("Host info not found"))
     }
 
+    fn rename_host(&mut self, name: &str) -> Result<()> {
+        if name.trim().is_empty() {
+            return Err(anyhow!("Host name can't be empty"));
+        }
+
+        let mut host = self.get_host()?;
+        if host.name == name {
+            return Ok(());
+        }
+
+        host.name = name.to_string();
+        self.data_db.update("host_info", &host)?;
+        info!("Host renamed to {}", name);
+        Ok(())
+    }
+
     fn add_mobile(&mut self, mobile: &MobileSchema) -> Result<()> {
         if let Some(mut host) = self.data_db.read::<HostSchema>("host_info")? {
-            // Update the host info with the new mobile id
-            host.registered_mobiles.push(mobile.id.clone());
-            self.data_db.update("host_info", &host)?;
+            let mut tx = Transaction::new();
+            // Update the host info with the new mobile id, once
+            if !host.registered_mobiles.contains(&mobile.id) {
+                host.registered_mobiles.push(mobile.id.clone());
+                tx.put("host_info", &host)?;
+
+                let activity = MobileActivity {
+                    registered_at: Some(unix_timestamp()),
+                    ..Default::default()
+                };
+                tx.put(&mobile.id, &activity)?;
+            }
             // Store the mobile info
-            self.data_db.add(&mobile.id, mobile)?;
+            tx.put(&mobile.id, mobile)?;
+            self.data_db.commit(tx)?;
             info!("Mobile device added successfully.");
             return Ok(());
         }
@@ -106,6 +220,83 @@ where
         error!("Failed to retrieve mobile info: Mobile info not found.");
         Err(anyhow!("Mobile info not found"))
     }
+
+    fn update_mobile(&mut self, mobile: &MobileSchema) -> Result<()> {
+        if self.data_db.read::<MobileSchema>(&mobile.id)?.is_none() {
+            error!("Failed to update mobile device: {} not found.", mobile.id);
+            return Err(anyhow!("Mobile info not found"));
+        }
+
+        self.data_db.update(&mobile.id, mobile)?;
+        info!("Mobile device {} updated successfully.", mobile.id);
+        Ok(())
+    }
+
+    fn list_mobiles(&self) -> Result<Vec<MobileSchema>> {
+        let mobiles = self.data_db.list::<MobileSchema>()?;
+        Ok(mobiles.into_iter().map(|(_, mobile)| mobile).collect())
+    }
+
+    fn remove_mobile(&mut self, id: &str) -> Result<()> {
+        let Some(mut host) = self.data_db.read::<HostSchema>("host_info")?
+        else {
+            error!("Failed to remove mobile device: Host info not found.");
+            return Err(anyhow!("Host info not found"));
+        };
+
+        let registered = host.registered_mobiles.len();
+        host.registered_mobiles.retain(|mobile_id| mobile_id != id);
+
+        if host.registered_mobiles.len() == registered
+            && self.data_db.read::<MobileSchema>(id)?.is_none()
+        {
+            error!("Failed to remove mobile device: {} not found.", id);
+            return Err(anyhow!("Mobile info not found"));
+        }
+
+        let mut tx = Transaction::new();
+        tx.put("host_info", &host)?
+            .remove::<MobileSchema>(id)
+            .remove::<MobileActivity>(id);
+        self.data_db.commit(tx)?;
+        info!("Mobile device {} removed successfully.", id);
+        Ok(())
+    }
+
+    fn get_mobile_activity(&self, id: &str) -> Result<MobileActivity> {
+        // Mobiles registered before the activity was tracked have none
+        Ok(self.data_db.read::<MobileActivity>(id)?.unwrap_or_default())
+    }
+
+    fn set_mobile_activity(
+        &mut self, id: &str, activity: &MobileActivity,
+    ) -> Result<()> {
+        if self.data_db.read::<MobileSchema>(id)?.is_none() {
+            error!("Failed to update mobile activity: {} not found.", id);
+            return Err(anyhow!("Mobile info not found"));
+        }
+
+        self.data_db.update(id, activity)
+    }
+
+    fn list_mobile_activity(&self) -> Result<Vec<(MobileId, MobileActivity)>> {
+        self.data_db
+            .keys::<MobileSchema>()?
+            .into_iter()
+            .map(|id| {
+                let activity = self.get_mobile_activity(&id)?;
+                Ok((id, activity))
+            })
+            .collect()
+    }
+
+    fn watch(&self) -> Result<AppDataWatcher> {
+        AppData::watch(self)
+    }
+
+    fn quarantined(&self) -> Result<Vec<QuarantinedRecord>> {
+        AppData::quarantined(self)
+    }
 }
 
 #[cfg(test)]
@@ -143,6 +334,74 @@ mod tests {
         assert!(app_data.is_ok());
     }
 
+    #[test]
+    fn test_new_app_data_fixes_registered_mobiles() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+
+        let host_info = HostInfo {
+            name: "TestHost".to_string(),
+            connection_type: ConnectionType::WLAN,
+        };
+
+        mock_db.expect_read::<HostSchema>().with(eq("host_info")).returning(
+            |_| {
+                Ok(Some(HostSchema {
+                    registered_mobiles: vec![
+                        "mobile_1".to_string(),
+                        "mobile_1".to_string(),
+                        "gone".to_string(),
+                    ],
+                    ..Default::default()
+                }))
+            },
+        );
+        mock_db.expect_count::<MobileSchema>().returning(|| Ok(1));
+        mock_db
+            .expect_keys::<MobileSchema>()
+            .returning(|| Ok(vec!["mobile_1".to_string()]));
+
+        mock_db
+            .expect_update::<HostSchema>()
+            .withf(|key, host| {
+                key == "host_info" && host.registered_mobiles == ["mobile_1"]
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        assert!(AppData::new(mock_db, host_info).is_ok());
+    }
+
+    #[test]
+    fn test_new_app_data_keeps_registration_order() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+
+        let host_info = HostInfo {
+            name: "TestHost".to_string(),
+            connection_type: ConnectionType::WLAN,
+        };
+
+        mock_db.expect_read::<HostSchema>().with(eq("host_info")).returning(
+            |_| {
+                Ok(Some(HostSchema {
+                    registered_mobiles: vec![
+                        "mobile_2".to_string(),
+                        "mobile_1".to_string(),
+                    ],
+                    ..Default::default()
+                }))
+            },
+        );
+        mock_db.expect_count::<MobileSchema>().returning(|| Ok(2));
+        mock_db.expect_keys::<MobileSchema>().returning(|| {
+            Ok(vec!["mobile_1".to_string(), "mobile_2".to_string()])
+        });
+        mock_db.expect_update::<HostSchema>().never();
+
+        assert!(AppData::new(mock_db, host_info).is_ok());
+    }
+
     #[test]
     fn test_add_mobile() {
         init_logger();
@@ -165,21 +424,232 @@ mod tests {
             .with(eq("host_info"))
             .returning(move |_| Ok(Some(host_schema.clone())));
 
+        // The host info and the mobile info are written in one transaction
         mock_db
-            .expect_update::<HostSchema>()
-            .withf(|key, host| {
-                key == "host_info"
-                    && host.registered_mobiles.contains(&"mobile_1".to_string())
+            .expect_commit()
+            .withf(|tx| {
+                let hosts = tx.put_items::<HostSchema>();
+                let mobiles = tx.put_items::<MobileSchema>();
+                hosts.len() == 1
+                    && hosts[0].0 == "host_info"
+                    && hosts[0].1.registered_mobiles == ["mobile_1"]
+                    && mobiles.len() == 1
+                    && mobiles[0].0 == "mobile_1"
+                    && mobiles[0].1.name == "Mobile1"
+                    && tx.put_items::<MobileActivity>()[0]
+                        .1
+                        .registered_at
+                        .is_some()
             })
-            .returning(|_, _| Ok(()));
-
-        mock_db
-            .expect_add::<MobileSchema>()
-            .withf(|key, mobile| key == "mobile_1" && mobile.name == "Mobile1")
-            .returning(|_, _| Ok(()));
+            .times(1)
+            .returning(|_| Ok(()));
 
         let mut app_data = AppData { data_db: mock_db };
         let result = app_data.add_mobile(&mobile_schema);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_add_mobile_again() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+        let host_schema = HostSchema {
+            registered_mobiles: vec!["mobile_1".to_string()],
+            ..Default::default()
+        };
+
+        mock_db
+            .expect_read::<HostSchema>()
+            .with(eq("host_info"))
+            .returning(move |_| Ok(Some(host_schema.clone())));
+
+        // The mobile info is updated without duplicating the id
+        mock_db
+            .expect_commit()
+            .withf(|tx| {
+                tx.put_items::<HostSchema>().is_empty()
+                    && tx.put_items::<MobileSchema>().len() == 1
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut app_data = AppData { data_db: mock_db };
+        let mobile =
+            MobileSchema { id: "mobile_1".to_string(), ..Default::default() };
+        assert!(app_data.add_mobile(&mobile).is_ok());
+    }
+
+    #[test]
+    fn test_list_mobiles() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+
+        //the host record is not used, the mobiles tree is the source of truth
+        mock_db.expect_read::<HostSchema>().never();
+        mock_db.expect_list::<MobileSchema>().times(1).returning(|| {
+            Ok(vec![(
+                "mobile_1".to_string(),
+                MobileSchema {
+                    id: "mobile_1".to_string(),
+                    ..Default::default()
+                },
+            )])
+        });
+
+        let app_data = AppData { data_db: mock_db };
+        let mobiles = app_data.list_mobiles().unwrap();
+        assert_eq!(mobiles.len(), 1);
+        assert_eq!(mobiles[0].id, "mobile_1");
+    }
+
+    #[test]
+    fn test_remove_mobile() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+        let host_schema = HostSchema {
+            registered_mobiles: vec![
+                "mobile_1".to_string(),
+                "mobile_2".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        mock_db
+            .expect_read::<HostSchema>()
+            .with(eq("host_info"))
+            .returning(move |_| Ok(Some(host_schema.clone())));
+
+        mock_db
+            .expect_commit()
+            .withf(|tx| {
+                let hosts = tx.put_items::<HostSchema>();
+                hosts.len() == 1
+                    && hosts[0].1.registered_mobiles == ["mobile_2"]
+                    && tx.removed_keys::<MobileSchema>() == ["mobile_1"]
+                    && tx.removed_keys::<MobileActivity>() == ["mobile_1"]
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut app_data = AppData { data_db: mock_db };
+        assert!(app_data.remove_mobile("mobile_1").is_ok());
+    }
+
+    #[test]
+    fn test_remove_unknown_mobile() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+
+        mock_db
+            .expect_read::<HostSchema>()
+            .with(eq("host_info"))
+            .returning(|_| Ok(Some(HostSchema::default())));
+
+        mock_db
+            .expect_read::<MobileSchema>()
+            .with(eq("unknown"))
+            .returning(|_| Ok(None));
+
+        mock_db.expect_commit().never();
+
+        let mut app_data = AppData { data_db: mock_db };
+        assert!(app_data.remove_mobile("unknown").is_err());
+    }
+
+    #[test]
+    fn test_reset_host() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+        let host_schema = HostSchema {
+            id: "old_id".to_string(),
+            name: "OldHost".to_string(),
+            connection_type: ConnectionType::AP,
+            registered_mobiles: vec!["mobile_1".to_string()],
+        };
+
+        mock_db
+            .expect_read::<HostSchema>()
+            .with(eq("host_info"))
+            .returning(move |_| Ok(Some(host_schema.clone())));
+
+        mock_db
+            .expect_keys::<MobileSchema>()
+            .returning(|| Ok(vec!["mobile_1".to_string()]));
+        mock_db
+            .expect_keys::<MobileActivity>()
+            .returning(|| Ok(vec!["mobile_1".to_string()]));
+
+        mock_db
+            .expect_commit()
+            .withf(|tx| {
+                let hosts = tx.put_items::<HostSchema>();
+                let host = &hosts[0].1;
+                tx.removed_keys::<MobileSchema>() == ["mobile_1"]
+                    && tx.removed_keys::<MobileActivity>() == ["mobile_1"]
+                    && hosts.len() == 1
+                    && host.id != "old_id"
+                    && host.name == "NewHost"
+                    && host.connection_type == ConnectionType::AP
+                    && host.registered_mobiles.is_empty()
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut app_data = AppData::open(mock_db);
+        let host = app_data.reset_host("NewHost").unwrap();
+        assert_ne!(host.id, "old_id");
+    }
+
+    #[test]
+    fn test_app_data_in_memory() {
+        init_logger();
+        let host_info = HostInfo {
+            name: "TestHost".to_string(),
+            connection_type: ConnectionType::AP,
+        };
+        let mut app_data = AppData::new(InMemoryDb::new(), host_info).unwrap();
+        let mobile = MobileSchema {
+            id: "mobile_1".to_string(),
+            name: "Mobile1".to_string(),
+            ..Default::default()
+        };
+
+        //registering twice keeps a single entry in the host
+        app_data.add_mobile(&mobile).unwrap();
+        app_data.add_mobile(&mobile).unwrap();
+        assert_eq!(
+            app_data.get_host().unwrap().registered_mobiles,
+            ["mobile_1"]
+        );
+        assert_eq!(app_data.list_mobiles().unwrap().len(), 1);
+        assert_eq!(
+            app_data.get_host_prov_info().unwrap().connection_type,
+            "AP"
+        );
+
+        let renamed =
+            MobileSchema { name: "Renamed".to_string(), ..mobile.clone() };
+        app_data.update_mobile(&renamed).unwrap();
+
+        //the first registration is kept when the mobile registers again
+        let activity = app_data.get_mobile_activity("mobile_1").unwrap();
+        assert!(activity.registered_at.is_some());
+        let activity = MobileActivity { connection_count: 3, ..activity };
+        app_data.set_mobile_activity("mobile_1", &activity).unwrap();
+        assert_eq!(
+            app_data.list_mobile_activity().unwrap(),
+            [("mobile_1".to_string(), activity)]
+        );
+        assert_eq!(app_data.get_mobile("mobile_1").unwrap().name, "Renamed");
+
+        app_data.remove_mobile("mobile_1").unwrap();
+        assert!(app_data.get_host().unwrap().registered_mobiles.is_empty());
+        assert!(app_data.update_mobile(&renamed).is_err());
+        assert!(app_data
+            .set_mobile_activity("mobile_1", &MobileActivity::default())
+            .is_err());
+        assert!(app_data.list_mobile_activity().unwrap().is_empty());
+        assert!(app_data.get_mobile("mobile_1").is_err());
+        assert!(app_data.remove_mobile("mobile_1").is_err());
+    }
 }
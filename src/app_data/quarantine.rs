@@ -0,0 +1,95 @@
+//! Records that can't be decoded.
+//!
+//! A record that fails to decode, corrupted on disk or written by an unknown
+//! layout, is moved out of its keyspace to the `quarantine` keyspace with its
+//! raw bytes and the error, so the other records are still served and the
+//! mobile it belonged to can be provisioned again. The records of an
+//! `EncryptedDb` that fail authentication are quarantined the same way, still
+//! sealed. The quarantined records are stored as JSON, whatever the codec of
+//! the database, and are listed or dropped by the `list-quarantined` and
+//! `drop-quarantined` commands.
+
+use serde::{Deserialize, Serialize};
+
+use super::kv_db::KvDbOps;
+use super::{unix_timestamp, AppData};
+use crate::error::Result;
+
+/// Name of the keyspace of the quarantined records.
+pub const QUARANTINE_KEYSPACE: &str = "quarantine";
+
+/// A record moved out of its keyspace because it can't be decoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuarantinedRecord {
+    pub keyspace: String,
+    pub key: String,
+    /// The record as it was stored.
+    pub record: Vec<u8>,
+    pub error: String,
+    /// Seconds since the unix epoch.
+    pub quarantined_at: u64,
+}
+
+impl QuarantinedRecord {
+    pub fn new(
+        keyspace: &str, key: &str, record: &[u8], error: &anyhow::Error,
+    ) -> Self {
+        Self {
+            keyspace: keyspace.to_string(),
+            key: key.to_string(),
+            record: record.to_vec(),
+            error: format!("{:#}", error),
+            quarantined_at: unix_timestamp(),
+        }
+    }
+
+    /// Key of a quarantined record in the quarantine keyspace.
+    pub fn key_of(keyspace: &str, key: &str) -> String {
+        format!("{}/{}", keyspace, key)
+    }
+
+    /// Encodes the record to be stored in the quarantine keyspace.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the record can't be serialized.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Decodes a record of the quarantine keyspace.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data is not a quarantined record.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+impl<Db> AppData<Db>
+where
+    Db: KvDbOps,
+{
+    /// Lists the records moved to quarantine because they can't be decoded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the quarantine can't be read.
+    pub fn quarantined(&self) -> Result<Vec<QuarantinedRecord>> {
+        self.data_db.quarantined()
+    }
+
+    /// Drops a quarantined record, its data is lost.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the quarantine can't be written.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the record was quarantined.
+    pub fn drop_quarantined(&self, keyspace: &str, key: &str) -> Result<bool> {
+        self.data_db.drop_quarantined(keyspace, key)
+    }
+}
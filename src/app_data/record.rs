@@ -0,0 +1,269 @@
+//! Versioned encoding of the records stored in the database.
+//!
+//! Every record starts with a header holding the version of the schema it was
+//! written with, followed by the payload encoded with the codec of the
+//! database:
+//!
+//! ```text
+//! | magic (4 bytes) | version (u32 LE) | payload |
+//! ```
+//!
+//! Records written before the header was introduced are plain bincode and are
+//! read as version 1. A record older than `SchemaType::VERSION` is upgraded
+//! with the `SchemaType::MIGRATIONS` of its schema when it is decoded.
+
+use anyhow::anyhow;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::codec::Codec;
+use super::kv_db::SchemaType;
+use crate::error::Result;
+
+/// Marks a versioned record, a legacy record can't start with these bytes
+/// since it would mean a string longer than 1GB.
+const MAGIC: [u8; 4] = [0xff, b'W', b'C', b'D'];
+const HEADER_LEN: usize = 8;
+
+/// Upgrades the payload of a record by one version, the payload is encoded
+/// with the given codec.
+pub type MigrationStep = fn(Codec, &[u8]) -> Result<Vec<u8>>;
+
+/// A decoded record.
+pub struct Decoded<ItemType> {
+    pub item: ItemType,
+    /// The upgraded record to store back, if the record was outdated.
+    pub upgraded: Option<Vec<u8>>,
+}
+
+/// Encodes an item with the current version of its schema.
+pub fn encode<ItemType>(codec: Codec, item: &ItemType) -> Result<Vec<u8>>
+where
+    ItemType: Serialize + SchemaType,
+{
+    Ok(with_header(ItemType::VERSION, &codec.serialize(item)?))
+}
+
+/// Decodes a record, upgrading it to the current version of its schema.
+///
+/// # Errors
+///
+/// Returns an error if the record was written by a newer version, if a
+/// migration step fails or if the payload can't be deserialized.
+pub fn decode<ItemType>(codec: Codec, raw: &[u8]) -> Result<Decoded<ItemType>>
+where
+    ItemType: DeserializeOwned + SchemaType,
+{
+    let (version, payload) = split_header(raw);
+
+    if version == ItemType::VERSION {
+        return Ok(Decoded {
+            item: codec.deserialize(payload)?,
+            upgraded: None,
+        });
+    }
+
+    if version > ItemType::VERSION || version == 0 {
+        return Err(anyhow!(
+            "Record of {} has version {}, supported up to {}",
+            ItemType::KEYSPACE_NAME,
+            version,
+            ItemType::VERSION
+        ));
+    }
+
+    let mut payload = payload.to_vec();
+    for from in version..ItemType::VERSION {
+        let step =
+            ItemType::MIGRATIONS.get(from as usize - 1).ok_or_else(|| {
+                anyhow!(
+                    "No migration of {} from version {}",
+                    ItemType::KEYSPACE_NAME,
+                    from
+                )
+            })?;
+        payload = step(codec, &payload)?;
+    }
+
+    Ok(Decoded {
+        item: codec.deserialize(&payload)?,
+        upgraded: Some(with_header(ItemType::VERSION, &payload)),
+    })
+}
+
+/// Returns true if the record was written by a newer version of its schema,
+/// such a record is valid but this build can't decode it.
+pub fn is_newer<ItemType: SchemaType>(raw: &[u8]) -> bool {
+    split_header(raw).0 > ItemType::VERSION
+}
+
+/// Encodes again a record of the current version with another codec.
+///
+/// # Errors
+///
+/// Returns an error if the record can't be decoded with `from`.
+pub fn transcode<ItemType>(
+    raw: &[u8], from: Codec, to: Codec,
+) -> Result<Vec<u8>>
+where
+    ItemType: Serialize + DeserializeOwned + SchemaType,
+{
+    if from == to {
+        return Ok(raw.to_vec());
+    }
+    encode(to, &decode::<ItemType>(from, raw)?.item)
+}
+
+fn with_header(version: u32, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&MAGIC);
+    record.extend_from_slice(&version.to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+fn split_header(raw: &[u8]) -> (u32, &[u8]) {
+    match raw.strip_prefix(&MAGIC) {
+        Some(rest) if rest.len() >= 4 => {
+            let (version, payload) = rest.split_at(4);
+            (u32::from_le_bytes(version.try_into().unwrap()), payload)
+        }
+        //legacy record without header
+        _ => (1, raw),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::{ConnectionType, HostSchema, MobileSchema};
+    use serde::Deserialize;
+
+    //layout of the test schema in version 1
+    #[derive(Serialize, Deserialize)]
+    struct TestSchemaV1 {
+        id: String,
+        name: String,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestSchema {
+        id: String,
+        name: String,
+        last_seen: u64,
+    }
+
+    fn test_schema_v1_to_v2(codec: Codec, payload: &[u8]) -> Result<Vec<u8>> {
+        let old: TestSchemaV1 = codec.deserialize(payload)?;
+        codec.serialize(&TestSchema {
+            id: old.id,
+            name: old.name,
+            last_seen: 0,
+        })
+    }
+
+    impl SchemaType for TestSchema {
+        const KEYSPACE_NAME: &'static str = "test_schema";
+        const VERSION: u32 = 2;
+        const MIGRATIONS: &'static [MigrationStep] = &[test_schema_v1_to_v2];
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mobile = MobileSchema {
+            id: "mobile_1".to_string(),
+            name: "Mobile1".to_string(),
+            ..Default::default()
+        };
+
+        let record = encode(Codec::Bincode, &mobile).unwrap();
+        assert_eq!(record[..4], MAGIC);
+
+        let decoded = decode::<MobileSchema>(Codec::Bincode, &record).unwrap();
+        assert_eq!(decoded.item.id, "mobile_1");
+        assert!(decoded.upgraded.is_none());
+    }
+
+    #[test]
+    fn test_transcode() {
+        let mobile =
+            MobileSchema { id: "mobile_1".to_string(), ..Default::default() };
+        let record = encode(Codec::Bincode, &mobile).unwrap();
+
+        let json =
+            transcode::<MobileSchema>(&record, Codec::Bincode, Codec::Json)
+                .unwrap();
+        assert_eq!(json, encode(Codec::Json, &mobile).unwrap());
+        assert!(decode::<MobileSchema>(Codec::Bincode, &json).is_err());
+        assert_eq!(
+            decode::<MobileSchema>(Codec::Json, &json).unwrap().item,
+            mobile
+        );
+    }
+
+    #[test]
+    fn test_json_upgrades_v1_record() {
+        let record = with_header(
+            1,
+            &Codec::Json
+                .serialize(&TestSchemaV1 {
+                    id: "id".to_string(),
+                    name: "name".to_string(),
+                })
+                .unwrap(),
+        );
+
+        let decoded = decode::<TestSchema>(Codec::Json, &record).unwrap();
+        assert_eq!(decoded.item.last_seen, 0);
+        let upgraded = decoded.upgraded.unwrap();
+        assert!(String::from_utf8_lossy(&upgraded).contains("\"last_seen\":0"));
+    }
+
+    #[test]
+    fn test_decode_legacy_v1_record() {
+        //records written before the versioning are plain bincode
+        let host = HostSchema {
+            id: "host_id".to_string(),
+            name: "Host".to_string(),
+            connection_type: ConnectionType::AP,
+            registered_mobiles: vec!["mobile_1".to_string()],
+        };
+        let legacy = bincode::serialize(&host).unwrap();
+
+        let decoded = decode::<HostSchema>(Codec::Bincode, &legacy).unwrap();
+        assert_eq!(decoded.item.id, "host_id");
+        assert_eq!(decoded.item.registered_mobiles, ["mobile_1"]);
+        assert!(decoded.upgraded.is_none());
+    }
+
+    #[test]
+    fn test_decode_upgrades_v1_record() {
+        let legacy = bincode::serialize(&TestSchemaV1 {
+            id: "id".to_string(),
+            name: "name".to_string(),
+        })
+        .unwrap();
+
+        let decoded = decode::<TestSchema>(Codec::Bincode, &legacy).unwrap();
+        assert_eq!(
+            decoded.item,
+            TestSchema {
+                id: "id".to_string(),
+                name: "name".to_string(),
+                last_seen: 0
+            }
+        );
+
+        //the upgraded record is read as the current version
+        let upgraded = decoded.upgraded.unwrap();
+        assert_eq!(split_header(&upgraded).0, 2);
+        let decoded = decode::<TestSchema>(Codec::Bincode, &upgraded).unwrap();
+        assert_eq!(decoded.item.last_seen, 0);
+        assert!(decoded.upgraded.is_none());
+    }
+
+    #[test]
+    fn test_decode_newer_record() {
+        let record = with_header(3, &[]);
+        assert!(decode::<TestSchema>(Codec::Bincode, &record).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! Retention policy of the pairings.
+//!
+//! A registered mobile expires when it hasn't connected for too long, and
+//! when the host has more pairings than allowed the least recently used ones
+//! are evicted. The policy only selects the mobiles to remove, the removal
+//! goes through `MobileComm` so a connected session is released with them.
+
+use std::fmt;
+
+use super::{MobileActivity, MobileId};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Limits on the registered mobiles, a `None` limit is not enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_inactive_days: Option<u64>,
+    pub max_pairings: Option<usize>,
+}
+
+/// Why a mobile is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiryReason {
+    /// Not used for more than the given days.
+    Inactive(u64),
+    /// Least recently used mobile above the given number of pairings.
+    Evicted(usize),
+}
+
+impl fmt::Display for ExpiryReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inactive(days) => write!(f, "inactive for {} days", days),
+            Self::Evicted(max) => write!(f, "more than {} pairings", max),
+        }
+    }
+}
+
+/// A mobile selected for removal by the policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiredMobile {
+    pub id: MobileId,
+    pub reason: ExpiryReason,
+}
+
+//last time the mobile was used, a mobile without history is used now
+fn last_used(activity: &MobileActivity, now: u64) -> u64 {
+    activity.last_connected_at.or(activity.registered_at).unwrap_or(now)
+}
+
+impl RetentionPolicy {
+    /// Returns true if no limit is enforced.
+    pub fn is_disabled(&self) -> bool {
+        self.max_inactive_days.is_none() && self.max_pairings.is_none()
+    }
+
+    /// Selects the mobiles to remove, `now` is in seconds since the unix
+    /// epoch.
+    ///
+    /// The mobiles in `in_use` are never removed, but they count towards
+    /// `max_pairings`.
+    pub fn select_expired(
+        &self, mobiles: &[(MobileId, MobileActivity)], in_use: &[MobileId],
+        now: u64,
+    ) -> Vec<ExpiredMobile> {
+        let mut expired = Vec::new();
+        let mut kept = Vec::new();
+
+        for (id, activity) in mobiles {
+            let last_used = last_used(activity, now);
+            let removable = !in_use.contains(id);
+
+            match self.max_inactive_days {
+                Some(days)
+                    if removable
+                        && now.saturating_sub(last_used)
+                            > days.saturating_mul(SECS_PER_DAY) =>
+                {
+                    expired.push(ExpiredMobile {
+                        id: id.clone(),
+                        reason: ExpiryReason::Inactive(days),
+                    });
+                }
+                _ => kept.push((last_used, removable, id)),
+            }
+        }
+
+        if let Some(max) = self.max_pairings {
+            let mut excess = kept.len().saturating_sub(max);
+
+            //least recently used first
+            kept.sort();
+            for (_, removable, id) in kept {
+                if excess == 0 {
+                    break;
+                }
+                if removable {
+                    expired.push(ExpiredMobile {
+                        id: id.clone(),
+                        reason: ExpiryReason::Evicted(max),
+                    });
+                    excess -= 1;
+                }
+            }
+        }
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_000 * SECS_PER_DAY;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn used_days_ago(id: &str, days: u64) -> (MobileId, MobileActivity) {
+        let activity = MobileActivity {
+            registered_at: Some(0),
+            last_connected_at: Some(NOW - days * SECS_PER_DAY),
+            ..Default::default()
+        };
+        (id.to_string(), activity)
+    }
+
+    #[test]
+    fn test_inactive_mobiles_expire() {
+        init_logger();
+        let policy =
+            RetentionPolicy { max_inactive_days: Some(30), max_pairings: None };
+        let mobiles = [
+            used_days_ago("recent", 1),
+            used_days_ago("stale", 31),
+            used_days_ago("connected", 90),
+            ("unknown".to_string(), MobileActivity::default()),
+        ];
+
+        let expired =
+            policy.select_expired(&mobiles, &["connected".to_string()], NOW);
+
+        assert_eq!(
+            expired,
+            [ExpiredMobile {
+                id: "stale".to_string(),
+                reason: ExpiryReason::Inactive(30),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_least_recently_used_are_evicted() {
+        init_logger();
+        let policy = RetentionPolicy {
+            max_inactive_days: Some(60),
+            max_pairings: Some(2),
+        };
+        let mobiles = [
+            used_days_ago("a", 5),
+            used_days_ago("b", 20),
+            used_days_ago("c", 10),
+            used_days_ago("d", 30),
+            used_days_ago("e", 90),
+        ];
+
+        //"d" is the least recently used but it is connected
+        let expired = policy.select_expired(&mobiles, &["d".to_string()], NOW);
+        let ids: Vec<_> = expired.iter().map(|e| e.id.as_str()).collect();
+
+        assert_eq!(ids, ["e", "b", "c"]);
+        assert_eq!(expired[1].reason, ExpiryReason::Evicted(2));
+        assert_eq!(expired[1].reason.to_string(), "more than 2 pairings");
+    }
+
+    #[test]
+    fn test_huge_inactivity_never_expires() {
+        init_logger();
+        let policy = RetentionPolicy {
+            max_inactive_days: Some(u64::MAX),
+            max_pairings: None,
+        };
+        assert!(policy
+            .select_expired(&[used_days_ago("a", 500)], &[], NOW)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_disabled_policy() {
+        init_logger();
+        let policy = RetentionPolicy::default();
+        assert!(policy.is_disabled());
+        assert!(policy
+            .select_expired(&[used_days_ago("a", 500)], &[], NOW)
+            .is_empty());
+    }
+}
@@ -2,6 +2,8 @@
 //! It includes the necessary types and implementations for serialization and deserialization,
 //! as well as the required traits for database schema handling.
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use serde::{Deserialize, Serialize};
 
 use super::kv_db::SchemaType;
@@ -10,21 +12,21 @@ use super::kv_db::SchemaType;
 pub type MobileId = String;
 
 /// Represents the properties of a video, including resolution and frames per second.
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct VideoProp {
     resolution: (u32, u32),
     fps: u32,
 }
 
 /// Represents information about a camera, including its name and supported video formats.
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct CameraInfo {
     pub name: String,
     pub format: Vec<VideoProp>,
 }
 
 /// Represents the schema for mobile devices, including ID, name, and associated cameras.
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct MobileSchema {
     pub id: MobileId,
     pub name: String,
@@ -33,6 +35,30 @@ pub struct MobileSchema {
 
 impl SchemaType for MobileSchema {
     const KEYSPACE_NAME: &'static str = "registered_mobiles";
+    const VERSION: u32 = 1;
+}
+
+/// Usage history of a registered mobile, the times are in seconds since the
+/// unix epoch and `None` until the event happens.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct MobileActivity {
+    pub registered_at: Option<u64>,
+    pub last_connected_at: Option<u64>,
+    pub connection_count: u64,
+    pub streaming_secs: u64,
+    pub last_camera: Option<String>,
+}
+
+impl SchemaType for MobileActivity {
+    const KEYSPACE_NAME: &'static str = "mobile_activity";
+    const VERSION: u32 = 1;
+}
+
+/// Returns the current time in seconds since the unix epoch.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
 }
 
 /// Type alias for Host ID, represented as a String.
@@ -57,4 +83,5 @@ pub struct HostSchema {
 
 impl SchemaType for HostSchema {
     const KEYSPACE_NAME: &'static str = "host_information";
+    const VERSION: u32 = 1;
 }
@@ -0,0 +1,836 @@
+//! This module provides a key-value database stored in SQLite.
+//!
+//! `SqliteDb` implements `KvDbOps` with a table per keyspace, named after
+//! `SchemaType::KEYSPACE_NAME`, holding the same versioned records as
+//! `DiskBasedDb`. The database is a single file inside the database
+//! directory, which is locked like the sled one, and the records of a sled
+//! database are copied once with `migrate_from_sled`.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::anyhow;
+use log::{error, info};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::codec::Codec;
+use super::kv_db::{
+    self, KvDbOps, SchemaType, CODEC_KEY, METADATA_TREE, SLED_DEFAULT_TREE,
+};
+use super::quarantine::{QuarantinedRecord, QUARANTINE_KEYSPACE};
+use super::record;
+use super::transaction::{Transaction, TxOp};
+use super::watch::{KvWatcher, Watchers};
+use crate::error::Result;
+
+/// Name of the SQLite file inside the database directory.
+const SQLITE_FILE_NAME: &str = "webcam-direct.sqlite3";
+
+/// Name of the table describing the database itself.
+const METADATA_TABLE: &str = "__webcam_direct_metadata";
+
+struct Inner {
+    conn: Connection,
+    //tables created by this connection
+    tables: HashSet<String>,
+}
+
+impl Inner {
+    //creates the table of a keyspace the first time it is used
+    fn table(&mut self, keyspace: &str) -> Result<String> {
+        let table = table_name(keyspace)?;
+        if !self.tables.contains(keyspace) {
+            self.conn.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY NOT \
+                     NULL, record BLOB NOT NULL) WITHOUT ROWID",
+                    table
+                ),
+                [],
+            )?;
+            self.tables.insert(keyspace.to_string());
+        }
+        Ok(table)
+    }
+
+    fn put(&mut self, keyspace: &str, key: &str, record: &[u8]) -> Result<()> {
+        let table = self.table(keyspace)?;
+        self.conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO {} (key, record) VALUES (?1, ?2)",
+                table
+            ),
+            params![key, record],
+        )?;
+        Ok(())
+    }
+
+    //a concurrent write already stored a newer record if the old one is gone
+    fn replace(
+        &mut self, keyspace: &str, key: &str, old: &[u8], new: &[u8],
+    ) -> Result<()> {
+        let table = self.table(keyspace)?;
+        self.conn.execute(
+            &format!(
+                "UPDATE {} SET record = ?1 WHERE key = ?2 AND record = ?3",
+                table
+            ),
+            params![new, key, old],
+        )?;
+        Ok(())
+    }
+
+    fn rows(&mut self, keyspace: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let table = self.table(keyspace)?;
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT key, record FROM {} ORDER BY key",
+            table
+        ))?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
+    }
+}
+
+//the keyspace names are identifiers chosen by the application, quoting
+//them keeps names such as `key` valid
+fn table_name(keyspace: &str) -> Result<String> {
+    if keyspace.is_empty()
+        || !keyspace.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(anyhow!("Invalid keyspace name: {:?}", keyspace));
+    }
+    Ok(format!("\"{}\"", keyspace))
+}
+
+/// A struct representing a key-value database stored in SQLite.
+pub struct SqliteDb {
+    inner: Mutex<Inner>,
+    codec: Codec,
+    watchers: Watchers,
+    //released after the database is closed
+    _lock: File,
+}
+
+impl SqliteDb {
+    /// Opens the SQLite database of the given directory.
+    ///
+    /// The directory is created and locked as in `DiskBasedDb::open_from`, and
+    /// the codec of the records is recorded in a new database.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database was created with another codec, or
+    /// if it can't be opened.
+    pub fn open_from<P: AsRef<Path>>(path: P, codec: Codec) -> Result<Self> {
+        let path = path.as_ref();
+        let lock = kv_db::lock_db_dir(path)?;
+
+        let conn = Connection::open(path.join(SQLITE_FILE_NAME))?;
+        conn.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY NOT NULL, \
+                 value TEXT NOT NULL)",
+                METADATA_TABLE
+            ),
+            [],
+        )?;
+
+        let stored: Option<String> = conn
+            .query_row(
+                &format!("SELECT value FROM {} WHERE key = ?1", METADATA_TABLE),
+                [CODEC_KEY],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match stored {
+            Some(name) => codec.check_stored(Codec::from_name(&name)?)?,
+            None => {
+                conn.execute(
+                    &format!(
+                        "INSERT INTO {} (key, value) VALUES (?1, ?2)",
+                        METADATA_TABLE
+                    ),
+                    [CODEC_KEY, codec.name()],
+                )?;
+            }
+        }
+
+        info!("SQLite database opened, codec: {}", codec);
+        Ok(Self {
+            inner: Mutex::new(Inner { conn, tables: HashSet::new() }),
+            codec,
+            watchers: Watchers::default(),
+            _lock: lock,
+        })
+    }
+
+    /// Copies every keyspace of the sled database at `sled_path` into this
+    /// one and removes the sled files.
+    ///
+    /// Nothing is done if there is no sled database or if this database
+    /// already has data, so the migration runs only once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the sled database was created with another codec,
+    /// or if it can't be copied.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the sled database was migrated.
+    pub fn migrate_from_sled<P: AsRef<Path>>(
+        &self, sled_path: P,
+    ) -> Result<bool> {
+        let sled_path = sled_path.as_ref();
+
+        if !sled_path.join("conf").is_file() || !sled_path.join("db").is_file()
+        {
+            return Ok(false);
+        }
+
+        if !self.is_empty()? {
+            info!("Database has data, skipping migration of {:?}", sled_path);
+            return Ok(false);
+        }
+
+        kv_db::check_sled_files(sled_path)?;
+        info!("Migrating sled database from {:?}", sled_path);
+
+        let sled = sled::open(sled_path)?;
+        let sled_codec =
+            kv_db::recorded_codec(&sled)?.unwrap_or(Codec::Bincode);
+        self.codec.check_stored(sled_codec)?;
+
+        let mut inner = self.lock()?;
+        let mut keyspaces = Vec::new();
+        for name in sled.tree_names() {
+            if name != SLED_DEFAULT_TREE && name != METADATA_TREE {
+                let keyspace = kv_db::key_to_string(&name)?;
+                let table = inner.table(&keyspace)?;
+                keyspaces.push((sled.open_tree(&name)?, table));
+            }
+        }
+
+        let tx = inner.conn.transaction()?;
+        let mut migrated = 0;
+        for (tree, table) in &keyspaces {
+            let mut insert = tx.prepare(&format!(
+                "INSERT INTO {} (key, record) VALUES (?1, ?2)",
+                table
+            ))?;
+            for item in tree.iter() {
+                let (key, value) = item?;
+                insert
+                    .execute(params![kv_db::key_to_string(&key)?, &*value])?;
+                migrated += 1;
+            }
+        }
+        tx.commit()?;
+
+        drop(keyspaces);
+        drop(sled);
+
+        info!("Migrated {} items, removing the sled database", migrated);
+        kv_db::remove_sled_files(sled_path)?;
+
+        Ok(true)
+    }
+
+    /// Upgrades every record of a keyspace to the current version of its
+    /// schema, so the upgrade doesn't wait for the records to be read.
+    ///
+    /// # Returns
+    ///
+    /// The number of upgraded records.
+    pub fn upgrade_keyspace<ItemType>(&self) -> Result<usize>
+    where
+        ItemType: DeserializeOwned + SchemaType,
+    {
+        let mut inner = self.lock()?;
+        let mut upgraded = 0;
+
+        for (key, data) in inner.rows(ItemType::KEYSPACE_NAME)? {
+            let decoded = match record::decode::<ItemType>(self.codec, &data) {
+                Ok(decoded) => decoded,
+                Err(e) if !record::is_newer::<ItemType>(&data) => {
+                    let keyspace = ItemType::KEYSPACE_NAME;
+                    self.quarantine(&mut inner, keyspace, &key, &data, e)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(record) = decoded.upgraded {
+                inner.replace(ItemType::KEYSPACE_NAME, &key, &data, &record)?;
+                upgraded += 1;
+            }
+        }
+
+        if upgraded > 0 {
+            info!(
+                "Upgraded {} items in keyspace: {} to version {}",
+                upgraded,
+                ItemType::KEYSPACE_NAME,
+                ItemType::VERSION
+            );
+        }
+
+        Ok(upgraded)
+    }
+
+    //decodes a record of the table, storing it back if it was upgraded, the
+    //record is quarantined if it can't be decoded
+    fn decode_item<ItemType>(
+        &self, inner: &mut Inner, key: &str, data: &[u8],
+    ) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType,
+    {
+        let decoded = match record::decode::<ItemType>(self.codec, data) {
+            Ok(decoded) => decoded,
+            Err(e) if !record::is_newer::<ItemType>(data) => {
+                self.quarantine(inner, ItemType::KEYSPACE_NAME, key, data, e)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let Some(upgraded) = decoded.upgraded {
+            info!(
+                "Upgraded item with key: {} in keyspace: {} to version {}",
+                key,
+                ItemType::KEYSPACE_NAME,
+                ItemType::VERSION
+            );
+            inner.replace(ItemType::KEYSPACE_NAME, key, data, &upgraded)?;
+        }
+        Ok(Some(decoded.item))
+    }
+
+    //moves an undecodable record to the quarantine table, unless it was
+    //overwritten in the meantime
+    fn quarantine(
+        &self, inner: &mut Inner, keyspace: &str, key: &str, data: &[u8],
+        e: anyhow::Error,
+    ) -> Result<bool> {
+        error!(
+            "Quarantining item with key: {} in keyspace: {}, error: {:?}",
+            key, keyspace, e
+        );
+
+        let quarantined = QuarantinedRecord::new(keyspace, key, data, &e);
+        let table = inner.table(keyspace)?;
+        let quarantine = inner.table(QUARANTINE_KEYSPACE)?;
+
+        let tx = inner.conn.transaction()?;
+        let moved = tx.execute(
+            &format!("DELETE FROM {} WHERE key = ?1 AND record = ?2", table),
+            params![key, data],
+        )? > 0;
+        if moved {
+            tx.execute(
+                &format!(
+                    "INSERT OR REPLACE INTO {} (key, record) VALUES (?1, ?2)",
+                    quarantine
+                ),
+                params![
+                    QuarantinedRecord::key_of(keyspace, key),
+                    quarantined.encode()?
+                ],
+            )?;
+        }
+        tx.commit()?;
+
+        if moved {
+            self.watchers.notify(keyspace, key, None);
+        }
+        Ok(moved)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Inner>> {
+        self.inner.lock().map_err(|_| anyhow!("SQLite database poisoned"))
+    }
+
+    /// Reads a value of the metadata table.
+    pub(super) fn metadata(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .lock()?
+            .conn
+            .query_row(
+                &format!("SELECT value FROM {} WHERE key = ?1", METADATA_TABLE),
+                [key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Writes a value of the metadata table.
+    pub(super) fn set_metadata(&self, key: &str, value: &str) -> Result<()> {
+        self.lock()?.conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+                METADATA_TABLE
+            ),
+            [key, value],
+        )?;
+        Ok(())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        let inner = self.lock()?;
+        let mut stmt = inner
+            .conn
+            .prepare("SELECT name FROM sqlite_schema WHERE type = 'table'")?;
+        let tables = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        for table in tables.iter().filter(|t| *t != METADATA_TABLE) {
+            let has_rows: bool = inner.conn.query_row(
+                &format!(
+                    "SELECT EXISTS (SELECT 1 FROM {})",
+                    table_name(table)?
+                ),
+                [],
+                |row| row.get(0),
+            )?;
+            if has_rows {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl KvDbOps for SqliteDb {
+    fn add<ItemType>(&self, key: &str, data: &ItemType) -> Result<()>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        let record = record::encode(self.codec, data)?;
+        self.lock()?.put(ItemType::KEYSPACE_NAME, key, &record)?;
+        self.watchers.notify(ItemType::KEYSPACE_NAME, key, Some(&record));
+        info!(
+            "Added item with key: {} to keyspace: {}",
+            key,
+            ItemType::KEYSPACE_NAME
+        );
+        Ok(())
+    }
+
+    fn read<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let mut inner = self.lock()?;
+        let table = inner.table(ItemType::KEYSPACE_NAME)?;
+        let data: Option<Vec<u8>> = inner
+            .conn
+            .query_row(
+                &format!("SELECT record FROM {} WHERE key = ?1", table),
+                [key],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match data {
+            Some(data) => self.decode_item(&mut inner, key, &data),
+            None => {
+                info!(
+                    "Item with key: {} not found in keyspace: {}",
+                    key,
+                    ItemType::KEYSPACE_NAME
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    fn peek<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let mut inner = self.lock()?;
+        let table = inner.table(ItemType::KEYSPACE_NAME)?;
+        let data: Option<Vec<u8>> = inner
+            .conn
+            .query_row(
+                &format!("SELECT record FROM {} WHERE key = ?1", table),
+                [key],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        data.map(|data| Ok(record::decode(self.codec, &data)?.item)).transpose()
+    }
+
+    fn update<ItemType>(&self, key: &str, data: &ItemType) -> Result<()>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        let record = record::encode(self.codec, data)?;
+        self.lock()?.put(ItemType::KEYSPACE_NAME, key, &record)?;
+        self.watchers.notify(ItemType::KEYSPACE_NAME, key, Some(&record));
+        info!(
+            "Updated item with key: {} in keyspace: {}",
+            key,
+            ItemType::KEYSPACE_NAME
+        );
+        Ok(())
+    }
+
+    fn delete<ItemType>(&self, key: &str) -> Result<Option<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let mut inner = self.lock()?;
+        let table = inner.table(ItemType::KEYSPACE_NAME)?;
+        let data: Option<Vec<u8>> = inner
+            .conn
+            .query_row(
+                &format!(
+                    "DELETE FROM {} WHERE key = ?1 RETURNING record",
+                    table
+                ),
+                [key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        drop(inner);
+
+        let Some(data) = data else {
+            return Ok(None);
+        };
+
+        self.watchers.notify(ItemType::KEYSPACE_NAME, key, None);
+        info!(
+            "Deleted item with key: {} from keyspace: {}",
+            key,
+            ItemType::KEYSPACE_NAME
+        );
+        //the record is removed as asked even if it can't be decoded
+        match record::decode::<ItemType>(self.codec, &data) {
+            Ok(decoded) => Ok(Some(decoded.item)),
+            Err(e) => {
+                error!("Deleted item {} can't be decoded: {:?}", key, e);
+                Ok(None)
+            }
+        }
+    }
+
+    fn list<ItemType>(&self) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        self.scan_prefix("")
+    }
+
+    fn scan_prefix<ItemType>(
+        &self, prefix: &str,
+    ) -> Result<Vec<(String, ItemType)>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let mut inner = self.lock()?;
+        let rows = inner.rows(ItemType::KEYSPACE_NAME)?;
+
+        let mut items = Vec::new();
+        for (key, data) in rows {
+            if !key.starts_with(prefix) {
+                continue;
+            }
+            if let Some(item) = self.decode_item(&mut inner, &key, &data)? {
+                items.push((key, item));
+            }
+        }
+        Ok(items)
+    }
+
+    fn keys<ItemType>(&self) -> Result<Vec<String>>
+    where
+        ItemType: SchemaType + 'static,
+    {
+        let mut inner = self.lock()?;
+        let table = inner.table(ItemType::KEYSPACE_NAME)?;
+        let mut stmt = inner
+            .conn
+            .prepare(&format!("SELECT key FROM {} ORDER BY key", table))?;
+        let keys = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(keys)
+    }
+
+    fn count<ItemType>(&self) -> Result<usize>
+    where
+        ItemType: SchemaType + 'static,
+    {
+        let mut inner = self.lock()?;
+        let table = inner.table(ItemType::KEYSPACE_NAME)?;
+        let count: i64 = inner.conn.query_row(
+            &format!("SELECT COUNT(*) FROM {}", table),
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn commit(&self, tx: Transaction) -> Result<()> {
+        if tx.is_empty() {
+            return Ok(());
+        }
+        let tx = tx.encoded_with(self.codec)?;
+
+        let mut inner = self.lock()?;
+        let tables = tx
+            .ops()
+            .iter()
+            .map(|op| inner.table(op.keyspace()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let sql_tx = inner.conn.transaction()?;
+        for (op, table) in tx.ops().iter().zip(&tables) {
+            match op {
+                TxOp::Put { key, record, .. } => {
+                    sql_tx.execute(
+                        &format!(
+                            "INSERT OR REPLACE INTO {} (key, record) VALUES \
+                             (?1, ?2)",
+                            table
+                        ),
+                        params![key, record],
+                    )?;
+                }
+                TxOp::Remove { key, .. } => {
+                    sql_tx.execute(
+                        &format!("DELETE FROM {} WHERE key = ?1", table),
+                        [key],
+                    )?;
+                }
+            }
+        }
+        sql_tx.commit()?;
+        drop(inner);
+
+        for op in tx.ops() {
+            match op {
+                TxOp::Put { key, record, .. } => {
+                    self.watchers.notify(op.keyspace(), key, Some(record))
+                }
+                TxOp::Remove { key, .. } => {
+                    self.watchers.notify(op.keyspace(), key, None)
+                }
+            }
+        }
+
+        info!("Committed transaction with {} writes", tx.ops().len());
+        Ok(())
+    }
+
+    fn watch<ItemType>(&self) -> Result<KvWatcher<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        self.watchers.add(self.codec)
+    }
+
+    fn quarantined(&self) -> Result<Vec<QuarantinedRecord>> {
+        self.lock()?
+            .rows(QUARANTINE_KEYSPACE)?
+            .iter()
+            .map(|(_, data)| QuarantinedRecord::decode(data))
+            .collect()
+    }
+
+    fn quarantine_item<ItemType>(
+        &self, key: &str, data: &ItemType, error: anyhow::Error,
+    ) -> Result<bool>
+    where
+        ItemType: Serialize + SchemaType + 'static,
+    {
+        let data = record::encode(self.codec, data)?;
+        let mut inner = self.lock()?;
+        self.quarantine(&mut inner, ItemType::KEYSPACE_NAME, key, &data, error)
+    }
+
+    fn drop_quarantined(&self, keyspace: &str, key: &str) -> Result<bool> {
+        let mut inner = self.lock()?;
+        let quarantine = inner.table(QUARANTINE_KEYSPACE)?;
+        let dropped = inner.conn.execute(
+            &format!("DELETE FROM {} WHERE key = ?1", quarantine),
+            [QuarantinedRecord::key_of(keyspace, key)],
+        )? > 0;
+        if dropped {
+            info!("Dropped quarantined item {} of {}", key, keyspace);
+        }
+        Ok(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::kv_db::DiskBasedDb;
+    use crate::app_data::{
+        AppData, ConnectionType, HostInfo, HostSchema, MobileSchema,
+    };
+    use crate::ble::AppDataStore;
+    use std::fs;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "wcd-sqlite-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn mobile(id: &str) -> MobileSchema {
+        MobileSchema {
+            id: id.to_string(),
+            name: format!("name-{}", id),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_crud_and_iteration() {
+        init_logger();
+        let dir = temp_dir("crud");
+        let db = SqliteDb::open_from(&dir, Codec::Json).unwrap();
+
+        for id in ["mobile:b", "mobile:a", "other"] {
+            db.add(id, &mobile(id)).unwrap();
+        }
+        db.update("other", &mobile("updated")).unwrap();
+
+        assert_eq!(db.count::<MobileSchema>().unwrap(), 3);
+        assert_eq!(db.count::<HostSchema>().unwrap(), 0);
+        assert_eq!(
+            db.keys::<MobileSchema>().unwrap(),
+            ["mobile:a", "mobile:b", "other"]
+        );
+        assert_eq!(
+            db.read::<MobileSchema>("other").unwrap(),
+            Some(mobile("updated"))
+        );
+
+        let items = db.scan_prefix::<MobileSchema>("mobile:").unwrap();
+        let keys: Vec<_> = items.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["mobile:a", "mobile:b"]);
+
+        assert!(db.delete::<MobileSchema>("other").unwrap().is_some());
+        assert!(db.delete::<MobileSchema>("other").unwrap().is_none());
+        assert!(db.read::<MobileSchema>("other").unwrap().is_none());
+
+        drop(db);
+        assert!(SqliteDb::open_from(&dir, Codec::Bincode).is_err());
+        let db = SqliteDb::open_from(&dir, Codec::Json).unwrap();
+        assert_eq!(db.list::<MobileSchema>().unwrap().len(), 2);
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_undecodable_records_are_quarantined() {
+        init_logger();
+        let dir = temp_dir("quarantine");
+        let db = SqliteDb::open_from(&dir, Codec::Bincode).unwrap();
+
+        db.add("good", &mobile("good")).unwrap();
+        db.lock()
+            .unwrap()
+            .put(MobileSchema::KEYSPACE_NAME, "bad", b"\xff\xff")
+            .unwrap();
+
+        assert_eq!(db.list::<MobileSchema>().unwrap().len(), 1);
+        assert!(db.read::<MobileSchema>("bad").unwrap().is_none());
+
+        let quarantined = db.quarantined().unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].key, "bad");
+        assert_eq!(quarantined[0].record, b"\xff\xff");
+
+        assert!(db
+            .drop_quarantined(MobileSchema::KEYSPACE_NAME, "bad")
+            .unwrap());
+        assert!(db.quarantined().unwrap().is_empty());
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_app_data_on_sqlite() {
+        init_logger();
+        let dir = temp_dir("app-data");
+        let host_info = HostInfo {
+            name: "Host".to_string(),
+            connection_type: ConnectionType::AP,
+        };
+        let db = SqliteDb::open_from(&dir, Codec::Bincode).unwrap();
+        let mut app_data = AppData::new(db, host_info).unwrap();
+
+        //the host and the mobile are written in one transaction
+        app_data.add_mobile(&mobile("mobile_1")).unwrap();
+        assert_eq!(
+            app_data.get_host().unwrap().registered_mobiles,
+            ["mobile_1"]
+        );
+
+        app_data.remove_mobile("mobile_1").unwrap();
+        assert!(app_data.list_mobiles().unwrap().is_empty());
+        assert!(app_data.get_host().unwrap().registered_mobiles.is_empty());
+
+        drop(app_data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_from_sled() {
+        init_logger();
+        let sled_dir = temp_dir("sled");
+        let dir = temp_dir("migrated");
+
+        {
+            let sled_db =
+                DiskBasedDb::open_from(&sled_dir, Codec::Json).unwrap();
+            sled_db.add("mobile_1", &mobile("mobile_1")).unwrap();
+            sled_db.add("host_info", &HostSchema::default()).unwrap();
+        }
+
+        //the sled database must have the same codec
+        let db = SqliteDb::open_from(&dir, Codec::Bincode).unwrap();
+        assert!(db.migrate_from_sled(&sled_dir).is_err());
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let db = SqliteDb::open_from(&dir, Codec::Json).unwrap();
+        assert!(db.migrate_from_sled(&sled_dir).unwrap());
+        assert_eq!(
+            db.read::<MobileSchema>("mobile_1").unwrap(),
+            Some(mobile("mobile_1"))
+        );
+        assert_eq!(db.keys::<HostSchema>().unwrap(), ["host_info"]);
+        assert!(!sled_dir.join("db").exists());
+
+        //the migration only runs once
+        assert!(!db.migrate_from_sled(&sled_dir).unwrap());
+
+        drop(db);
+        fs::remove_dir_all(&sled_dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! Writes to several keyspaces that are committed all-or-nothing.
+//!
+//! The items are encoded with `TX_CODEC` when they are added to the
+//! `Transaction`, so a transaction that can't be encoded never reaches the
+//! database. A database with another codec encodes them again when they are
+//! committed, see `Transaction::encoded_with`.
+//!
+//! ```ignore
+//! let mut tx = Transaction::new();
+//! tx.put("host_info", &host)?.put(&mobile.id, &mobile)?;
+//! db.commit(tx)?;
+//! ```
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::codec::Codec;
+use super::kv_db::SchemaType;
+use super::record;
+use crate::error::Result;
+
+/// Codec of the records added to a transaction.
+pub const TX_CODEC: Codec = Codec::Bincode;
+
+/// Encodes a record again with another codec, see `record::transcode`.
+pub type Transcoder = fn(&[u8], Codec, Codec) -> Result<Vec<u8>>;
+
+/// A write of a transaction.
+#[derive(Debug, Clone)]
+pub enum TxOp {
+    Put {
+        keyspace: &'static str,
+        key: String,
+        record: Vec<u8>,
+        transcode: Transcoder,
+    },
+    Remove {
+        keyspace: &'static str,
+        key: String,
+    },
+}
+
+impl TxOp {
+    /// Returns the write of an item, encoded with `TX_CODEC`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the item can't be encoded.
+    pub fn put<ItemType>(
+        keyspace: &'static str, key: &str, item: &ItemType,
+    ) -> Result<Self>
+    where
+        ItemType: Serialize + DeserializeOwned + SchemaType,
+    {
+        Ok(TxOp::Put {
+            keyspace,
+            key: key.to_string(),
+            record: record::encode(TX_CODEC, item)?,
+            transcode: record::transcode::<ItemType>,
+        })
+    }
+
+    pub fn keyspace(&self) -> &'static str {
+        match self {
+            TxOp::Put { keyspace, .. } | TxOp::Remove { keyspace, .. } => {
+                keyspace
+            }
+        }
+    }
+}
+
+/// A list of writes committed with `KvDbOps::commit`.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    ops: Vec<TxOp>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces an item.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the item can't be encoded.
+    pub fn put<ItemType>(
+        &mut self, key: &str, item: &ItemType,
+    ) -> Result<&mut Self>
+    where
+        ItemType: Serialize + DeserializeOwned + SchemaType,
+    {
+        self.ops.push(TxOp::put(ItemType::KEYSPACE_NAME, key, item)?);
+        Ok(self)
+    }
+
+    /// Removes an item, if it exists.
+    pub fn remove<ItemType>(&mut self, key: &str) -> &mut Self
+    where
+        ItemType: SchemaType,
+    {
+        self.ops.push(TxOp::Remove {
+            keyspace: ItemType::KEYSPACE_NAME,
+            key: key.to_string(),
+        });
+        self
+    }
+
+    /// Returns the writes in the order they were added.
+    pub fn ops(&self) -> &[TxOp] {
+        &self.ops
+    }
+
+    /// Takes the writes, to rewrite their records.
+    pub fn into_ops(self) -> Vec<TxOp> {
+        self.ops
+    }
+
+    /// Adds a write with an already encoded record.
+    pub fn push(&mut self, op: TxOp) -> &mut Self {
+        self.ops.push(op);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Encodes the records with `codec` instead of `TX_CODEC`, the returned
+    /// transaction is only meant to be committed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a record can't be encoded again.
+    pub fn encoded_with(self, codec: Codec) -> Result<Self> {
+        if codec == TX_CODEC {
+            return Ok(self);
+        }
+
+        let ops = self
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                TxOp::Put { keyspace, key, record, transcode } => {
+                    Ok(TxOp::Put {
+                        keyspace,
+                        key,
+                        record: transcode(&record, TX_CODEC, codec)?,
+                        transcode,
+                    })
+                }
+                remove => Ok(remove),
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { ops })
+    }
+
+    /// Returns the items put in the keyspace of `ItemType`.
+    #[cfg(test)]
+    pub fn put_items<ItemType>(&self) -> Vec<(String, ItemType)>
+    where
+        ItemType: serde::de::DeserializeOwned + SchemaType,
+    {
+        self.ops
+            .iter()
+            .filter_map(|op| match op {
+                TxOp::Put { keyspace, key, record, .. }
+                    if *keyspace == ItemType::KEYSPACE_NAME =>
+                {
+                    let item = record::decode(TX_CODEC, record).unwrap().item;
+                    Some((key.clone(), item))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the keys removed from the keyspace of `ItemType`.
+    #[cfg(test)]
+    pub fn removed_keys<ItemType>(&self) -> Vec<String>
+    where
+        ItemType: SchemaType,
+    {
+        self.ops
+            .iter()
+            .filter_map(|op| match op {
+                TxOp::Remove { keyspace, key }
+                    if *keyspace == ItemType::KEYSPACE_NAME =>
+                {
+                    Some(key.clone())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
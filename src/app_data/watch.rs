@@ -0,0 +1,287 @@
+//! Change notifications of the data store.
+//!
+//! `KvDbOps::watch` returns a `KvWatcher` that yields the writes of a keyspace
+//! as they are committed, and `AppData::watch` turns the writes of the host
+//! and mobiles keyspaces into `AppEvent`s, so other components can react to
+//! the changes without polling.
+//!
+//! ```ignore
+//! let mut events = app_data.watch()?;
+//! while let Some(event) = events.recv().await {
+//!     info!("Data store event: {:?}", event);
+//! }
+//! ```
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use log::error;
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc;
+
+use super::codec::Codec;
+use super::kv_db::{KvDbOps, SchemaType};
+use super::{record, AppData, HostSchema, MobileId, MobileSchema};
+use crate::error::Result;
+
+/// A write of a keyspace, the record is `None` when the key was removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawEvent {
+    pub key: String,
+    pub record: Option<Vec<u8>>,
+}
+
+pub type RawEventSender = mpsc::UnboundedSender<RawEvent>;
+
+/// The watchers of a database that notifies its own writes.
+#[derive(Default)]
+pub struct Watchers {
+    senders: Mutex<Vec<(&'static str, RawEventSender)>>,
+}
+
+impl Watchers {
+    /// Adds a watcher of the keyspace of `ItemType`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the list of watchers is poisoned.
+    pub fn add<ItemType>(&self, codec: Codec) -> Result<KvWatcher<ItemType>>
+    where
+        ItemType: DeserializeOwned + SchemaType + 'static,
+    {
+        let (tx, watcher) = KvWatcher::channel(codec);
+        self.senders
+            .lock()
+            .map_err(|_| anyhow!("Watchers poisoned"))?
+            .push((ItemType::KEYSPACE_NAME, tx));
+        Ok(watcher)
+    }
+
+    /// Sends a write to the watchers of the keyspace, dropping the closed
+    /// ones.
+    pub fn notify(&self, keyspace: &str, key: &str, record: Option<&[u8]>) {
+        let Ok(mut senders) = self.senders.lock() else {
+            return;
+        };
+
+        senders.retain(|(watched, tx)| {
+            if *watched != keyspace {
+                return !tx.is_closed();
+            }
+            let event = RawEvent {
+                key: key.to_string(),
+                record: record.map(<[u8]>::to_vec),
+            };
+            tx.send(event).is_ok()
+        });
+    }
+}
+
+/// A typed write of a keyspace.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvEvent<ItemType> {
+    Put(String, ItemType),
+    Removed(String),
+}
+
+type Decoder<ItemType> = Box<dyn Fn(&str, &[u8]) -> Result<ItemType> + Send>;
+
+/// Yields the writes of a keyspace, decoding the records as they are received.
+pub struct KvWatcher<ItemType> {
+    events: mpsc::UnboundedReceiver<RawEvent>,
+    decode: Decoder<ItemType>,
+}
+
+impl<ItemType> KvWatcher<ItemType>
+where
+    ItemType: DeserializeOwned + SchemaType + 'static,
+{
+    /// Creates a watcher of versioned records encoded with `codec` and the
+    /// sender to feed it.
+    pub fn channel(codec: Codec) -> (RawEventSender, Self) {
+        let (tx, events) = mpsc::unbounded_channel();
+        let decode = Box::new(move |_: &str, raw: &[u8]| {
+            Ok(record::decode::<ItemType>(codec, raw)?.item)
+        });
+        (tx, Self { events, decode })
+    }
+}
+
+impl<ItemType: 'static> KvWatcher<ItemType> {
+    /// Transforms the decoded items, used by the wrappers of a database.
+    pub fn map<Mapped>(
+        self, f: impl Fn(&str, ItemType) -> Result<Mapped> + Send + 'static,
+    ) -> KvWatcher<Mapped> {
+        let decode = self.decode;
+        KvWatcher {
+            events: self.events,
+            decode: Box::new(move |key, raw| f(key, decode(key, raw)?)),
+        }
+    }
+
+    /// Waits for the next write, a record that can't be decoded is logged and
+    /// skipped.
+    ///
+    /// Returns `None` once the database is dropped.
+    pub async fn recv(&mut self) -> Option<KvEvent<ItemType>> {
+        loop {
+            let event = self.events.recv().await?;
+            let Some(raw) = event.record else {
+                return Some(KvEvent::Removed(event.key));
+            };
+
+            match (self.decode)(&event.key, &raw) {
+                Ok(item) => return Some(KvEvent::Put(event.key, item)),
+                Err(e) => {
+                    error!(
+                        "Failed to decode watched record {}: {:?}",
+                        event.key, e
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// A change of the pairing data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AppEvent {
+    MobileAdded(MobileSchema),
+    MobileUpdated(MobileSchema),
+    MobileRemoved(MobileId),
+    HostRenamed(String),
+}
+
+/// Yields the changes of the pairing data.
+pub struct AppDataWatcher {
+    mobiles: KvWatcher<MobileSchema>,
+    host: KvWatcher<HostSchema>,
+    known_mobiles: HashSet<MobileId>,
+    host_name: Option<String>,
+}
+
+impl AppDataWatcher {
+    /// Waits for the next change, the changes of a mobile are in order but
+    /// they are not ordered with the renames of the host.
+    ///
+    /// Returns `None` once the data store is dropped.
+    pub async fn recv(&mut self) -> Option<AppEvent> {
+        loop {
+            let event = tokio::select! {
+                event = self.mobiles.recv() => self.mobile_event(event?),
+                event = self.host.recv() => self.host_event(event?),
+            };
+
+            if event.is_some() {
+                return event;
+            }
+        }
+    }
+
+    fn mobile_event(
+        &mut self, event: KvEvent<MobileSchema>,
+    ) -> Option<AppEvent> {
+        match event {
+            KvEvent::Put(id, mobile) => {
+                if self.known_mobiles.insert(id) {
+                    Some(AppEvent::MobileAdded(mobile))
+                } else {
+                    Some(AppEvent::MobileUpdated(mobile))
+                }
+            }
+            KvEvent::Removed(id) => self
+                .known_mobiles
+                .remove(&id)
+                .then_some(AppEvent::MobileRemoved(id)),
+        }
+    }
+
+    fn host_event(&mut self, event: KvEvent<HostSchema>) -> Option<AppEvent> {
+        let KvEvent::Put(_, host) = event else {
+            return None;
+        };
+
+        if self.host_name.as_ref() == Some(&host.name) {
+            return None;
+        }
+
+        self.host_name = Some(host.name.clone());
+        Some(AppEvent::HostRenamed(host.name))
+    }
+}
+
+impl<Db> AppData<Db>
+where
+    Db: KvDbOps,
+{
+    /// Watches the changes of the pairing data made from now on.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the keyspaces can't be watched or read.
+    pub fn watch(&self) -> Result<AppDataWatcher> {
+        let mobiles = self.data_db.watch::<MobileSchema>()?;
+        let host = self.data_db.watch::<HostSchema>()?;
+
+        Ok(AppDataWatcher {
+            mobiles,
+            host,
+            known_mobiles: self
+                .data_db
+                .keys::<MobileSchema>()?
+                .into_iter()
+                .collect(),
+            host_name: self
+                .data_db
+                .read::<HostSchema>("host_info")?
+                .map(|host| host.name),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::{ConnectionType, HostInfo, InMemoryDb};
+    use crate::ble::AppDataStore;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[tokio::test]
+    async fn test_app_data_events() {
+        init_logger();
+        let host_info = HostInfo {
+            name: "TestHost".to_string(),
+            connection_type: ConnectionType::WLAN,
+        };
+        let mut app_data = AppData::new(InMemoryDb::new(), host_info).unwrap();
+        let mut events = app_data.watch().unwrap();
+
+        let mobile =
+            MobileSchema { id: "mobile_1".to_string(), ..Default::default() };
+        //the keyspaces are watched independently, so wait for every event
+        app_data.add_mobile(&mobile).unwrap();
+        assert_eq!(
+            events.recv().await,
+            Some(AppEvent::MobileAdded(mobile.clone()))
+        );
+
+        app_data.update_mobile(&mobile).unwrap();
+        assert_eq!(events.recv().await, Some(AppEvent::MobileUpdated(mobile)));
+
+        app_data.remove_mobile("mobile_1").unwrap();
+        assert_eq!(
+            events.recv().await,
+            Some(AppEvent::MobileRemoved("mobile_1".to_string()))
+        );
+
+        app_data.reset_host("NewHost").unwrap();
+        assert_eq!(
+            events.recv().await,
+            Some(AppEvent::HostRenamed("NewHost".to_string()))
+        );
+    }
+}
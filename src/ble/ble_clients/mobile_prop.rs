@@ -11,10 +11,12 @@ use bluer::{Adapter, AdapterEvent, DeviceEvent, DeviceProperty};
 use futures::{pin_mut, stream::SelectAll, StreamExt};
 use log::info;
 
-use tokio::sync::oneshot;
+use tokio::{sync::oneshot, task::JoinHandle};
 
 pub struct MobilePropClient {
-    _tx_drop: oneshot::Sender<()>,
+    tx_drop: oneshot::Sender<()>,
+    ready_rx: Option<oneshot::Receiver<()>>,
+    task: JoinHandle<()>,
 }
 
 impl MobilePropClient {
@@ -22,13 +24,33 @@ impl MobilePropClient {
         info!("Starting MobilePropClient");
 
         let (tx, rx) = oneshot::channel();
-        tokio::spawn(async move {
-            if let Err(e) = device_props(ble_adapter, server_conn, rx).await {
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            if let Err(e) =
+                device_props(ble_adapter, server_conn, rx, ready_tx).await
+            {
                 info!("MobilePropClient failed: {:?}", e);
             }
         });
 
-        Self { _tx_drop: tx }
+        Self { tx_drop: tx, ready_rx: Some(ready_rx), task }
+    }
+
+    /// Waits until the adapter events are being watched.
+    pub async fn wait_ready(&mut self) -> Result<()> {
+        if let Some(ready_rx) = self.ready_rx.take() {
+            ready_rx
+                .await
+                .map_err(|_| anyhow!("MobilePropClient failed to start"))?;
+        }
+        Ok(())
+    }
+
+    /// Stops watching the connection state of the mobiles.
+    pub async fn stop(self) -> Result<()> {
+        let _ = self.tx_drop.send(());
+        self.task.await?;
+        Ok(())
     }
 }
 
@@ -53,6 +75,7 @@ async fn send_mobile_disconnected(
 
 pub async fn device_props(
     adapter: Adapter, server_conn: ServerConn, mut _rx: oneshot::Receiver<()>,
+    ready_tx: oneshot::Sender<()>,
 ) -> Result<()> {
     //let filter_addr: HashSet<_> = env::args().filter_map(|arg| arg.parse::<Address>().ok()).collect();
 
@@ -62,6 +85,7 @@ pub async fn device_props(
     let mut all_change_events = SelectAll::new();
 
     info!("MobilePropClient started");
+    let _ = ready_tx.send(());
     loop {
         tokio::select! {
             Some(device_event) = device_events.next() => {
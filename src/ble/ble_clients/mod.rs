@@ -1,3 +1,41 @@
 pub mod mobile_prop;
 pub mod provisioner;
 pub mod sdp_exchanger;
+
+use bluer::{
+    adv::{Advertisement, AdvertisementHandle},
+    Adapter,
+};
+use futures::future;
+use log::{error, info};
+use tokio::sync::watch;
+
+/// Name of the host as it is advertised, the clients advertise again when
+/// it changes.
+pub type HostName = watch::Receiver<String>;
+
+/// Advertises again with the new host name every time it changes, it never
+/// returns so the current advertisement lives as long as the future.
+pub async fn readvertise_on_rename(
+    adapter: &Adapter, host_name: &mut HostName,
+    adv_handle: AdvertisementHandle,
+    advertisement: impl Fn(String) -> Advertisement,
+) {
+    let mut adv_handle = Some(adv_handle);
+
+    while host_name.changed().await.is_ok() {
+        let name = host_name.borrow_and_update().clone();
+        info!("Advertising the new host name {}", name);
+
+        //drop the old advertisement first, the adapter has few slots
+        drop(adv_handle.take());
+        match adapter.advertise(advertisement(name)).await {
+            Ok(handle) => adv_handle = Some(handle),
+            Err(e) => error!("Failed to advertise the host, error: {:?}", e),
+        }
+    }
+
+    //the name can't change anymore
+    let _adv_handle = adv_handle;
+    future::pending::<()>().await;
+}
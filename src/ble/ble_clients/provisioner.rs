@@ -2,43 +2,59 @@
 use crate::error::Result;
 use crate::{
     ble::{
+        ble_clients::{readvertise_on_rename, HostName},
         ble_cmd_api::{BleApi, BleCmd, BleQuery},
         ble_server::ServerConn,
+        frame::Framing,
     },
     gatt_const::{
-        PROV_CHAR_HOST_INFO_UUID, PROV_CHAR_MOBILE_INFO_UUID,
-        PROV_SERV_HOST_UUID,
+        PROV_CHAR_HOST_INFO_FRAMED_UUID, PROV_CHAR_HOST_INFO_UUID,
+        PROV_CHAR_MOBILE_INFO_UUID, PROV_SERV_HOST_UUID,
     },
 };
+use anyhow::anyhow;
 use bluer::{
     adv::{Advertisement, AdvertisementHandle},
     gatt::local::{
         Application, ApplicationHandle, Characteristic, CharacteristicRead,
         CharacteristicWrite, CharacteristicWriteMethod, ReqError, Service,
     },
-    Adapter,
+    Adapter, Uuid,
 };
 use futures::FutureExt;
 use log::{error, info};
-use tokio::sync::oneshot;
+use tokio::{sync::oneshot, task::JoinHandle};
 
 pub struct ProvisionerClient {
-    _tx_drop: oneshot::Sender<()>,
+    tx_drop: oneshot::Sender<()>,
+    ready_rx: Option<oneshot::Receiver<()>>,
+    task: JoinHandle<()>,
 }
 
 impl ProvisionerClient {
     pub fn new(
-        ble_adapter: Adapter, server_conn: ServerConn, host_name: String,
+        ble_adapter: Adapter, server_conn: ServerConn, mut host_name: HostName,
     ) -> Self {
         let (tx, rx) = oneshot::channel();
+        let (ready_tx, ready_rx) = oneshot::channel();
 
-        tokio::spawn(async move {
-            if let Ok((_adv_handle, _app_handle)) =
-                provisioner(ble_adapter, server_conn, host_name).await
+        let task = tokio::spawn(async move {
+            let name = host_name.borrow_and_update().clone();
+            if let Ok((adv_handle, _app_handle)) =
+                provisioner(ble_adapter.clone(), server_conn, name).await
             {
                 info!("Provisioner started");
-
-                let _ = rx.await;
+                let _ = ready_tx.send(());
+
+                tokio::select! {
+                    _ = rx => {}
+                    _ = readvertise_on_rename(
+                        &ble_adapter,
+                        &mut host_name,
+                        adv_handle,
+                        advertisement,
+                    ) => {}
+                }
 
                 info!("Provisioner stopped");
             } else {
@@ -46,7 +62,33 @@ impl ProvisionerClient {
             }
         });
 
-        Self { _tx_drop: tx }
+        Self { tx_drop: tx, ready_rx: Some(ready_rx), task }
+    }
+
+    /// Waits until the provisioner service is advertised and served.
+    pub async fn wait_ready(&mut self) -> Result<()> {
+        if let Some(ready_rx) = self.ready_rx.take() {
+            ready_rx
+                .await
+                .map_err(|_| anyhow!("Provisioner failed to start"))?;
+        }
+        Ok(())
+    }
+
+    /// Stops advertising and serving the provisioner service.
+    pub async fn stop(self) -> Result<()> {
+        let _ = self.tx_drop.send(());
+        self.task.await?;
+        Ok(())
+    }
+}
+
+fn advertisement(host_name: String) -> Advertisement {
+    Advertisement {
+        service_uuids: vec![PROV_SERV_HOST_UUID].into_iter().collect(),
+        discoverable: Some(true),
+        local_name: Some(host_name),
+        ..Default::default()
     }
 }
 
@@ -58,69 +100,26 @@ pub async fn provisioner(
         adapter.name(),
         adapter.address().await?
     );
-    let le_advertisement = Advertisement {
-        service_uuids: vec![PROV_SERV_HOST_UUID].into_iter().collect(),
-        discoverable: Some(true),
-        local_name: Some(host_name),
-        ..Default::default()
-    };
-    let adv_handle = adapter.advertise(le_advertisement).await?;
+    let adv_handle = adapter.advertise(advertisement(host_name)).await?;
 
     info!("Serving GATT service on Bluetooth adapter {}", adapter.name());
 
-    let reader_server_conn = server_conn.clone();
     let writer_server_conn = server_conn.clone();
     let app = Application {
         services: vec![Service {
             uuid: PROV_SERV_HOST_UUID,
             primary: true,
             characteristics: vec![
-                Characteristic {
-                    uuid: PROV_CHAR_HOST_INFO_UUID,
-                    read: Some(CharacteristicRead {
-                        read: true,
-                        fun: Box::new(move |req| {
-                            //prepare the cmd to send to the server
-                            let (tx, rx) = oneshot::channel();
-
-                            let req = BleApi::HostInfo(BleQuery {
-                                addr: req.device_address.to_string(),
-                                max_buffer_len: req.mtu as usize,
-                                resp: tx,
-                            });
-
-                            let reader_server_conn = reader_server_conn.clone();
-
-                            async move {
-                                if let Err(_) =
-                                    reader_server_conn.send(req).await
-                                {
-                                    error!("Error sending host info request");
-                                    //return Err(ReqError::Failed);
-                                    return Ok(vec![]);
-                                }
-
-                                if let Ok(resp) = rx.await {
-                                    if let Ok(resp) = resp {
-                                        return Ok(resp);
-                                    } else {
-                                        error!("Error reading host info");
-                                    }
-                                } else {
-                                    error!(
-                                        "Error receiving host info response"
-                                    );
-                                }
-
-                                //Err(ReqError::Failed)
-                                Ok(vec![])
-                            }
-                            .boxed()
-                        }),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                },
+                host_info_char(
+                    PROV_CHAR_HOST_INFO_UUID,
+                    Framing::Json,
+                    server_conn.clone(),
+                ),
+                host_info_char(
+                    PROV_CHAR_HOST_INFO_FRAMED_UUID,
+                    Framing::Binary,
+                    server_conn.clone(),
+                ),
                 Characteristic {
                     uuid: PROV_CHAR_MOBILE_INFO_UUID,
                     write: Some(CharacteristicWrite {
@@ -173,3 +172,52 @@ pub async fn provisioner(
 
     Ok((adv_handle, app_handle))
 }
+
+//host info read in chunks with the given framing
+fn host_info_char(
+    uuid: Uuid, framing: Framing, reader_server_conn: ServerConn,
+) -> Characteristic {
+    Characteristic {
+        uuid,
+        read: Some(CharacteristicRead {
+            read: true,
+            fun: Box::new(move |req| {
+                //prepare the cmd to send to the server
+                let (tx, rx) = oneshot::channel();
+
+                let req = BleApi::HostInfo(
+                    framing,
+                    BleQuery {
+                        addr: req.device_address.to_string(),
+                        mtu: req.mtu as usize,
+                        resp: tx,
+                    },
+                );
+
+                let reader_server_conn = reader_server_conn.clone();
+
+                async move {
+                    if reader_server_conn.send(req).await.is_err() {
+                        error!("Error sending host info request");
+                        return Err(ReqError::Failed);
+                    }
+
+                    match rx.await {
+                        Ok(Ok(resp)) => Ok(resp),
+                        Ok(Err(e)) => {
+                            error!("Error reading host info, {:?}", e);
+                            Err(ReqError::Failed)
+                        }
+                        Err(_) => {
+                            error!("Error receiving host info response");
+                            Err(ReqError::Failed)
+                        }
+                    }
+                }
+                .boxed()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
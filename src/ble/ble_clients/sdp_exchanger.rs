@@ -1,5 +1,6 @@
+use crate::ble::ble_clients::{readvertise_on_rename, HostName};
 use crate::ble::ble_cmd_api::{
-    BleApi, BleCmd, BleSub, PubSubSubscriber, PubSubTopic,
+    Address, BleApi, BleBuffer, BleCmd, BleSub, PubSubSubscriber, PubSubTopic,
 };
 use crate::ble::ble_server::ServerConn;
 use crate::error::Result;
@@ -14,30 +15,37 @@ use bluer::gatt::local::{
     CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite,
     CharacteristicWriteMethod, ReqError, Service,
 };
-use bluer::gatt::{CharacteristicReader, CharacteristicWriter};
 use bluer::Adapter;
 use bluer::Uuid;
-use futures::{future, pin_mut, FutureExt, StreamExt};
+use futures::stream::{self, BoxStream};
+use futures::{future, pin_mut, StreamExt};
 use log::{error, info};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::oneshot::{self, Receiver};
+use tokio::task::JoinHandle;
+use tokio_stream::StreamMap;
 
 pub struct SdpExchangerClient {
-    _tx_drop: oneshot::Sender<()>,
+    tx_drop: oneshot::Sender<()>,
+    ready_rx: Option<Receiver<()>>,
+    task: JoinHandle<()>,
 }
 
 impl SdpExchangerClient {
     pub fn new(
-        ble_adapter: Adapter, server_conn: ServerConn, host_name: String,
+        ble_adapter: Adapter, server_conn: ServerConn, host_name: HostName,
         host_id: String,
     ) -> Self {
         info!("Starting SdpExchangerClient");
 
-        let (_tx_drop, _rx_drop) = oneshot::channel();
-        tokio::spawn(async move {
+        let (tx_drop, rx_drop) = oneshot::channel();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
             if let Err(e) = sdp_exchanger(
                 ble_adapter,
-                _rx_drop,
+                rx_drop,
+                ready_tx,
                 server_conn,
                 host_name,
                 host_id,
@@ -50,7 +58,24 @@ impl SdpExchangerClient {
             }
         });
 
-        Self { _tx_drop }
+        Self { tx_drop, ready_rx: Some(ready_rx), task }
+    }
+
+    /// Waits until the SDP exchanger service is advertised and served.
+    pub async fn wait_ready(&mut self) -> Result<()> {
+        if let Some(ready_rx) = self.ready_rx.take() {
+            ready_rx
+                .await
+                .map_err(|_| anyhow!("SdpExchanger failed to start"))?;
+        }
+        Ok(())
+    }
+
+    /// Stops advertising and serving the SDP exchanger service.
+    pub async fn stop(self) -> Result<()> {
+        let _ = self.tx_drop.send(());
+        self.task.await?;
+        Ok(())
     }
 }
 
@@ -83,14 +108,42 @@ async fn send_mobile_pnp_id(
     Ok(())
 }
 
+async fn send_mobile_sdp_resp(
+    server_conn: ServerConn, device_address: String, new_value: Vec<u8>,
+) -> Result<()> {
+    let (tx, rx) = oneshot::channel();
+
+    let cmd = BleApi::MobileSdpResponse(BleCmd {
+        addr: device_address,
+        payload: new_value,
+        resp: tx,
+    });
+
+    server_conn.send(cmd).await.map_err(|e| {
+        error!("Error sending mobile sdp answer {:?}", e);
+        anyhow!("Error sending mobile sdp answer")
+    })?;
+
+    let resp = rx.await.map_err(|e| {
+        error!("Error receiving mobile sdp answer response, {:?}", e);
+        anyhow!("Error receiving mobile sdp answer response")
+    })?;
+
+    resp.map_err(|e| {
+        error!("Error mobile sdp answer rejected, {:?}", e);
+        anyhow!("Error mobile sdp answer rejected")
+    })?;
+
+    Ok(())
+}
+
 async fn send_subscriber(
-    server_conn: ServerConn, device_address: String, buffer_len: usize,
+    server_conn: ServerConn, device_address: String, mtu: usize,
 ) -> Result<PubSubSubscriber> {
     let (tx, rx) = oneshot::channel();
 
     //crate subscription request
-    let ble_sub =
-        BleSub { addr: device_address, max_buffer_len: buffer_len, resp: tx };
+    let ble_sub = BleSub { addr: device_address, mtu, resp: tx };
 
     server_conn
         .send(BleApi::Subscribe(PubSubTopic::SdpCall, ble_sub))
@@ -113,9 +166,156 @@ async fn send_subscriber(
     Ok(sub_recv)
 }
 
+//writes of a mobile to a characteristic, each read of at most the MTU is
+//a chunk, the stream ends with the writes
+fn writes_of<R>(reader: R, mtu: usize) -> BoxStream<'static, BleBuffer>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    stream::unfold(reader, move |mut reader| async move {
+        let mut buf = vec![0; mtu];
+        match reader.read(&mut buf).await {
+            Ok(0) => {
+                info!("Write stream ended");
+                None
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                Some((buf, reader))
+            }
+            Err(err) => {
+                info!("Write stream error: {}", &err);
+                None
+            }
+        }
+    })
+    .boxed()
+}
+
+//notifies a mobile the calls published to it, ends once the server closes
+//the subscription or a notification fails
+fn notify_calls<W>(
+    mut notifier: W, mut subscriber: PubSubSubscriber,
+) -> BoxStream<'static, io::Result<()>>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    stream::once(async move {
+        while let Some(data) = subscriber.recv().await {
+            info!("Received data from server: {:?}", data);
+            //the chunks fit in a notification
+            notifier.write_all(&data).await?;
+        }
+        Ok(())
+    })
+    .boxed()
+}
+
+//what a mobile did on the exchanger
+#[derive(Debug)]
+enum MobileEvent {
+    PnpId(Address, BleBuffer),
+    SdpResponse(Address, BleBuffer),
+    NotifyEnded(Address, io::Result<()>),
+}
+
+//streams of the mobiles using the exchanger, keyed by their address so
+//every mobile is served on its own, a new stream of a mobile replaces the
+//previous one
+struct Mobiles {
+    server_conn: ServerConn,
+    pnp_writes: StreamMap<Address, BoxStream<'static, BleBuffer>>,
+    sdp_writes: StreamMap<Address, BoxStream<'static, BleBuffer>>,
+    notifiers: StreamMap<Address, BoxStream<'static, io::Result<()>>>,
+}
+
+impl Mobiles {
+    fn new(server_conn: ServerConn) -> Self {
+        Self {
+            server_conn,
+            pnp_writes: StreamMap::new(),
+            sdp_writes: StreamMap::new(),
+            notifiers: StreamMap::new(),
+        }
+    }
+
+    fn accept_pnp_writes<R>(&mut self, addr: Address, reader: R, mtu: usize)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        self.pnp_writes.insert(addr, writes_of(reader, mtu));
+    }
+
+    fn accept_sdp_writes<R>(&mut self, addr: Address, reader: R, mtu: usize)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        self.sdp_writes.insert(addr, writes_of(reader, mtu));
+    }
+
+    //subscribes the mobile to its SDP calls, notified with the notifier
+    async fn subscribe<W>(
+        &mut self, addr: Address, notifier: W, mtu: usize,
+    ) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let subscriber =
+            send_subscriber(self.server_conn.clone(), addr.clone(), mtu)
+                .await?;
+        self.notifiers.insert(addr, notify_calls(notifier, subscriber));
+        Ok(())
+    }
+
+    //waits for the next event of any mobile, it is cancel safe
+    async fn next(&mut self) -> MobileEvent {
+        tokio::select! {
+            Some((addr, data)) = self.pnp_writes.next() => {
+                MobileEvent::PnpId(addr, data)
+            }
+            Some((addr, data)) = self.sdp_writes.next() => {
+                MobileEvent::SdpResponse(addr, data)
+            }
+            Some((addr, ended)) = self.notifiers.next() => {
+                MobileEvent::NotifyEnded(addr, ended)
+            }
+            else => future::pending().await,
+        }
+    }
+
+    async fn handle(&mut self, event: MobileEvent) {
+        match event {
+            MobileEvent::PnpId(addr, data) => {
+                let server_conn = self.server_conn.clone();
+                if let Err(e) =
+                    send_mobile_pnp_id(server_conn, addr, data).await
+                {
+                    error!("Failed to send mobile pnp id: {:?}", e);
+                }
+            }
+            MobileEvent::SdpResponse(addr, data) => {
+                let server_conn = self.server_conn.clone();
+                if let Err(e) =
+                    send_mobile_sdp_resp(server_conn, addr, data).await
+                {
+                    error!("Failed to send mobile sdp answer: {:?}", e);
+                }
+            }
+            MobileEvent::NotifyEnded(addr, Ok(())) => {
+                //the session of the mobile was released
+                info!("SDP call subscription of {} closed by the server", addr);
+            }
+            MobileEvent::NotifyEnded(addr, Err(e)) => {
+                error!("Failed to write notify to {}: {:?}", addr, e);
+            }
+        }
+    }
+}
+
 async fn sdp_exchanger(
-    ble_adapter: Adapter, mut rx_drop: Receiver<()>, server_conn: ServerConn,
-    host_name: String, host_id: String,
+    ble_adapter: Adapter, mut rx_drop: Receiver<()>,
+    ready_tx: oneshot::Sender<()>, server_conn: ServerConn,
+    mut host_name: HostName, host_id: String,
 ) -> Result<()> {
     info!(
         "Advertising Sdp Exchanger on Bluetooth adapter {} with address {}",
@@ -123,14 +323,15 @@ async fn sdp_exchanger(
         ble_adapter.address().await?
     );
     let host_id = Uuid::parse_str(&host_id)?;
-    let le_advertisement = Advertisement {
+    let advertisement = |host_name| Advertisement {
         service_uuids: vec![host_id].into_iter().collect(),
         discoverable: Some(true),
         local_name: Some(host_name),
         ..Default::default()
     };
 
-    let _adv_handle = ble_adapter.advertise(le_advertisement).await?;
+    let name = host_name.borrow_and_update().clone();
+    let adv_handle = ble_adapter.advertise(advertisement(name)).await?;
 
     info!("Serving GATT service on Bluetooth adapter {}", ble_adapter.name());
 
@@ -180,22 +381,21 @@ async fn sdp_exchanger(
 
     let _app_handle = ble_adapter.serve_gatt_application(app).await?;
 
-    //current device address
-    let mut current_device_addr = String::new();
-
-    // Webcam pnp id write event
-    let mut pnp_read_buf = Vec::new();
-    let mut pnp_reader_opt: Option<CharacteristicReader> = None;
+    let _ = ready_tx.send(());
 
-    //Webcam sdp exchange notify
-    let mut notifier_opt: Option<CharacteristicWriter> = None;
-    let mut sub_recv_opt: Option<PubSubSubscriber> = None;
+    //every mobile writes and is notified on its own
+    let mut mobiles = Mobiles::new(server_conn);
 
-    let mut sdp_read_buf = Vec::new();
-    let mut sdp_reader_opt: Option<CharacteristicReader> = None;
+    let readvertise = readvertise_on_rename(
+        &ble_adapter,
+        &mut host_name,
+        adv_handle,
+        advertisement,
+    );
 
     pin_mut!(char_webcam_pnp_control);
     pin_mut!(char_sdp_exchange_control);
+    pin_mut!(readvertise);
 
     loop {
         tokio::select! {
@@ -204,44 +404,14 @@ async fn sdp_exchanger(
                 match evt {
                     Some(CharacteristicControlEvent::Write(req)) => {
                         info!("Accepting write event for PnP with MTU {} from {}", req.mtu(), req.device_address());
-                        pnp_read_buf = vec![0; req.mtu()];
-                        current_device_addr = req.device_address().to_string();
-                        pnp_reader_opt = Some(req.accept()?);
+                        let addr = req.device_address().to_string();
+                        let mtu = req.mtu();
+                        mobiles.accept_pnp_writes(addr, req.accept()?, mtu);
                     },
                     _ => {
                         error!("Error accepting write event");
                     },
                 }
-
-
-            }
-
-            _ = async {
-                let read_res = match &mut pnp_reader_opt {
-                    Some(reader) => reader.read(&mut pnp_read_buf).await,
-                    None => future::pending().await,
-                };
-
-                match read_res {
-                    Ok(0) => {
-                        info!("Write stream ended");
-                        pnp_reader_opt = None;
-                    }
-                    Ok(n) => {
-                        if let Err(e) = send_mobile_pnp_id(
-                            server_conn.clone(),
-                            current_device_addr.clone(),
-                            pnp_read_buf[0..n].to_vec(),
-                        ).await {
-                            error!("Failed to send mobile pnp id: {:?}", e);
-                        }
-                    }
-                    Err(err) => {
-                        info!("Write stream error: {}", &err);
-                        pnp_reader_opt = None;
-                    }
-                }
-            } => {
             }
 
             //sdp exchange write event
@@ -249,26 +419,19 @@ async fn sdp_exchanger(
                 match evt {
                     Some(CharacteristicControlEvent::Write(req)) => {
                         info!("Accepting write event for SDP Exchanger with MTU {} from {}", req.mtu(), req.device_address());
-                        sdp_read_buf = vec![0; req.mtu()];
-                        current_device_addr = req.device_address().to_string();
-                        sdp_reader_opt = Some(req.accept()?);
+                        let addr = req.device_address().to_string();
+                        let mtu = req.mtu();
+                        mobiles.accept_sdp_writes(addr, req.accept()?, mtu);
                     },
 
                     Some(CharacteristicControlEvent::Notify(notifier)) => {
                         info!("Accepting notify request event with MTU {} from {}", notifier.mtu(), notifier.device_address());
-
-                        match send_subscriber(
-                            server_conn.clone(),
-                            notifier.device_address().to_string(),
-                            notifier.mtu(),
-                        ).await {
-                            Ok(sub_recv) => {
-                                sub_recv_opt = Some(sub_recv);
-                                notifier_opt = Some(notifier);
-                            },
-                            Err(e) => {
-                                error!("Failed to send sdp notify: {:?}", e);
-                            }
+                        let addr = notifier.device_address().to_string();
+                        let mtu = notifier.mtu();
+                        if let Err(e) =
+                            mobiles.subscribe(addr, notifier, mtu).await
+                        {
+                            error!("Failed to send sdp notify: {:?}", e);
                         }
                     },
                     _ => {
@@ -277,60 +440,143 @@ async fn sdp_exchanger(
                 }
             }
 
-            _ = async {
-                let read_res = match &mut sdp_reader_opt {
-                    Some(reader) => reader.read(&mut sdp_read_buf).await,
-                    None => future::pending().await,
-                };
+            //writes of the mobiles and the end of their notifications
+            event = mobiles.next() => mobiles.handle(event).await,
 
-                match read_res {
-                    Ok(0) => {
-                        info!("Write stream ended");
-                        sdp_reader_opt = None;
-                    }
-                    Ok(n) => {
-                        //todo
-                        info!("Received SDP data: {:?}", &sdp_read_buf[0..n]);
-                    }
-                    Err(err) => {
-                        info!("Write stream error: {}", &err);
-                        sdp_reader_opt = None;
-                    }
-                }
-            } => {
+            _ = &mut readvertise => {}
+
+            _ = &mut rx_drop => {
+                info!("SdpExchangerClient stopped");
+                break;
             }
 
-            //receive data from server
-            pub_data = async {
-                match &mut sub_recv_opt {
-                    Some(pub_recv) => pub_recv.recv().await,
-                    None => future::pending().await,
-                }
-            } => {
-                match pub_data {
-                    Ok(data) => {
-                        info!("Received data from server: {:?}", data);
-
-                        if let Some(notifier) = notifier_opt.as_mut() {
-                            if let Err(e) = notifier.write(&data).await {
-                                error!("Failed to write notify: {:?}", e);
-                                notifier_opt = None;
-                            }
-                        }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::ble_cmd_api::PubSubPublisher;
+    use std::time::Duration;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    type Subscribed = mpsc::UnboundedReceiver<(Address, PubSubPublisher)>;
+    type Written = mpsc::UnboundedReceiver<(Address, String)>;
+
+    //server that subscribes every mobile and reports what the mobiles wrote
+    fn fake_server() -> (ServerConn, Subscribed, Written) {
+        let (server_conn, mut requests) = mpsc::channel(8);
+        let (sub_tx, sub_rx) = mpsc::unbounded_channel();
+        let (written_tx, written_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Some(req) = requests.recv().await {
+                match req {
+                    BleApi::Subscribe(PubSubTopic::SdpCall, sub) => {
+                        let (publisher, subscriber) = mpsc::unbounded_channel();
+                        let _ = sub_tx.send((sub.addr, publisher));
+                        let _ = sub.resp.send(Ok(subscriber));
                     }
-                    Err(e) => {
-                        error!("Error receiving data from server: {:?}", e);
+                    BleApi::MobilePnpId(cmd)
+                    | BleApi::MobileSdpResponse(cmd) => {
+                        let payload = String::from_utf8(cmd.payload).unwrap();
+                        let _ = written_tx.send((cmd.addr, payload));
+                        let _ = cmd.resp.send(Ok(()));
                     }
+                    req => panic!("Unexpected request {:?}", req),
                 }
             }
+        });
 
-            _ = &mut rx_drop => {
-                info!("SdpExchangerClient stopped");
-                break;
+        (server_conn, sub_rx, written_rx)
+    }
+
+    async fn read_notified(notified: &mut DuplexStream) -> String {
+        let mut buf = vec![0; 64];
+        let n = timeout(Duration::from_secs(1), notified.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_two_mobiles_exchange_at_once() {
+        init_logger();
+        let (server_conn, mut sub_rx, mut written_rx) = fake_server();
+        let mut mobiles = Mobiles::new(server_conn);
+
+        //both mobiles subscribe and write their answers
+        let mut notified = Vec::new();
+        let mut answers = Vec::new();
+        let mut publishers = Vec::new();
+        for addr in ["addr_1", "addr_2"] {
+            let (notifier, mobile_notified) = duplex(64);
+            let (mobile_answers, reader) = duplex(64);
+            mobiles.subscribe(addr.to_string(), notifier, 64).await.unwrap();
+            mobiles.accept_sdp_writes(addr.to_string(), reader, 64);
+            notified.push(mobile_notified);
+            answers.push(mobile_answers);
+            publishers.push(sub_rx.recv().await.unwrap());
+        }
+        let (mut pnp_writes, reader) = duplex(64);
+        mobiles.accept_pnp_writes("addr_2".to_string(), reader, 64);
+
+        let (tx_drop, mut rx_drop) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    event = mobiles.next() => mobiles.handle(event).await,
+                    _ = &mut rx_drop => break,
+                }
             }
+        });
 
+        //every mobile is notified of its own call
+        for (addr, publisher) in &publishers {
+            publisher.send(format!("call {}", addr).into_bytes()).unwrap();
         }
+        assert_eq!(read_notified(&mut notified[0]).await, "call addr_1");
+        assert_eq!(read_notified(&mut notified[1]).await, "call addr_2");
+
+        //the writes reach the server with the address of their mobile
+        answers[1].write_all(b"answer 2").await.unwrap();
+        assert_eq!(
+            written_rx.recv().await.unwrap(),
+            ("addr_2".to_string(), "answer 2".to_string())
+        );
+        pnp_writes.write_all(b"mobile_2").await.unwrap();
+        answers[0].write_all(b"answer 1").await.unwrap();
+        let mut written = vec![
+            written_rx.recv().await.unwrap(),
+            written_rx.recv().await.unwrap(),
+        ];
+        written.sort();
+        assert_eq!(
+            written,
+            [
+                ("addr_1".to_string(), "answer 1".to_string()),
+                ("addr_2".to_string(), "mobile_2".to_string()),
+            ]
+        );
+
+        //a closed subscription doesn't affect the other mobile
+        let (_, publisher_1) = publishers.remove(0);
+        drop(publisher_1);
+        assert_eq!(read_notified(&mut notified[0]).await, "");
+        publishers[0].1.send(b"call again".to_vec()).unwrap();
+        assert_eq!(read_notified(&mut notified[1]).await, "call again");
+
+        let _ = tx_drop.send(());
+        task.await.unwrap();
     }
-
-    Ok(())
 }
@@ -1,16 +1,28 @@
-use tokio::sync::{broadcast, oneshot};
+use std::path::PathBuf;
+
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
 
 pub type Address = String;
 pub type BleBuffer = Vec<u8>;
 pub type Responder<T> = oneshot::Sender<T>;
 
+use crate::access_point_ctl::wifi_manager::WifiCredentials;
+use crate::app_data::{
+    ExpiredMobile, MobileActivity, MobileId, MobileSchema, QuarantinedRecord,
+    RetentionPolicy,
+};
 use crate::error::Result;
 
+use super::frame::Framing;
+use super::mobile_comm::HostProvInfo;
+
 //Query
 #[derive(Debug)]
 pub struct Query<RespType> {
     pub addr: Address,
-    pub max_buffer_len: usize,
+    //negotiated MTU of the connection
+    pub mtu: usize,
     pub resp: Responder<Result<RespType>>,
 }
 
@@ -22,14 +34,69 @@ pub struct Cmd<RespType> {
     pub resp: Responder<Result<RespType>>,
 }
 
+//Host request, not bound to a mobile
+#[derive(Debug)]
+pub struct HostReq<RespType> {
+    pub resp: Responder<Result<RespType>>,
+}
+
+//Status of the mobiles connected to the host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MobilesStatus {
+    pub connected: usize,
+    pub streaming: usize,
+}
+
+//Virtual device created for a camera of a mobile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VDeviceInfo {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+//Mobile connected to the host and the state of its session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MobileInfo {
+    pub addr: Address,
+    pub state: String,
+    pub virtual_devices: Vec<VDeviceInfo>,
+}
+
+//Usage history of a registered mobile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MobileActivityInfo {
+    pub id: MobileId,
+    #[serde(flatten)]
+    pub activity: MobileActivity,
+}
+
+//Status of the access point
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ApStatus {
+    pub creds: Option<WifiCredentials>,
+    pub healthy: bool,
+}
+
 pub type BleQuery = Query<BleBuffer>;
 pub type BleCmd = Cmd<()>;
 
-//PubSub
-pub type PubSubPublisher = broadcast::Sender<BleBuffer>;
-pub type PubSubSubscriber = broadcast::Receiver<BleBuffer>;
+//PubSub, every subscriber has its own channel
+pub type PubSubPublisher = mpsc::UnboundedSender<BleBuffer>;
+pub type PubSubSubscriber = mpsc::UnboundedReceiver<BleBuffer>;
 pub type BleSub = Query<PubSubSubscriber>;
-pub type BlePub = Cmd<()>;
+pub type BlePub = SdpOffer;
+
+//Answer of a mobile to an SDP offer
+pub type SdpAnswer = oneshot::Receiver<String>;
+
+//SDP offer of the streaming session of a virtual device, published to the
+//mobile of the device, the response waits for the answer of the mobile
+#[derive(Debug)]
+pub struct SdpOffer {
+    pub vdevice: PathBuf,
+    pub payload: BleBuffer,
+    pub resp: Responder<Result<SdpAnswer>>,
+}
 
 #[derive(Debug, Eq, PartialEq, Hash)]
 pub enum PubSubTopic {
@@ -46,7 +113,7 @@ pub enum BleApi {
     RegisterMobile(BleCmd),
 
     //Read host info
-    HostInfo(BleQuery),
+    HostInfo(Framing, BleQuery),
 
     //Mobile Pnp ID
     MobilePnpId(BleCmd),
@@ -57,4 +124,23 @@ pub enum BleApi {
     //Publish/Subscribe API
     Subscribe(PubSubTopic, BleSub),
     Publish(PubSubTopic, BlePub),
+
+    //Daemon lifecycle
+    Status(HostReq<MobilesStatus>),
+    ConnectedMobiles(HostReq<Vec<Address>>),
+    Shutdown(HostReq<()>),
+
+    //Control API
+    RegisteredMobiles(HostReq<Vec<MobileSchema>>),
+    MobilesInfo(HostReq<Vec<MobileInfo>>),
+    MobilesActivity(HostReq<Vec<MobileActivityInfo>>),
+    QuarantinedRecords(HostReq<Vec<QuarantinedRecord>>),
+    ForgetMobile(String, HostReq<()>),
+    RenameHost(String, HostReq<HostProvInfo>),
+    ReleaseSessions(MobileId, HostReq<usize>),
+    ExpireMobiles(RetentionPolicy, HostReq<Vec<ExpiredMobile>>),
+    ApStatus(HostReq<ApStatus>),
+    StartWifi(HostReq<()>),
+    StopWifi(HostReq<()>),
+    RotateWifiCreds(HostReq<WifiCredentials>),
 }
@@ -1,16 +1,31 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use log::{error, info};
-use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinHandle,
+    time::{interval_at, Instant, MissedTickBehavior},
+};
 
+use crate::access_point_ctl::{AccessPointCtl, SharedAp};
+use crate::app_data::{
+    ExpiredMobile, MobileSchema, QuarantinedRecord, RetentionPolicy,
+};
 use crate::error::Result;
 use anyhow::anyhow;
 
 #[cfg(test)]
 use mockall::automock;
 
-use super::ble_cmd_api::{BleApi, BleBuffer, PubSubSubscriber, PubSubTopic};
+use super::ble_cmd_api::{
+    Address, ApStatus, BleApi, BleBuffer, HostReq, MobileActivityInfo,
+    MobileInfo, MobilesStatus, PubSubSubscriber, PubSubTopic, SdpAnswer,
+};
+use super::frame::Framing;
+use super::mobile_comm::HostProvInfo;
 
 //trait
 #[cfg_attr(test, automock)]
@@ -21,7 +36,7 @@ pub trait MultiMobileCommService: Send + Sync + 'static {
     ) -> Result<()>;
 
     fn read_host_info(
-        &mut self, addr: String, max_size: usize,
+        &mut self, addr: String, mtu: usize, framing: Framing,
     ) -> Result<BleBuffer>;
     fn device_disconnected(&mut self, addr: String) -> Result<()>;
 
@@ -30,37 +45,143 @@ pub trait MultiMobileCommService: Send + Sync + 'static {
     ) -> Result<()>;
 
     async fn subscribe_to_sdp_req(
-        &mut self, addr: String, max_size: usize,
+        &mut self, addr: String, mtu: usize,
     ) -> Result<PubSubSubscriber>;
 
     fn set_mobile_sdp_resp(
         &mut self, addr: String, data: BleBuffer,
     ) -> Result<()>;
+
+    /// Sends a payload to the mobile subscribed with the given address only,
+    /// chunked for its MTU.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the mobile is not subscribed.
+    fn publish_to(&mut self, addr: Address, payload: BleBuffer) -> Result<()>;
+
+    /// Publishes the SDP offer of a streaming session to the mobile of the
+    /// virtual device, once the mobile answered its previous offers.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the virtual device is unknown or if its mobile is
+    /// not subscribed.
+    ///
+    /// # Returns
+    ///
+    /// The answer of the mobile, once it is written.
+    fn offer_sdp(
+        &mut self, vdevice: PathBuf, offer: BleBuffer,
+    ) -> Result<SdpAnswer>;
+
+    /// Evicts the mobiles that stayed in a state longer than its deadline,
+    /// releasing their sessions.
+    ///
+    /// # Returns
+    ///
+    /// The addresses of the evicted mobiles.
+    fn evict_expired(&mut self) -> Vec<Address>;
+
+    fn connected_mobiles(&self) -> Vec<Address>;
+
+    fn status(&self) -> MobilesStatus;
+
+    fn mobiles_info(&self) -> Vec<MobileInfo>;
+
+    fn registered_mobiles(&self) -> Result<Vec<MobileSchema>>;
+
+    fn mobiles_activity(&self) -> Result<Vec<MobileActivityInfo>>;
+
+    fn quarantined_records(&self) -> Result<Vec<QuarantinedRecord>>;
+
+    fn rename_host(&mut self, name: String) -> Result<HostProvInfo>;
+
+    fn forget_mobile(&mut self, id: String) -> Result<()>;
+
+    fn release_sessions(&mut self, id: &str) -> usize;
+
+    fn expire_mobiles(
+        &mut self, policy: RetentionPolicy,
+    ) -> Result<Vec<ExpiredMobile>>;
+
+    fn release_all(&mut self) -> Result<()>;
 }
 
 pub type ServerConn = mpsc::Sender<BleApi>;
 
+/// Period of the eviction of the mobiles stuck before streaming.
+const SWEEP_PERIOD: Duration = Duration::from_secs(5);
+
+/// Sends a host request to the server and waits for its response.
+///
+/// ```ignore
+/// let mobiles = host_request(&server_conn, BleApi::MobilesInfo).await?;
+/// ```
+pub async fn host_request<RespType>(
+    server_conn: &ServerConn, req: impl FnOnce(HostReq<RespType>) -> BleApi,
+) -> Result<RespType> {
+    let (resp, rx) = oneshot::channel();
+    server_conn
+        .send(req(HostReq { resp }))
+        .await
+        .map_err(|_| anyhow!("BleServer task is not running"))?;
+    rx.await?
+}
+
+/// Queries the status of the connected mobiles, an answer also means that the
+/// request loop of the server is alive.
+pub async fn request_status(server_conn: &ServerConn) -> Result<MobilesStatus> {
+    host_request(server_conn, BleApi::Status).await
+}
+
 pub struct BleServer {
     ble_tx: ServerConn,
     _drop_tx: oneshot::Sender<()>,
+    task: JoinHandle<()>,
 }
 
 impl BleServer {
+    /// Creates the server and spawns its request loop, the access point, if
+    /// any, is controlled from the same loop.
     pub fn new(
-        mut comm_handler: impl MultiMobileCommService, req_buffer_size: usize,
+        mut comm_handler: impl MultiMobileCommService, ap: Option<SharedAp>,
+        req_buffer_size: usize,
     ) -> Self {
         let (ble_tx, mut ble_rx) = mpsc::channel(req_buffer_size);
 
         let (_drop_tx, mut _drop_rx) = oneshot::channel();
 
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
+            let mut sweep =
+                interval_at(Instant::now() + SWEEP_PERIOD, SWEEP_PERIOD);
+            sweep.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
             loop {
                 tokio::select! {
-                    _ = async {
-                         if let Some(req) = ble_rx.recv().await {
-                            handle_request(&mut comm_handler, req).await;
-                         }
-                    }  => {}
+                    req = ble_rx.recv() => match req {
+                        Some(BleApi::Shutdown(req)) => {
+                            info!("MobileManager task is shutting down");
+                            let released = comm_handler.release_all();
+                            if let Err(e) = req.resp.send(released) {
+                                error!("Error sending shutdown resp: {:?}", e);
+                            }
+                            break;
+                        }
+                        Some(req) => {
+                            handle_request(&mut comm_handler, ap.as_ref(), req)
+                                .await
+                        }
+                        None => break,
+                    },
+
+                    //mobiles stuck before streaming are evicted
+                    _ = sweep.tick() => {
+                        let evicted = comm_handler.evict_expired();
+                        if !evicted.is_empty() {
+                            info!("Evicted stale mobiles: {:?}", evicted);
+                        }
+                    }
 
                     _ = &mut _drop_rx => {
                         info!("MobileManager task is stopping");
@@ -70,18 +191,32 @@ impl BleServer {
             }
         });
 
-        Self { ble_tx, _drop_tx }
+        Self { ble_tx, _drop_tx, task }
     }
 
     pub fn connection(&self) -> ServerConn {
         self.ble_tx.clone()
     }
+
+    /// Returns the addresses of the mobiles currently connected.
+    pub async fn connected_mobiles(&self) -> Result<Vec<Address>> {
+        host_request(&self.ble_tx, BleApi::ConnectedMobiles).await
+    }
+
+    /// Stops the server, releasing every connected mobile and its virtual
+    /// devices before the request loop ends.
+    pub async fn shutdown(self) -> Result<()> {
+        let result = host_request(&self.ble_tx, BleApi::Shutdown).await;
+        self.task.await?;
+        result
+    }
 }
 
 //This function does not return a Result since every request is successful
 //if internally any operation fails, it should handle it accordingly
 async fn handle_request(
-    comm_handler: &mut impl MultiMobileCommService, req: BleApi,
+    comm_handler: &mut impl MultiMobileCommService, ap: Option<&SharedAp>,
+    req: BleApi,
 ) {
     match req {
         BleApi::MobileDisconnected(cmd) => {
@@ -105,9 +240,9 @@ async fn handle_request(
             }
         }
 
-        BleApi::HostInfo(query) => {
+        BleApi::HostInfo(framing, query) => {
             if let Err(e) = query.resp.send(
-                comm_handler.read_host_info(query.addr, query.max_buffer_len),
+                comm_handler.read_host_info(query.addr, query.mtu, framing),
             ) {
                 error!("Error sending host info: {:?}", e);
             }
@@ -138,7 +273,7 @@ async fn handle_request(
                     //process subscribe
                     if let Err(e) = sub.resp.send(
                         comm_handler
-                            .subscribe_to_sdp_req(sub.addr, sub.max_buffer_len)
+                            .subscribe_to_sdp_req(sub.addr, sub.mtu)
                             .await,
                     ) {
                         error!(
@@ -149,17 +284,140 @@ async fn handle_request(
                 }
             }
         }
-        _ => {
-            error!("Not handle request: {:?}", req);
+
+        BleApi::Publish(topic, offer) => {
+            //only the mobile streaming the virtual device
+            match topic {
+                PubSubTopic::SdpCall => {
+                    if let Err(e) = offer.resp.send(
+                        comm_handler.offer_sdp(offer.vdevice, offer.payload),
+                    ) {
+                        error!(
+                            "Error sending sdp call pub response, error: {:?}",
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        BleApi::Status(req) => {
+            if let Err(e) = req.resp.send(Ok(comm_handler.status())) {
+                error!("Error sending mobiles status: {:?}", e);
+            }
+        }
+
+        BleApi::ConnectedMobiles(req) => {
+            if let Err(e) = req.resp.send(Ok(comm_handler.connected_mobiles()))
+            {
+                error!("Error sending connected mobiles: {:?}", e);
+            }
+        }
+
+        BleApi::RegisteredMobiles(req) => {
+            if let Err(e) = req.resp.send(comm_handler.registered_mobiles()) {
+                error!("Error sending registered mobiles: {:?}", e);
+            }
+        }
+
+        BleApi::MobilesInfo(req) => {
+            if let Err(e) = req.resp.send(Ok(comm_handler.mobiles_info())) {
+                error!("Error sending mobiles info: {:?}", e);
+            }
+        }
+
+        BleApi::MobilesActivity(req) => {
+            if let Err(e) = req.resp.send(comm_handler.mobiles_activity()) {
+                error!("Error sending mobiles activity: {:?}", e);
+            }
+        }
+
+        BleApi::QuarantinedRecords(req) => {
+            if let Err(e) = req.resp.send(comm_handler.quarantined_records()) {
+                error!("Error sending quarantined records: {:?}", e);
+            }
+        }
+
+        BleApi::RenameHost(name, req) => {
+            if let Err(e) = req.resp.send(comm_handler.rename_host(name)) {
+                error!("Error sending rename host response: {:?}", e);
+            }
+        }
+
+        BleApi::ForgetMobile(id, req) => {
+            if let Err(e) = req.resp.send(comm_handler.forget_mobile(id)) {
+                error!("Error sending forget mobile response: {:?}", e);
+            }
+        }
+
+        BleApi::ReleaseSessions(id, req) => {
+            let released = comm_handler.release_sessions(&id);
+            if let Err(e) = req.resp.send(Ok(released)) {
+                error!("Error sending released sessions: {:?}", e);
+            }
+        }
+
+        BleApi::ExpireMobiles(policy, req) => {
+            if let Err(e) = req.resp.send(comm_handler.expire_mobiles(policy)) {
+                error!("Error sending expired mobiles: {:?}", e);
+            }
+        }
+
+        BleApi::ApStatus(req) => spawn_with_ap(ap, req, |ap| {
+            Ok(ApStatus { creds: ap.get_creds(), healthy: ap.is_healthy() })
+        }),
+
+        BleApi::StartWifi(req) => spawn_with_ap(ap, req, |ap| ap.start_wifi()),
+
+        BleApi::StopWifi(req) => spawn_with_ap(ap, req, |ap| ap.stop_wifi()),
+
+        BleApi::RotateWifiCreds(req) => {
+            spawn_with_ap(ap, req, |ap| ap.rotate_creds())
+        }
+
+        //shutdown is handled by the request loop, it never reaches here
+        BleApi::Shutdown(req) => {
+            error!("Shutdown reached the request handler");
+            let resp = Err(anyhow!("Shutdown is handled by the server loop"));
+            if req.resp.send(resp).is_err() {
+                error!("Error sending shutdown response");
+            }
         }
     };
 }
 
+//run an operation on the access point, if the host has one, the processes
+//of the access point are controlled by blocking calls so the operation runs
+//off the request loop and answers the request once done
+fn spawn_with_ap<T, F>(ap: Option<&SharedAp>, req: HostReq<T>, op: F)
+where
+    T: Send + 'static,
+    F: FnOnce(&mut (dyn AccessPointCtl + Send)) -> Result<T> + Send + 'static,
+{
+    let ap = ap.cloned();
+    tokio::spawn(async move {
+        let result = tokio::task::spawn_blocking(move || {
+            let ap =
+                ap.ok_or_else(|| anyhow!("Access point is not available"))?;
+            let mut ap =
+                ap.lock().map_err(|_| anyhow!("Access point poisoned"))?;
+            op(&mut *ap)
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.into()));
+
+        if req.resp.send(result).is_err() {
+            error!("Error sending access point response");
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use mockall::predicate::eq;
 
-    use crate::ble::ble_cmd_api::{BleCmd, BleQuery};
+    use crate::access_point_ctl::MockAccessPointCtl;
+    use crate::ble::ble_cmd_api::SdpOffer;
 
     use super::*;
 
@@ -176,4 +434,115 @@ mod tests {
     async fn test_ble_server_register_mobile() {
         init_logger();
     }
+
+    #[tokio::test]
+    async fn test_ble_server_answers_while_wifi_starts() {
+        init_logger();
+        let mut mock_comm = MockMultiMobileCommService::new();
+        mock_comm
+            .expect_status()
+            .times(1)
+            .returning(|| MobilesStatus { connected: 0, streaming: 0 });
+
+        //hostapd takes its time to start
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let mut mock_ap = MockAccessPointCtl::new();
+        mock_ap.expect_start_wifi().times(1).returning(move || {
+            release_rx.recv().unwrap();
+            Ok(())
+        });
+        let ap: SharedAp = std::sync::Arc::new(std::sync::Mutex::new(mock_ap));
+
+        let ble_server = BleServer::new(mock_comm, Some(ap), 4);
+        let server_conn = ble_server.connection();
+        let start_wifi = tokio::spawn(async move {
+            host_request(&server_conn, BleApi::StartWifi).await
+        });
+
+        let status = tokio::time::timeout(
+            Duration::from_secs(1),
+            request_status(&ble_server.connection()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(status, MobilesStatus { connected: 0, streaming: 0 });
+
+        release_tx.send(()).unwrap();
+        start_wifi.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ble_server_status() {
+        init_logger();
+        let mut mock_comm = MockMultiMobileCommService::new();
+
+        mock_comm
+            .expect_status()
+            .times(1)
+            .returning(|| MobilesStatus { connected: 2, streaming: 1 });
+
+        let ble_server = BleServer::new(mock_comm, None, 4);
+
+        let status = request_status(&ble_server.connection()).await.unwrap();
+        assert_eq!(status, MobilesStatus { connected: 2, streaming: 1 });
+    }
+
+    #[tokio::test]
+    async fn test_ble_server_publish_to_one_mobile() {
+        init_logger();
+        let mut mock_comm = MockMultiMobileCommService::new();
+
+        mock_comm
+            .expect_offer_sdp()
+            .with(eq(PathBuf::from("/dev/video2")), eq(b"v=0".to_vec()))
+            .times(1)
+            .returning(|_, _| {
+                let (tx, answer) = oneshot::channel();
+                tx.send("answer".to_string()).unwrap();
+                Ok(answer)
+            });
+
+        let ble_server = BleServer::new(mock_comm, None, 4);
+
+        let (resp, rx) = oneshot::channel();
+        let offer = SdpOffer {
+            vdevice: PathBuf::from("/dev/video2"),
+            payload: b"v=0".to_vec(),
+            resp,
+        };
+        ble_server
+            .connection()
+            .send(BleApi::Publish(PubSubTopic::SdpCall, offer))
+            .await
+            .unwrap();
+
+        let answer = rx.await.unwrap().unwrap();
+        assert_eq!(answer.await.unwrap(), "answer");
+    }
+
+    #[tokio::test]
+    async fn test_ble_server_shutdown() {
+        init_logger();
+        let mut mock_comm = MockMultiMobileCommService::new();
+
+        mock_comm
+            .expect_connected_mobiles()
+            .times(1)
+            .returning(|| vec!["00:11:22:33:44:55".to_string()]);
+        mock_comm.expect_release_all().times(1).returning(|| Ok(()));
+
+        let ble_server = BleServer::new(mock_comm, None, 4);
+
+        assert_eq!(
+            ble_server.connected_mobiles().await.unwrap(),
+            vec!["00:11:22:33:44:55".to_string()]
+        );
+
+        let server_conn = ble_server.connection();
+        assert!(ble_server.shutdown().await.is_ok());
+
+        //the request loop is gone after the shutdown
+        assert!(server_conn.is_closed());
+    }
 }
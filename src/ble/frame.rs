@@ -0,0 +1,465 @@
+//! Framing of the messages exchanged with the mobiles.
+//!
+//! A message larger than the MTU is split in chunks, every chunk is sent in a
+//! frame. A binary frame starts with an 8 bytes header, all the fields in big
+//! endian:
+//!
+//! | offset | size | field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 1    | version of the format, `FRAME_VERSION`  |
+//! | 1      | 1    | id of the message                       |
+//! | 2      | 2    | sequence number of the chunk            |
+//! | 4      | 2    | total length of the message             |
+//! | 6      | 2    | CRC-16 of the header fields and payload |
+//!
+//! The mobiles that predate the binary frames send a JSON `BufferComm` per
+//! chunk. The frames written by a mobile are told apart by their first byte,
+//! a JSON object never starts with `FRAME_VERSION`, the mobile picks the
+//! framing of the host info by the characteristic it reads.
+
+use anyhow::anyhow;
+use crc::{Crc, CRC_16_IBM_3740};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::ble_cmd_api::BleBuffer;
+use crate::error::Result;
+
+/// Version of the binary frame format.
+pub const FRAME_VERSION: u8 = 1;
+
+/// Length of the header of a binary frame.
+pub const HEADER_LEN: usize = 8;
+
+const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+
+/// Framing of the chunks of a message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// A JSON `BufferComm` per chunk, used by the older mobiles.
+    #[default]
+    Json,
+    /// A binary `Frame` per chunk.
+    Binary,
+}
+
+impl Framing {
+    /// Detects the framing of a chunk written by a mobile.
+    pub fn detect(data: &[u8]) -> Self {
+        match data.first() {
+            Some(&FRAME_VERSION) => Framing::Binary,
+            _ => Framing::Json,
+        }
+    }
+}
+
+//legacy chunk, the payload is a slice of the message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BufferComm {
+    pub remain_len: usize,
+    pub payload: String,
+}
+
+/// A chunk of a message in a binary frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub msg_id: u8,
+    pub seq: u16,
+    pub total_len: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Encodes the frame with its header.
+    pub fn encode(&self) -> BleBuffer {
+        let mut data = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        data.push(FRAME_VERSION);
+        data.push(self.msg_id);
+        data.extend_from_slice(&self.seq.to_be_bytes());
+        data.extend_from_slice(&self.total_len.to_be_bytes());
+
+        let crc = checksum(&data, &self.payload);
+        data.extend_from_slice(&crc.to_be_bytes());
+        data.extend_from_slice(&self.payload);
+        data
+    }
+
+    /// Decodes a frame, checking its version and checksum.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the frame is truncated, of an unknown version or
+    /// corrupted.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_LEN {
+            return Err(anyhow!("Frame too short: {} bytes", data.len()));
+        }
+
+        let (header, payload) = data.split_at(HEADER_LEN);
+        if header[0] != FRAME_VERSION {
+            return Err(anyhow!("Unknown frame version {}", header[0]));
+        }
+
+        let crc = u16::from_be_bytes([header[6], header[7]]);
+        if crc != checksum(&header[..6], payload) {
+            return Err(anyhow!("Frame checksum mismatch"));
+        }
+
+        Ok(Self {
+            msg_id: header[1],
+            seq: u16::from_be_bytes([header[2], header[3]]),
+            total_len: u16::from_be_bytes([header[4], header[5]]),
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+fn checksum(header: &[u8], payload: &[u8]) -> u16 {
+    let mut digest = CRC.digest();
+    digest.update(header);
+    digest.update(payload);
+    digest.finalize()
+}
+
+/// A message sent to a mobile one chunk at a time.
+#[derive(Debug)]
+pub struct Outgoing {
+    framing: Framing,
+    msg_id: u8,
+    message: String,
+    offset: usize,
+    seq: u16,
+}
+
+impl Outgoing {
+    /// Prepares a message to be sent with the given framing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message doesn't fit in binary frames.
+    pub fn new(framing: Framing, msg_id: u8, message: String) -> Result<Self> {
+        if framing == Framing::Binary && message.len() > u16::MAX as usize {
+            return Err(anyhow!("Message too long: {} bytes", message.len()));
+        }
+
+        Ok(Self { framing, msg_id, message, offset: 0, seq: 0 })
+    }
+
+    /// Returns `true` once the last chunk was sent.
+    pub fn is_done(&self) -> bool {
+        self.seq > 0 && self.offset >= self.message.len()
+    }
+
+    /// Returns the next chunk, framed to fit in `max_len` bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `max_len` can't hold any payload.
+    pub fn next_chunk(&mut self, max_len: usize) -> Result<BleBuffer> {
+        match self.framing {
+            Framing::Json => self.next_json_chunk(max_len),
+            Framing::Binary => self.next_binary_chunk(max_len),
+        }
+    }
+
+    //the legacy chunks are cut at char boundaries, the payload is a string
+    //escaped in the JSON object so it is shortened until the object fits
+    fn next_json_chunk(&mut self, max_len: usize) -> Result<BleBuffer> {
+        let remain = &self.message[self.offset..];
+        let first_char = remain.chars().next().map_or(0, char::len_utf8);
+
+        let mut end = max_len.min(remain.len());
+        loop {
+            while !remain.is_char_boundary(end) {
+                end -= 1;
+            }
+            //at least one char, even if it doesn't fit
+            end = end.max(first_char);
+
+            let chunk = serde_json::to_vec(&BufferComm {
+                remain_len: remain.len() - end,
+                payload: remain[..end].to_string(),
+            })?;
+
+            let excess = chunk.len().saturating_sub(max_len);
+            if excess == 0 || end == first_char {
+                self.offset += end;
+                self.seq = self.seq.saturating_add(1);
+                return Ok(chunk);
+            }
+            end = end.saturating_sub(excess);
+        }
+    }
+
+    fn next_binary_chunk(&mut self, max_len: usize) -> Result<BleBuffer> {
+        let chunk_len = max_len.saturating_sub(HEADER_LEN);
+        let remain = &self.message.as_bytes()[self.offset..];
+        if chunk_len == 0 && !remain.is_empty() {
+            return Err(anyhow!(
+                "Buffer of {} bytes can't hold a frame",
+                max_len
+            ));
+        }
+
+        let end = chunk_len.min(remain.len());
+        let frame = Frame {
+            msg_id: self.msg_id,
+            seq: self.seq,
+            total_len: self.message.len() as u16,
+            payload: remain[..end].to_vec(),
+        };
+
+        self.offset += end;
+        self.seq = self
+            .seq
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("Too many frames in message"))?;
+
+        Ok(frame.encode())
+    }
+}
+
+//message being received in binary frames
+#[derive(Debug)]
+struct Incoming {
+    msg_id: u8,
+    next_seq: u16,
+    total_len: usize,
+    complete: bool,
+}
+
+/// Assembles the messages written by a mobile from their chunks.
+///
+/// The binary frames of a message must arrive in order, a frame repeated
+/// right after it was received is ignored, a missing or unexpected one drops
+/// the message. A first frame with the id of a complete message starts a new
+/// message with that id.
+#[derive(Debug, Default)]
+pub struct Assembler {
+    incoming: Option<Incoming>,
+    buffer: Vec<u8>,
+}
+
+impl Assembler {
+    /// Adds a chunk written by the mobile.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the chunk can't be decoded or is out of order, the
+    /// partial message is dropped.
+    ///
+    /// # Returns
+    ///
+    /// The message once its last chunk is received.
+    pub fn push(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let result = match Framing::detect(data) {
+            Framing::Json => self.push_json(data),
+            Framing::Binary => self.push_binary(data),
+        };
+
+        if result.is_err() {
+            self.incoming = None;
+            self.buffer.clear();
+        }
+
+        result
+    }
+
+    fn push_json(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let chunk = serde_json::from_slice::<BufferComm>(data)?;
+        self.incoming = None;
+        self.buffer.extend_from_slice(chunk.payload.as_bytes());
+
+        if chunk.remain_len == 0 {
+            return Ok(Some(std::mem::take(&mut self.buffer)));
+        }
+        Ok(None)
+    }
+
+    fn push_binary(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let frame = Frame::decode(data)?;
+
+        match &self.incoming {
+            Some(incoming)
+                if incoming.msg_id == frame.msg_id
+                    && frame.seq < incoming.next_seq
+                    && !(incoming.complete && frame.seq == 0) =>
+            {
+                warn!(
+                    "Duplicated frame {} of message {} ignored",
+                    frame.seq, frame.msg_id
+                );
+                return Ok(None);
+            }
+            _ if frame.seq == 0 => {
+                if !self.buffer.is_empty() {
+                    warn!(
+                        "Partial message dropped by message {}",
+                        frame.msg_id
+                    );
+                }
+                self.buffer.clear();
+                self.incoming = Some(Incoming {
+                    msg_id: frame.msg_id,
+                    next_seq: 0,
+                    total_len: frame.total_len as usize,
+                    complete: false,
+                });
+            }
+            _ => {}
+        }
+
+        let incoming = self
+            .incoming
+            .as_mut()
+            .filter(|incoming| incoming.msg_id == frame.msg_id)
+            .ok_or_else(|| {
+                anyhow!("Frame of unknown message {}", frame.msg_id)
+            })?;
+
+        if frame.seq != incoming.next_seq {
+            return Err(anyhow!(
+                "Frame {} of message {} lost",
+                incoming.next_seq,
+                frame.msg_id
+            ));
+        }
+        if frame.total_len as usize != incoming.total_len {
+            return Err(anyhow!("Length of message {} changed", frame.msg_id));
+        }
+        if self.buffer.len() + frame.payload.len() > incoming.total_len {
+            return Err(anyhow!("Message {} too long", frame.msg_id));
+        }
+
+        self.buffer.extend_from_slice(&frame.payload);
+        incoming.next_seq = incoming.next_seq.wrapping_add(1);
+
+        if self.buffer.len() == incoming.total_len {
+            incoming.complete = true;
+            return Ok(Some(std::mem::take(&mut self.buffer)));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn send_all(mut outgoing: Outgoing, max_len: usize) -> Vec<BleBuffer> {
+        let mut chunks = Vec::new();
+        while !outgoing.is_done() {
+            let chunk = outgoing.next_chunk(max_len).unwrap();
+            assert!(chunk.len() <= max_len || max_len < HEADER_LEN);
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        init_logger();
+        let frame = Frame {
+            msg_id: 7,
+            seq: 2,
+            total_len: 300,
+            payload: b"payload".to_vec(),
+        };
+
+        let mut data = frame.encode();
+        assert_eq!(data.len(), HEADER_LEN + 7);
+        assert_eq!(Framing::detect(&data), Framing::Binary);
+        assert_eq!(Frame::decode(&data).unwrap(), frame);
+
+        //a corrupted byte is detected
+        data[HEADER_LEN] ^= 0x01;
+        assert!(Frame::decode(&data).is_err());
+        assert!(Frame::decode(&data[..4]).is_err());
+        assert_eq!(Framing::detect(b"{\"remain_len\":0}"), Framing::Json);
+    }
+
+    #[test]
+    fn test_binary_message_in_chunks() {
+        init_logger();
+        let message = "Hôst ñame 📷".repeat(10);
+        let outgoing =
+            Outgoing::new(Framing::Binary, 3, message.clone()).unwrap();
+        let chunks = send_all(outgoing, 20);
+        assert!(chunks.len() > 1);
+
+        let mut assembler = Assembler::default();
+        let (last, first) = chunks.split_last().unwrap();
+        for chunk in first {
+            assert_eq!(assembler.push(chunk).unwrap(), None);
+            //a repeated frame is ignored
+            assert_eq!(assembler.push(chunk).unwrap(), None);
+        }
+        let received = assembler.push(last).unwrap().unwrap();
+        assert_eq!(String::from_utf8(received).unwrap(), message);
+        //the last frame repeated is ignored as well
+        assert_eq!(assembler.push(last).unwrap(), None);
+
+        //the id of a complete message can be reused
+        let reused = "Öther hôst".repeat(5);
+        let outgoing =
+            Outgoing::new(Framing::Binary, 3, reused.clone()).unwrap();
+        let mut received = None;
+        for chunk in send_all(outgoing, 20) {
+            received = assembler.push(&chunk).unwrap();
+        }
+        assert_eq!(String::from_utf8(received.unwrap()).unwrap(), reused);
+
+        //a lost frame drops the message
+        let outgoing = Outgoing::new(Framing::Binary, 4, message).unwrap();
+        let chunks = send_all(outgoing, 20);
+        assert_eq!(assembler.push(&chunks[0]).unwrap(), None);
+        assert!(assembler.push(&chunks[2]).is_err());
+        assert!(assembler.push(&chunks[1]).is_err());
+    }
+
+    #[test]
+    fn test_json_message_in_chunks() {
+        init_logger();
+        //the chunks are cut at char boundaries
+        let message = "Hôst ñame 📷".to_string();
+        let outgoing =
+            Outgoing::new(Framing::Json, 0, message.clone()).unwrap();
+        let chunks = send_all(outgoing, 3);
+
+        let mut assembler = Assembler::default();
+        let mut received = None;
+        for chunk in &chunks {
+            let legacy: BufferComm = serde_json::from_slice(chunk).unwrap();
+            assert_eq!(legacy.payload.chars().count(), 1);
+            received = assembler.push(chunk).unwrap();
+        }
+        assert_eq!(String::from_utf8(received.unwrap()).unwrap(), message);
+
+        //the whole JSON object fits when the buffer is large enough
+        let outgoing =
+            Outgoing::new(Framing::Json, 0, "\"quoted\"\r\n".repeat(8))
+                .unwrap();
+        let chunks = send_all(outgoing, 40);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 40));
+    }
+
+    #[test]
+    fn test_empty_message() {
+        init_logger();
+        let outgoing =
+            Outgoing::new(Framing::Binary, 1, String::new()).unwrap();
+        let chunks = send_all(outgoing, 20);
+        assert_eq!(chunks.len(), 1);
+
+        let mut assembler = Assembler::default();
+        assert_eq!(assembler.push(&chunks[0]).unwrap(), Some(Vec::new()));
+
+        let mut outgoing =
+            Outgoing::new(Framing::Binary, 1, "host".to_string()).unwrap();
+        assert!(outgoing.next_chunk(HEADER_LEN).is_err());
+    }
+}
@@ -1,28 +1,36 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
 use log::{error, info};
 
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
-use tokio::sync::broadcast;
+use tokio::sync::{mpsc, oneshot};
 
 use super::{
-    ble_cmd_api::{Address, BleBuffer, PubSubPublisher, PubSubSubscriber},
+    ble_cmd_api::{
+        Address, BleBuffer, MobileActivityInfo, MobileInfo, MobilesStatus,
+        PubSubPublisher, PubSubSubscriber, SdpAnswer, VDeviceInfo,
+    },
     ble_server::MultiMobileCommService,
+    frame::{Assembler, Framing, Outgoing},
 };
 use crate::vdevice_builder::VDevice;
-use crate::{app_data::MobileSchema, error::Result};
+use crate::{
+    app_data::{
+        unix_timestamp, AppDataWatcher, ExpiredMobile, MobileActivity,
+        MobileId, MobileSchema, QuarantinedRecord, RetentionPolicy,
+    },
+    error::Result,
+};
 
 #[cfg(test)]
 use mockall::automock;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct BufferComm {
-    pub remain_len: usize,
-    pub payload: String,
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HostProvInfo {
     pub id: String,
@@ -49,6 +57,14 @@ pub trait AppDataStore: Send + Sync + 'static {
     /// Returns an error if the host information is not found in the data store.
     fn get_host_prov_info(&self) -> Result<HostProvInfo>;
 
+    /// Renames the host, the name advertised to the mobiles.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name is empty or if the host information is
+    /// not found in the data store.
+    fn rename_host(&mut self, name: &str) -> Result<()>;
+
     /// Adds a mobile device to the data store.
     ///
     /// # Errors
@@ -57,6 +73,118 @@ pub trait AppDataStore: Send + Sync + 'static {
     fn add_mobile(&mut self, mobile: &MobileSchema) -> Result<()>;
 
     fn get_mobile(&self, id: &str) -> Result<MobileSchema>;
+
+    /// Replaces the record of a registered mobile device.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the mobile device is not registered.
+    fn update_mobile(&mut self, mobile: &MobileSchema) -> Result<()>;
+
+    /// Retrieves every mobile device registered in the data store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the mobile devices can't be read from the data store.
+    fn list_mobiles(&self) -> Result<Vec<MobileSchema>>;
+
+    /// Removes a mobile device from the data store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the mobile device is not registered.
+    fn remove_mobile(&mut self, id: &str) -> Result<()>;
+
+    /// Retrieves the usage history of a mobile device, empty if none was
+    /// recorded yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the history can't be read from the data store.
+    fn get_mobile_activity(&self, id: &str) -> Result<MobileActivity>;
+
+    /// Stores the usage history of a mobile device.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the mobile device is not registered.
+    fn set_mobile_activity(
+        &mut self, id: &str, activity: &MobileActivity,
+    ) -> Result<()>;
+
+    /// Retrieves the usage history of every registered mobile device.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the history can't be read from the data store.
+    fn list_mobile_activity(&self) -> Result<Vec<(MobileId, MobileActivity)>>;
+
+    /// Watches the changes of the pairing data made from now on.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data store can't be watched.
+    fn watch(&self) -> Result<AppDataWatcher>;
+
+    /// Lists the records moved to quarantine because they can't be decoded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the quarantine can't be read.
+    fn quarantined(&self) -> Result<Vec<QuarantinedRecord>>;
+}
+
+//lets the daemon pick the database backend at runtime
+impl<T: AppDataStore + ?Sized> AppDataStore for Box<T> {
+    fn get_host_prov_info(&self) -> Result<HostProvInfo> {
+        (**self).get_host_prov_info()
+    }
+
+    fn rename_host(&mut self, name: &str) -> Result<()> {
+        (**self).rename_host(name)
+    }
+
+    fn add_mobile(&mut self, mobile: &MobileSchema) -> Result<()> {
+        (**self).add_mobile(mobile)
+    }
+
+    fn get_mobile(&self, id: &str) -> Result<MobileSchema> {
+        (**self).get_mobile(id)
+    }
+
+    fn update_mobile(&mut self, mobile: &MobileSchema) -> Result<()> {
+        (**self).update_mobile(mobile)
+    }
+
+    fn list_mobiles(&self) -> Result<Vec<MobileSchema>> {
+        (**self).list_mobiles()
+    }
+
+    fn remove_mobile(&mut self, id: &str) -> Result<()> {
+        (**self).remove_mobile(id)
+    }
+
+    fn get_mobile_activity(&self, id: &str) -> Result<MobileActivity> {
+        (**self).get_mobile_activity(id)
+    }
+
+    fn set_mobile_activity(
+        &mut self, id: &str, activity: &MobileActivity,
+    ) -> Result<()> {
+        (**self).set_mobile_activity(id, activity)
+    }
+
+    fn list_mobile_activity(&self) -> Result<Vec<(MobileId, MobileActivity)>> {
+        (**self).list_mobile_activity()
+    }
+
+    fn watch(&self) -> Result<AppDataWatcher> {
+        (**self).watch()
+    }
+
+    fn quarantined(&self) -> Result<Vec<QuarantinedRecord>> {
+        (**self).quarantined()
+    }
 }
 
 pub type VDeviceMap = HashMap<PathBuf, VDevice>;
@@ -68,6 +196,10 @@ pub trait VDeviceBuilderOps: Send + Sync + 'static {
 //Provisioning:   ReadHostInfo->WriteMobileInfo->WriteMobileId->ReadyToStream
 //Identification: WriteMobileId->ReadyToStream
 //
+//Reading the host info restarts the provisioning and writing the mobile id
+//restarts the identification, from any other state. A mobile that stays in
+//a state before ReadyToStream longer than its deadline is evicted.
+//
 #[derive(Debug)]
 enum MobileDataState {
     ReadHostInfo,
@@ -76,27 +208,118 @@ enum MobileDataState {
 
     WriteMobileId,
 
-    SaveMobileData { mobile: MobileSchema },
+    SaveMobileData {
+        mobile: MobileSchema,
+    },
 
-    ReadyToStream { virtual_devices: VDeviceMap },
+    ReadyToStream {
+        mobile_id: MobileId,
+        virtual_devices: VDeviceMap,
+        //set once the mobile answers its first offer
+        streaming_since: Option<Instant>,
+        //streaming session waiting for the answer to its offer
+        answer_to: Option<oneshot::Sender<String>>,
+        //offers sent once the previous one is answered
+        queued_offers: VecDeque<(BleBuffer, oneshot::Sender<String>)>,
+    },
+}
+
+impl MobileDataState {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::ReadHostInfo => "ReadHostInfo",
+            Self::WriteMobileInfo => "WriteMobileInfo",
+            Self::WriteMobileId => "WriteMobileId",
+            Self::SaveMobileData { .. } => "SaveMobileData",
+            Self::ReadyToStream { .. } => "ReadyToStream",
+        }
+    }
+
+    //id of the mobile, once it is identified
+    fn mobile_id(&self) -> Option<&str> {
+        match self {
+            Self::SaveMobileData { mobile } => Some(&mobile.id),
+            Self::ReadyToStream { mobile_id, .. } => Some(mobile_id),
+            _ => None,
+        }
+    }
 }
 
 //State for the communication buffer
 enum CommBufferStatus {
-    RemainLen(usize),      //used in queries
-    CurrentBuffer(String), //used in commands
+    Outgoing(Outgoing),  //used in queries
+    Incoming(Assembler), //used in commands
+}
+
+//MTU until the mobile negotiates a larger one
+const DEFAULT_MTU: usize = 23;
+
+//opcode and handle of a notification, the largest ATT header of a chunk
+const ATT_HEADER_LEN: usize = 3;
+
+/// Time a mobile may stay in each state before it reaches `ReadyToStream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateDeadlines {
+    pub read_host_info: Duration,
+    pub write_mobile_info: Duration,
+    pub write_mobile_id: Duration,
+    /// Time between the mobile id and the subscription to the SDP calls.
+    pub subscribe: Duration,
+}
+
+impl Default for StateDeadlines {
+    fn default() -> Self {
+        Self {
+            read_host_info: Duration::from_secs(30),
+            write_mobile_info: Duration::from_secs(60),
+            write_mobile_id: Duration::from_secs(30),
+            subscribe: Duration::from_secs(30),
+        }
+    }
+}
+
+impl StateDeadlines {
+    //a streaming mobile has no deadline
+    fn of(&self, state: &MobileDataState) -> Option<Duration> {
+        match state {
+            MobileDataState::ReadHostInfo => Some(self.read_host_info),
+            MobileDataState::WriteMobileInfo => Some(self.write_mobile_info),
+            MobileDataState::WriteMobileId => Some(self.write_mobile_id),
+            MobileDataState::SaveMobileData { .. } => Some(self.subscribe),
+            MobileDataState::ReadyToStream { .. } => None,
+        }
+    }
 }
 
 struct ConnectedMobileData {
     pub mobile_state: MobileDataState,
+    //when the mobile entered its state
+    pub state_since: Instant,
+    //negotiated MTU of the connection
+    pub mtu: usize,
+    //framing of the chunks sent to the mobile, the one it reads or writes
+    pub framing: Framing,
+    //transfer in flight with the mobile
     pub buffer_status: Option<CommBufferStatus>,
 }
 
-//caller to send SDP data as a publisher
-//to all mobiles subscribed
-struct MobileSdpCaller {
-    pub max_buffer_len: usize,
-    pub publisher: PubSubPublisher,
+impl ConnectedMobileData {
+    fn new(
+        mobile_state: MobileDataState, buffer_status: Option<CommBufferStatus>,
+    ) -> Self {
+        Self {
+            mobile_state,
+            state_since: Instant::now(),
+            mtu: DEFAULT_MTU,
+            framing: Framing::default(),
+            buffer_status,
+        }
+    }
+
+    //largest chunk sent to the mobile
+    fn chunk_size(&self) -> usize {
+        self.mtu.saturating_sub(ATT_HEADER_LEN)
+    }
 }
 
 pub struct MobileComm<Db, VDevBuilder> {
@@ -106,32 +329,159 @@ pub struct MobileComm<Db, VDevBuilder> {
     vdevice_index: HashMap<PathBuf, Address>,
 
     host_info: String,
-    sdp_caller: MobileSdpCaller,
+    //id of the next message sent to a mobile
+    next_msg_id: u8,
+    //channel of each mobile subscribed to the SDP calls
+    sdp_subscribers: HashMap<Address, PubSubPublisher>,
+    //virtual devices ready to start a streaming session
+    new_vdevices: Option<mpsc::UnboundedSender<PathBuf>>,
+    deadlines: StateDeadlines,
     vdev_builder: VDevBuilder,
 }
 
 impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps>
     MobileComm<Db, VDevBuilder>
 {
-    pub fn new(db: Db, vdev_builder: VDevBuilder) -> Result<Self> {
-        let host = db.get_host_prov_info()?;
-        let host_info = serde_json::to_string(&host)?;
+    //drops the connection state of a mobile and its virtual devices,
+    //returns false if the mobile was not connected
+    fn release_mobile(&mut self, addr: &Address) -> bool {
+        let Some(connected_data) = self.mobiles_connected.remove(addr) else {
+            return false;
+        };
+
+        //closes the notifications of the mobile
+        self.sdp_subscribers.remove(addr);
+
+        if let MobileDataState::ReadyToStream { virtual_devices, .. } =
+            connected_data.mobile_state
+        {
+            //remove the virtual devices
+            for (path, _) in virtual_devices {
+                info!("Removing index with path {:?}", path);
+                if self.vdevice_index.remove(&path).is_none() {
+                    error!("Device not found in vdevice index {:?}", path);
+                }
+            }
+        }
+
+        true
+    }
 
-        let (sender, _) = broadcast::channel(16);
-        let sdp_caller = MobileSdpCaller {
-            max_buffer_len: 19, //default mtu size 23 - 4 bytes for header
-            publisher: sender,
+    //ends the session of a mobile, accounting the time it streamed,
+    //returns false if the mobile was not connected
+    fn end_session(&mut self, addr: &Address) -> bool {
+        if let Some(ConnectedMobileData {
+            mobile_state:
+                MobileDataState::ReadyToStream {
+                    mobile_id,
+                    streaming_since: Some(streaming_since),
+                    ..
+                },
+            ..
+        }) = self.mobiles_connected.get(addr)
+        {
+            let id = mobile_id.clone();
+            let streamed = streaming_since.elapsed().as_secs();
+            self.track_activity(&id, |activity| {
+                activity.streaming_secs += streamed;
+            });
+        }
+
+        self.release_mobile(addr)
+    }
+
+    //publishes the next queued offer of a mobile, the mobile answers one
+    //offer at a time
+    fn send_next_offer(&mut self, addr: &Address) -> Result<()> {
+        let Some(ConnectedMobileData {
+            mobile_state:
+                MobileDataState::ReadyToStream { answer_to, queued_offers, .. },
+            ..
+        }) = self.mobiles_connected.get_mut(addr)
+        else {
+            return Ok(());
         };
 
+        if answer_to.as_ref().is_some_and(|tx| !tx.is_closed()) {
+            return Ok(());
+        }
+
+        //the offers of the sessions already gone are dropped
+        queued_offers.retain(|(_, tx)| !tx.is_closed());
+        let Some((offer, tx)) = queued_offers.pop_front() else {
+            *answer_to = None;
+            return Ok(());
+        };
+        *answer_to = Some(tx);
+
+        self.publish_to(addr.clone(), offer)
+    }
+
+    //moves a connected mobile to the next state, its connection is kept
+    fn move_to(
+        &mut self, addr: &Address, mobile_state: MobileDataState,
+        buffer_status: Option<CommBufferStatus>,
+    ) {
+        if let Some(data) = self.mobiles_connected.get_mut(addr) {
+            info!("Mobile: {:?} in state {}", addr, mobile_state.name());
+            data.mobile_state = mobile_state;
+            data.state_since = Instant::now();
+            data.buffer_status = buffer_status;
+        }
+    }
+
+    //the chunks written by the mobile tell its framing
+    fn note_framing(&mut self, addr: &Address, data: &[u8]) {
+        if let Some(connected_data) = self.mobiles_connected.get_mut(addr) {
+            connected_data.framing = Framing::detect(data);
+        }
+    }
+
+    //the activity is informative, failing to track it doesn't fail the mobile
+    fn track_activity(
+        &mut self, id: &str, update: impl FnOnce(&mut MobileActivity),
+    ) {
+        let result =
+            self.db.get_mobile_activity(id).and_then(|mut activity| {
+                update(&mut activity);
+                self.db.set_mobile_activity(id, &activity)
+            });
+
+        if let Err(e) = result {
+            error!("Failed to track activity of mobile {}, error: {:?}", id, e);
+        }
+    }
+
+    pub fn new(db: Db, vdev_builder: VDevBuilder) -> Result<Self> {
+        let host_info = serde_json::to_string(&db.get_host_prov_info()?)?;
+
         Ok(Self {
             db,
             mobiles_connected: HashMap::new(),
             vdevice_index: HashMap::new(),
             host_info,
-            sdp_caller,
+            next_msg_id: 0,
+            sdp_subscribers: HashMap::new(),
+            new_vdevices: None,
+            deadlines: StateDeadlines::default(),
             vdev_builder,
         })
     }
+
+    /// Sends the path of every virtual device created for a subscribed
+    /// mobile, so a streaming session can offer to stream it.
+    pub fn with_streaming(
+        mut self, new_vdevices: mpsc::UnboundedSender<PathBuf>,
+    ) -> Self {
+        self.new_vdevices = Some(new_vdevices);
+        self
+    }
+
+    /// Sets the time a mobile may stay in each state before it is evicted.
+    pub fn with_deadlines(mut self, deadlines: StateDeadlines) -> Self {
+        self.deadlines = deadlines;
+        self
+    }
 }
 
 #[async_trait]
@@ -139,19 +489,7 @@ impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps> MultiMobileCommService
     for MobileComm<Db, VDevBuilder>
 {
     fn device_disconnected(&mut self, addr: Address) -> Result<()> {
-        if let Some(connected_data) = self.mobiles_connected.remove(&addr) {
-            if let MobileDataState::ReadyToStream { virtual_devices } =
-                connected_data.mobile_state
-            {
-                //remove the virtual devices
-                for (path, _) in virtual_devices {
-                    info!("Removing index with path {:?}", path);
-                    if self.vdevice_index.remove(&path).is_none() {
-                        error!("Device not found in vdevice index {:?}", path);
-                    }
-                }
-            }
-
+        if self.end_session(&addr) {
             info!(
                 "Mobile: {:?} disconnected and removed from connected devices",
                 addr
@@ -164,65 +502,66 @@ impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps> MultiMobileCommService
     }
 
     fn read_host_info(
-        &mut self, addr: Address, max_buffer_len: usize,
+        &mut self, addr: Address, mtu: usize, framing: Framing,
     ) -> Result<BleBuffer> {
         info!("Host info requested by: {:?}", addr);
 
-        let total_len = self.host_info.len();
+        //a mobile can always start the provisioning again
+        if self.mobiles_connected.get(&addr).is_some_and(|data| {
+            !matches!(data.mobile_state, MobileDataState::ReadHostInfo)
+        }) {
+            info!("Mobile: {:?} restarts the provisioning", addr);
+            self.end_session(&addr);
+        }
 
         //if mobile is not connected, add it with the state ReadHostInfo
         //start condition
         if !self.mobiles_connected.contains_key(&addr) {
+            let host_info = Outgoing::new(
+                framing,
+                self.next_msg_id,
+                self.host_info.clone(),
+            )?;
+            self.next_msg_id = self.next_msg_id.wrapping_add(1);
+
             self.mobiles_connected.insert(
                 addr.clone(),
-                ConnectedMobileData {
-                    mobile_state: MobileDataState::ReadHostInfo,
-                    buffer_status: Some(CommBufferStatus::RemainLen(total_len)),
-                },
+                ConnectedMobileData::new(
+                    MobileDataState::ReadHostInfo,
+                    Some(CommBufferStatus::Outgoing(host_info)),
+                ),
             );
         }
 
-        if let ConnectedMobileData {
-            mobile_state: MobileDataState::ReadHostInfo,
-            buffer_status: Some(CommBufferStatus::RemainLen(remain)),
-        } = self
+        let data = self
             .mobiles_connected
             .get_mut(&addr)
-            .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?
+            .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?;
+        //the read requests carry the MTU of the connection
+        data.mtu = mtu;
+        data.framing = framing;
+        let chunk_size = data.chunk_size();
+
+        if let ConnectedMobileData {
+            mobile_state: MobileDataState::ReadHostInfo,
+            buffer_status: Some(CommBufferStatus::Outgoing(host_info)),
+            ..
+        } = data
         {
-            let initial_len = total_len - *remain;
+            let chunk = host_info.next_chunk(chunk_size)?;
 
-            let ble_comm = if max_buffer_len >= *remain {
-                *remain = total_len;
+            if host_info.is_done() {
                 //move to next state
-                self.mobiles_connected.insert(
-                    addr.clone(),
-                    ConnectedMobileData {
-                        mobile_state: MobileDataState::WriteMobileInfo,
-                        buffer_status: Some(CommBufferStatus::CurrentBuffer(
-                            "".to_string(),
-                        )),
-                    },
+                self.move_to(
+                    &addr,
+                    MobileDataState::WriteMobileInfo,
+                    Some(CommBufferStatus::Incoming(Assembler::default())),
                 );
-                info!("Mobile: {:?} in state WriteMobileInfo", addr);
-
-                BufferComm {
-                    remain_len: 0,
-                    payload: self.host_info[initial_len..total_len].to_owned(),
-                }
-            } else {
-                *remain -= max_buffer_len;
-                BufferComm {
-                    remain_len: *remain,
-                    payload: self.host_info
-                        [initial_len..initial_len + max_buffer_len]
-                        .to_owned(),
-                }
-            };
+            }
 
-            info!("Sending host info: {:?}", ble_comm);
+            info!("Sending host info chunk of {} bytes", chunk.len());
 
-            return Ok(serde_json::to_vec(&ble_comm)?);
+            return Ok(chunk);
         }
 
         error!("Mobile is not reading host info");
@@ -233,38 +572,33 @@ impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps> MultiMobileCommService
         &mut self, addr: Address, data: BleBuffer,
     ) -> Result<()> {
         info!("Registering mobile: {:?}", addr);
+        self.note_framing(&addr, &data);
 
         if let ConnectedMobileData {
             mobile_state: MobileDataState::WriteMobileInfo,
-            buffer_status: Some(CommBufferStatus::CurrentBuffer(current_buffer)),
+            buffer_status: Some(CommBufferStatus::Incoming(assembler)),
+            ..
         } = self
             .mobiles_connected
             .get_mut(&addr)
             .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?
         {
-            let buff_comm = serde_json::from_slice::<BufferComm>(&data)?;
-
-            info!("buff_comm {:?}", buff_comm);
-
-            current_buffer.push_str(&buff_comm.payload);
-
-            info!("current_buffer {:?}", buff_comm);
-
-            if buff_comm.remain_len == 0 {
-                let mobile = serde_json::from_str(&current_buffer)?;
-                self.db.add_mobile(&mobile)?;
-                info!("Mobile registered: {:?}", mobile);
+            if let Some(message) = assembler.push(&data)? {
+                let mobile: MobileSchema = serde_json::from_slice(&message)?;
+                //a mobile provisioned again keeps its single registration
+                if self.db.get_mobile(&mobile.id).is_ok() {
+                    self.db.update_mobile(&mobile)?;
+                    info!("Mobile registration updated: {:?}", mobile);
+                } else {
+                    self.db.add_mobile(&mobile)?;
+                    info!("Mobile registered: {:?}", mobile);
+                }
                 //move to next state
-                self.mobiles_connected.insert(
-                    addr.clone(),
-                    ConnectedMobileData {
-                        mobile_state: MobileDataState::WriteMobileId,
-                        buffer_status: Some(CommBufferStatus::CurrentBuffer(
-                            "".to_string(),
-                        )),
-                    },
+                self.move_to(
+                    &addr,
+                    MobileDataState::WriteMobileId,
+                    Some(CommBufferStatus::Incoming(Assembler::default())),
                 );
-                info!("Mobile: {:#?} in state WriteMobileId", mobile);
             }
         } else {
             error!("Mobile is not writing mobile info");
@@ -279,51 +613,51 @@ impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps> MultiMobileCommService
     ) -> Result<()> {
         info!("Mobile Pnp ID: {:?}", addr);
 
+        //a mobile can always start the identification again
+        if self.mobiles_connected.get(&addr).is_some_and(|data| {
+            !matches!(data.mobile_state, MobileDataState::WriteMobileId)
+        }) {
+            info!("Mobile: {:?} restarts the identification", addr);
+            self.end_session(&addr);
+        }
+
         if !self.mobiles_connected.contains_key(&addr) {
             //new connection, already registered
             self.mobiles_connected.insert(
                 addr.clone(),
-                ConnectedMobileData {
-                    mobile_state: MobileDataState::WriteMobileId,
-                    buffer_status: Some(CommBufferStatus::CurrentBuffer(
-                        "".to_string(),
-                    )),
-                },
+                ConnectedMobileData::new(
+                    MobileDataState::WriteMobileId,
+                    Some(CommBufferStatus::Incoming(Assembler::default())),
+                ),
             );
         }
+        self.note_framing(&addr, &data);
 
         if let ConnectedMobileData {
             mobile_state: MobileDataState::WriteMobileId,
-            buffer_status: Some(CommBufferStatus::CurrentBuffer(current_buffer)),
+            buffer_status: Some(CommBufferStatus::Incoming(assembler)),
+            ..
         } = self
             .mobiles_connected
             .get_mut(&addr)
             .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?
         {
-            let buff_comm = serde_json::from_slice::<BufferComm>(&data)?;
-
-            info!("buff_comm {:?}", buff_comm);
-
-            current_buffer.push_str(&buff_comm.payload);
-
-            info!("current_buffer {:?}", buff_comm);
-
-            if buff_comm.remain_len == 0 {
-                let mobile_id = current_buffer.clone();
+            if let Some(message) = assembler.push(&data)? {
+                let mobile_id = String::from_utf8(message)?;
                 if let Ok(mobile) = self.db.get_mobile(&mobile_id) {
                     info!("Mobile: {:#?} found", mobile);
+                    self.track_activity(&mobile_id, |activity| {
+                        activity.last_connected_at = Some(unix_timestamp());
+                        activity.connection_count += 1;
+                    });
                     //move to next State
-                    self.mobiles_connected.insert(
-                        addr.clone(),
-                        ConnectedMobileData {
-                            mobile_state: MobileDataState::SaveMobileData {
-                                mobile,
-                            },
-                            buffer_status: None,
-                        },
+                    self.move_to(
+                        &addr,
+                        MobileDataState::SaveMobileData { mobile },
+                        None,
                     );
                 } else {
-                    error!("Mobile with id: {current_buffer} not found");
+                    error!("Mobile with id: {mobile_id} not found");
                     return Err(anyhow!("Mobile not found"));
                 }
             }
@@ -336,13 +670,14 @@ impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps> MultiMobileCommService
     }
 
     async fn subscribe_to_sdp_req(
-        &mut self, addr: String, max_size: usize,
+        &mut self, addr: String, mtu: usize,
     ) -> Result<PubSubSubscriber> {
         info!("Subscribe to SDP call: {:?}", addr);
         //get the virtual device
         let vdev_map = if let Some(ConnectedMobileData {
             mobile_state: MobileDataState::SaveMobileData { mobile },
             buffer_status: None,
+            ..
         }) = self.mobiles_connected.get(&addr)
         {
             self.vdev_builder.create_from(mobile.clone()).await?
@@ -355,7 +690,9 @@ impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps> MultiMobileCommService
 
         if let Some(ConnectedMobileData {
             mobile_state: MobileDataState::SaveMobileData { mobile },
+            framing,
             buffer_status: None,
+            ..
         }) = self.mobiles_connected.remove(&addr)
         {
             info!("Mobile: {:#?} is subscribe to SDP call", mobile);
@@ -363,56 +700,322 @@ impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps> MultiMobileCommService
             //update the index
             for (path, _) in &vdev_map {
                 self.vdevice_index.insert(path.clone(), addr.clone());
+                if let Some(new_vdevices) = &self.new_vdevices {
+                    let _ = new_vdevices.send(path.clone());
+                }
             }
 
-            //move to next state
+            //move to next state, the notifications are sized for the MTU
+            //of this mobile
             self.mobiles_connected.insert(
                 addr.clone(),
                 ConnectedMobileData {
                     mobile_state: MobileDataState::ReadyToStream {
+                        mobile_id: mobile.id.clone(),
                         virtual_devices: vdev_map,
+                        streaming_since: None,
+                        answer_to: None,
+                        queued_offers: VecDeque::new(),
                     },
-                    buffer_status: Some(CommBufferStatus::CurrentBuffer(
-                        "".to_string(),
+                    state_since: Instant::now(),
+                    mtu,
+                    framing,
+                    buffer_status: Some(CommBufferStatus::Incoming(
+                        Assembler::default(),
                     )),
                 },
             );
-
-            //update the max buffer len
-            self.sdp_caller.max_buffer_len = max_size;
         } else {
             return Err(anyhow!(
                 "Mobile not ready is not ready to start streaming"
             ));
         }
 
-        Ok(self.sdp_caller.publisher.subscribe())
+        //a new subscription replaces the previous one
+        let (publisher, subscriber) = mpsc::unbounded_channel();
+        self.sdp_subscribers.insert(addr, publisher);
+
+        Ok(subscriber)
+    }
+
+    fn publish_to(&mut self, addr: Address, payload: BleBuffer) -> Result<()> {
+        let publisher = self
+            .sdp_subscribers
+            .get(&addr)
+            .ok_or_else(|| anyhow!("Mobile {} is not subscribed", addr))?;
+        let connected_data = self
+            .mobiles_connected
+            .get(&addr)
+            .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?;
+
+        //the chunks are sized for the MTU of this mobile
+        let mut message = Outgoing::new(
+            connected_data.framing,
+            self.next_msg_id,
+            String::from_utf8(payload)?,
+        )?;
+        self.next_msg_id = self.next_msg_id.wrapping_add(1);
+
+        while !message.is_done() {
+            let chunk = message.next_chunk(connected_data.chunk_size())?;
+            publisher
+                .send(chunk)
+                .map_err(|_| anyhow!("Mobile {} unsubscribed", addr))?;
+        }
+
+        info!("SDP call published to mobile {:?}", addr);
+        Ok(())
+    }
+
+    fn offer_sdp(
+        &mut self, vdevice: PathBuf, offer: BleBuffer,
+    ) -> Result<SdpAnswer> {
+        let addr =
+            self.vdevice_index.get(&vdevice).cloned().ok_or_else(|| {
+                anyhow!("Virtual device {:?} not found", vdevice)
+            })?;
+
+        let Some(ConnectedMobileData {
+            mobile_state:
+                MobileDataState::ReadyToStream {
+                    mobile_id,
+                    virtual_devices,
+                    queued_offers,
+                    ..
+                },
+            ..
+        }) = self.mobiles_connected.get_mut(&addr)
+        else {
+            return Err(anyhow!("Mobile {} is not ready to stream", addr));
+        };
+
+        let (tx, answer) = oneshot::channel();
+        queued_offers.push_back((offer, tx));
+
+        //the offered camera is the last one the mobile streams
+        let id = mobile_id.clone();
+        let camera = virtual_devices.get(&vdevice).map(|v| v.name.clone());
+
+        info!("Offering to stream {:?} from mobile {:?}", vdevice, addr);
+        self.send_next_offer(&addr)?;
+
+        if let Some(camera) = camera {
+            self.track_activity(&id, |activity| {
+                activity.last_camera = Some(camera);
+            });
+        }
+
+        Ok(answer)
+    }
+
+    fn evict_expired(&mut self) -> Vec<Address> {
+        let expired: Vec<Address> = self
+            .mobiles_connected
+            .iter()
+            .filter(|(_, data)| {
+                self.deadlines.of(&data.mobile_state).is_some_and(|deadline| {
+                    data.state_since.elapsed() > deadline
+                })
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+
+        for addr in &expired {
+            info!("Mobile: {:?} evicted, its state expired", addr);
+            self.end_session(addr);
+        }
+
+        expired
+    }
+
+    fn connected_mobiles(&self) -> Vec<Address> {
+        self.mobiles_connected.keys().cloned().collect()
+    }
+
+    fn status(&self) -> MobilesStatus {
+        let streaming = self
+            .mobiles_connected
+            .values()
+            .filter(|data| {
+                matches!(
+                    data.mobile_state,
+                    MobileDataState::ReadyToStream { .. }
+                )
+            })
+            .count();
+
+        MobilesStatus { connected: self.mobiles_connected.len(), streaming }
+    }
+
+    fn mobiles_info(&self) -> Vec<MobileInfo> {
+        self.mobiles_connected
+            .iter()
+            .map(|(addr, data)| {
+                let virtual_devices = match &data.mobile_state {
+                    MobileDataState::ReadyToStream {
+                        virtual_devices, ..
+                    } => virtual_devices
+                        .iter()
+                        .map(|(path, vdevice)| VDeviceInfo {
+                            path: path.clone(),
+                            name: vdevice.name.clone(),
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+
+                MobileInfo {
+                    addr: addr.clone(),
+                    state: data.mobile_state.name().to_string(),
+                    virtual_devices,
+                }
+            })
+            .collect()
+    }
+
+    fn registered_mobiles(&self) -> Result<Vec<MobileSchema>> {
+        self.db.list_mobiles()
+    }
+
+    fn mobiles_activity(&self) -> Result<Vec<MobileActivityInfo>> {
+        let activity = self.db.list_mobile_activity()?;
+        Ok(activity
+            .into_iter()
+            .map(|(id, activity)| MobileActivityInfo { id, activity })
+            .collect())
+    }
+
+    fn quarantined_records(&self) -> Result<Vec<QuarantinedRecord>> {
+        self.db.quarantined()
+    }
+
+    fn rename_host(&mut self, name: String) -> Result<HostProvInfo> {
+        self.db.rename_host(&name)?;
+        let host = self.db.get_host_prov_info()?;
+        self.host_info = serde_json::to_string(&host)?;
+
+        //the mobiles reading the old host info start over
+        let reading: Vec<Address> = self
+            .mobiles_connected
+            .iter()
+            .filter(|(_, data)| {
+                matches!(data.mobile_state, MobileDataState::ReadHostInfo)
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in reading {
+            info!("Mobile {} reads the host info again", addr);
+            self.release_mobile(&addr);
+        }
+
+        Ok(host)
+    }
+
+    fn forget_mobile(&mut self, id: String) -> Result<()> {
+        info!("Forgetting mobile: {:?}", id);
+        self.db.remove_mobile(&id)?;
+
+        //a connected mobile loses its session and virtual devices right away
+        self.release_sessions(&id);
+
+        Ok(())
+    }
+
+    fn release_sessions(&mut self, id: &str) -> usize {
+        let addrs: Vec<Address> = self
+            .mobiles_connected
+            .iter()
+            .filter(|(_, data)| data.mobile_state.mobile_id() == Some(id))
+            .map(|(addr, _)| addr.clone())
+            .collect();
+
+        for addr in &addrs {
+            info!("Releasing mobile {:?} connected as {}", id, addr);
+            self.release_mobile(addr);
+        }
+
+        addrs.len()
+    }
+
+    fn expire_mobiles(
+        &mut self, policy: RetentionPolicy,
+    ) -> Result<Vec<ExpiredMobile>> {
+        let now = unix_timestamp();
+        let mut mobiles = self.db.list_mobile_activity()?;
+
+        //the mobiles registered before the activity was tracked start
+        //aging now
+        for (id, activity) in &mut mobiles {
+            if activity.registered_at.is_none()
+                && activity.last_connected_at.is_none()
+            {
+                activity.registered_at = Some(now);
+                self.db.set_mobile_activity(id, activity)?;
+            }
+        }
+
+        let in_use: Vec<MobileId> = self
+            .mobiles_connected
+            .values()
+            .filter_map(|data| data.mobile_state.mobile_id())
+            .map(|id| id.to_string())
+            .collect();
+
+        let expired = policy.select_expired(&mobiles, &in_use, now);
+        for mobile in &expired {
+            self.forget_mobile(mobile.id.clone())?;
+        }
+
+        Ok(expired)
+    }
+
+    fn release_all(&mut self) -> Result<()> {
+        info!(
+            "Releasing {} connected mobiles and their virtual devices",
+            self.mobiles_connected.len()
+        );
+
+        //dropping the state drops the virtual devices
+        self.mobiles_connected.clear();
+        self.vdevice_index.clear();
+        self.sdp_subscribers.clear();
+
+        Ok(())
     }
 
     fn set_mobile_sdp_resp(
         &mut self, addr: String, data: BleBuffer,
     ) -> Result<()> {
+        self.note_framing(&addr, &data);
+
         if let ConnectedMobileData {
-            mobile_state: MobileDataState::ReadyToStream { .. },
-            buffer_status: Some(CommBufferStatus::CurrentBuffer(current_buffer)),
+            mobile_state:
+                MobileDataState::ReadyToStream {
+                    answer_to, streaming_since, ..
+                },
+            buffer_status: Some(CommBufferStatus::Incoming(assembler)),
+            ..
         } = self
             .mobiles_connected
             .get_mut(&addr)
             .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?
         {
-            let buff_comm = serde_json::from_slice::<BufferComm>(&data)?;
-
-            info!("buff_comm {:?}", buff_comm);
+            if let Some(message) = assembler.push(&data)? {
+                let sdp = String::from_utf8(message)?;
+                info!("SDP data: {:?}", sdp);
 
-            current_buffer.push_str(&buff_comm.payload);
-
-            info!("current_buffer {:?}", buff_comm);
-
-            if buff_comm.remain_len == 0 {
-                info!("SDP data: {:?}", current_buffer);
+                //the answer goes back to the session that made the offer
+                let session = answer_to
+                    .take()
+                    .ok_or_else(|| anyhow!("No offer waiting for an answer"))?;
+                let delivered = session.send(sdp);
+                //the mobile streams from its first delivered answer
+                if delivered.is_ok() {
+                    streaming_since.get_or_insert_with(Instant::now);
+                }
 
-                //parse the sdp data and use it some how
-                //ex: virtual_device.send_sdp_data(&data)?;
+                self.send_next_offer(&addr)?;
+                delivered.map_err(|_| anyhow!("Streaming session is gone"))?;
             }
         } else {
             return Err(anyhow!("Mobile is not ready to stream"));
@@ -421,3 +1024,490 @@ impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps> MultiMobileCommService
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::{AppData, ConnectionType, HostInfo, InMemoryDb};
+    use crate::ble::frame::{self, BufferComm};
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    struct NoVDevices;
+
+    //names a virtual device per camera without creating it, the device
+    //numbers don't exist so dropping them does nothing
+    struct NamedVDevices;
+
+    #[async_trait]
+    impl VDeviceBuilderOps for NamedVDevices {
+        async fn create_from(
+            &self, mobile: MobileSchema,
+        ) -> Result<VDeviceMap> {
+            Ok(mobile
+                .cameras
+                .iter()
+                .enumerate()
+                .map(|(i, camera)| {
+                    let vdevice = VDevice {
+                        name: format!("{}-{}", mobile.name, camera.name),
+                        device_num: u32::MAX - i as u32,
+                    };
+                    (PathBuf::from(format!("/dev/video{}", 100 + i)), vdevice)
+                })
+                .collect())
+        }
+    }
+
+    #[async_trait]
+    impl VDeviceBuilderOps for NoVDevices {
+        async fn create_from(&self, _: MobileSchema) -> Result<VDeviceMap> {
+            Ok(VDeviceMap::new())
+        }
+    }
+
+    fn buffer(payload: &str) -> BleBuffer {
+        let buff_comm =
+            BufferComm { remain_len: 0, payload: payload.to_string() };
+        serde_json::to_vec(&buff_comm).unwrap()
+    }
+
+    const MOBILE: &str = r#"{"id":"mobile_1","name":"Mobile1","cameras":[]}"#;
+
+    const TWO_CAMERAS: &str = r#"{"id":"mobile_1","name":"Mobile1","cameras":[
+        {"name":"front","format":[]},{"name":"back","format":[]}]}"#;
+
+    type TestComm<VDevBuilder> = MobileComm<AppData<InMemoryDb>, VDevBuilder>;
+
+    fn test_comm_with<VDevBuilder: VDeviceBuilderOps>(
+        vdev_builder: VDevBuilder,
+    ) -> TestComm<VDevBuilder> {
+        let app_data = AppData::new(
+            InMemoryDb::new(),
+            HostInfo {
+                name: "TestHost".to_string(),
+                connection_type: ConnectionType::WLAN,
+            },
+        )
+        .unwrap();
+        MobileComm::new(app_data, vdev_builder).unwrap()
+    }
+
+    fn test_comm() -> TestComm<NoVDevices> {
+        test_comm_with(NoVDevices)
+    }
+
+    //provisions a mobile up to the identification, ready to subscribe
+    fn provision<VDevBuilder: VDeviceBuilderOps>(
+        comm: &mut TestComm<VDevBuilder>, addr: &str, mobile: &str,
+    ) {
+        let id = serde_json::from_str::<MobileSchema>(mobile).unwrap().id;
+        comm.read_host_info(addr.to_string(), 512, Framing::Json).unwrap();
+        comm.set_register_mobile(addr.to_string(), buffer(mobile)).unwrap();
+        comm.set_mobile_pnp_id(addr.to_string(), buffer(&id)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_provision_and_identify_in_memory() {
+        init_logger();
+        let mut comm = test_comm();
+        let addr = "00:11:22:33:44:55".to_string();
+
+        //provisioning
+        let host_info =
+            comm.read_host_info(addr.clone(), 512, Framing::Json).unwrap();
+        let host_info: BufferComm = serde_json::from_slice(&host_info).unwrap();
+        assert_eq!(host_info.remain_len, 0);
+        assert!(host_info.payload.contains("TestHost"));
+
+        comm.set_register_mobile(addr.clone(), buffer(MOBILE)).unwrap();
+        comm.set_mobile_pnp_id(addr.clone(), buffer("mobile_1")).unwrap();
+        comm.subscribe_to_sdp_req(addr.clone(), 100).await.unwrap();
+
+        assert_eq!(comm.status(), MobilesStatus { connected: 1, streaming: 1 });
+        assert_eq!(comm.registered_mobiles().unwrap()[0].name, "Mobile1");
+
+        comm.device_disconnected(addr.clone()).unwrap();
+        assert_eq!(comm.status(), MobilesStatus { connected: 0, streaming: 0 });
+
+        //identification of the registered mobile
+        comm.set_mobile_pnp_id(addr.clone(), buffer("mobile_1")).unwrap();
+        comm.subscribe_to_sdp_req(addr.clone(), 100).await.unwrap();
+        assert_eq!(comm.mobiles_info()[0].state, "ReadyToStream");
+        comm.device_disconnected(addr.clone()).unwrap();
+
+        let activity = comm.mobiles_activity().unwrap();
+        assert_eq!(activity.len(), 1);
+        assert_eq!(activity[0].id, "mobile_1");
+        assert_eq!(activity[0].activity.connection_count, 2);
+        assert!(activity[0].activity.registered_at.is_some());
+        assert!(activity[0].activity.last_connected_at.is_some());
+
+        //the renamed host is provisioned with the new name
+        comm.read_host_info("addr_2".to_string(), 8, Framing::Json).unwrap();
+        let host = comm.rename_host("Renamed".to_string()).unwrap();
+        assert_eq!(host.name, "Renamed");
+        assert!(!comm.connected_mobiles().contains(&"addr_2".to_string()));
+        let host_info = comm
+            .read_host_info("addr_2".to_string(), 512, Framing::Json)
+            .unwrap();
+        let host_info: BufferComm = serde_json::from_slice(&host_info).unwrap();
+        assert!(host_info.payload.contains("\"name\":\"Renamed\""));
+        assert!(comm.rename_host("".to_string()).is_err());
+
+        //a forgotten mobile can't be identified
+        comm.forget_mobile("mobile_1".to_string()).unwrap();
+        assert!(comm.registered_mobiles().unwrap().is_empty());
+        assert!(comm.set_mobile_pnp_id(addr, buffer("mobile_1")).is_err());
+    }
+
+    //sends a message in binary frames of at most 20 bytes
+    fn frames(msg_id: u8, message: &str) -> Vec<BleBuffer> {
+        let mut outgoing =
+            Outgoing::new(Framing::Binary, msg_id, message.to_string())
+                .unwrap();
+        let mut frames = Vec::new();
+        while !outgoing.is_done() {
+            frames.push(outgoing.next_chunk(20).unwrap());
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_provision_in_frames_non_ascii_host() {
+        init_logger();
+        let app_data = AppData::new(
+            InMemoryDb::new(),
+            HostInfo {
+                name: "Café 📷".to_string(),
+                connection_type: ConnectionType::WLAN,
+            },
+        )
+        .unwrap();
+        let mut comm = MobileComm::new(app_data, NoVDevices).unwrap();
+
+        //the legacy chunks are cut at char boundaries
+        let mut host_info = Assembler::default();
+        let host_info = loop {
+            let chunk =
+                comm.read_host_info("addr_1".to_string(), 3, Framing::Json);
+            if let Some(message) = host_info.push(&chunk.unwrap()).unwrap() {
+                break String::from_utf8(message).unwrap();
+            }
+        };
+        assert!(host_info.contains("Café 📷"));
+
+        let mut host_info = Assembler::default();
+        let host_info = loop {
+            let chunk =
+                comm.read_host_info("addr_2".to_string(), 20, Framing::Binary);
+            let chunk = chunk.unwrap();
+            assert!(chunk.len() <= 20);
+            if let Some(message) = host_info.push(&chunk).unwrap() {
+                break String::from_utf8(message).unwrap();
+            }
+        };
+        assert!(host_info.contains("Café 📷"));
+
+        let mobile = r#"{"id":"mobile_1","name":"Móvil","cameras":[]}"#;
+        for frame in frames(1, mobile) {
+            comm.set_register_mobile("addr_2".to_string(), frame).unwrap();
+        }
+        let frames = frames(2, "mobile_1");
+        //a corrupted frame is rejected
+        let mut corrupted = frames[0].clone();
+        corrupted[frame::HEADER_LEN] ^= 0x01;
+        assert!(comm
+            .set_mobile_pnp_id("addr_2".to_string(), corrupted)
+            .is_err());
+        for frame in frames {
+            comm.set_mobile_pnp_id("addr_2".to_string(), frame).unwrap();
+        }
+
+        assert_eq!(comm.registered_mobiles().unwrap()[0].name, "Móvil");
+        let info = comm.mobiles_info();
+        let addr_2 = info.iter().find(|info| info.addr == "addr_2").unwrap();
+        assert_eq!(addr_2.state, "SaveMobileData");
+    }
+
+    #[tokio::test]
+    async fn test_chunks_sized_per_mobile_mtu() {
+        init_logger();
+        let mut comm = test_comm();
+        let mobiles = [("addr_1", 23), ("addr_2", 100)];
+
+        //the reads of both mobiles are interleaved
+        let mut host_info: Vec<Assembler> =
+            mobiles.iter().map(|_| Assembler::default()).collect();
+        let mut done = [false, false];
+        while done != [true, true] {
+            for (i, (addr, mtu)) in mobiles.into_iter().enumerate() {
+                if done[i] {
+                    continue;
+                }
+                let chunk = comm
+                    .read_host_info(addr.to_string(), mtu, Framing::Binary)
+                    .unwrap();
+                assert!(chunk.len() <= mtu - ATT_HEADER_LEN);
+                done[i] = host_info[i].push(&chunk).unwrap().is_some();
+            }
+        }
+
+        let mut subscribers = Vec::new();
+        for (addr, mtu) in mobiles {
+            comm.set_register_mobile(addr.to_string(), buffer(MOBILE)).unwrap();
+            comm.set_mobile_pnp_id(addr.to_string(), buffer("mobile_1"))
+                .unwrap();
+            let subscriber = comm
+                .subscribe_to_sdp_req(addr.to_string(), mtu * 2)
+                .await
+                .unwrap();
+            subscribers.push(subscriber);
+        }
+
+        //the MTU of the subscription is kept per mobile
+        assert_eq!(comm.mobiles_connected["addr_1"].mtu, 46);
+        assert_eq!(comm.mobiles_connected["addr_2"].chunk_size(), 197);
+
+        //only the addressed mobile receives the call, in its own chunks
+        let offer = "v=0 ice-pwd:secret ".repeat(10);
+        comm.publish_to("addr_1".to_string(), offer.clone().into_bytes())
+            .unwrap();
+        let mut call = Assembler::default();
+        let received = loop {
+            let chunk = subscribers[0].try_recv().unwrap();
+            assert!(chunk.len() <= 43);
+            if let Some(message) = call.push(&chunk).unwrap() {
+                break String::from_utf8(message).unwrap();
+            }
+        };
+        assert_eq!(received, offer);
+        assert!(subscribers[0].try_recv().is_err());
+        assert!(subscribers[1].try_recv().is_err());
+
+        //a released mobile can't be called
+        comm.device_disconnected("addr_2".to_string()).unwrap();
+        assert!(subscribers[1].recv().await.is_none());
+        assert!(comm
+            .publish_to("addr_2".to_string(), b"v=0".to_vec())
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_answer_returns_to_the_offering_session() {
+        init_logger();
+        let mut comm = test_comm();
+        let vdevice = PathBuf::from("/dev/video2");
+
+        //the vdevice is unknown until its mobile subscribes
+        assert!(comm.offer_sdp(vdevice.clone(), b"v=0".to_vec()).is_err());
+
+        provision(&mut comm, "addr_1", MOBILE);
+        let mut subscriber =
+            comm.subscribe_to_sdp_req("addr_1".to_string(), 512).await.unwrap();
+        comm.vdevice_index.insert(vdevice.clone(), "addr_1".to_string());
+
+        //an answer nobody asked for is rejected
+        assert!(comm
+            .set_mobile_sdp_resp("addr_1".to_string(), buffer("v=1"))
+            .is_err());
+
+        let answer = comm.offer_sdp(vdevice.clone(), b"v=0".to_vec()).unwrap();
+        assert!(subscriber.try_recv().is_ok());
+
+        comm.set_mobile_sdp_resp("addr_1".to_string(), buffer("v=1")).unwrap();
+        assert_eq!(answer.await.unwrap(), "v=1");
+    }
+
+    //reads a whole message published to a mobile
+    fn published(subscriber: &mut PubSubSubscriber) -> String {
+        let mut assembler = Assembler::default();
+        loop {
+            let chunk = subscriber.try_recv().unwrap();
+            if let Some(message) = assembler.push(&chunk).unwrap() {
+                return String::from_utf8(message).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offers_queued_for_two_cameras() {
+        init_logger();
+        let mut comm = test_comm();
+        let front = PathBuf::from("/dev/video2");
+        let back = PathBuf::from("/dev/video3");
+
+        provision(&mut comm, "addr_1", TWO_CAMERAS);
+        let mut subscriber =
+            comm.subscribe_to_sdp_req("addr_1".to_string(), 512).await.unwrap();
+        for vdevice in [&front, &back] {
+            comm.vdevice_index.insert(vdevice.clone(), "addr_1".to_string());
+        }
+
+        //the offer of the second camera waits for the first answer
+        let front_answer =
+            comm.offer_sdp(front, b"front offer".to_vec()).unwrap();
+        let back_answer = comm.offer_sdp(back, b"back offer".to_vec()).unwrap();
+        assert_eq!(published(&mut subscriber), "front offer");
+        assert!(subscriber.try_recv().is_err());
+
+        comm.set_mobile_sdp_resp("addr_1".to_string(), buffer("front answer"))
+            .unwrap();
+        assert_eq!(front_answer.await.unwrap(), "front answer");
+        assert_eq!(published(&mut subscriber), "back offer");
+
+        comm.set_mobile_sdp_resp("addr_1".to_string(), buffer("back answer"))
+            .unwrap();
+        assert_eq!(back_answer.await.unwrap(), "back answer");
+        assert!(subscriber.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_activity_of_the_offered_camera() {
+        init_logger();
+        let mut comm = test_comm_with(NamedVDevices);
+        let addr = "addr_1".to_string();
+
+        provision(&mut comm, &addr, TWO_CAMERAS);
+        let mut subscriber =
+            comm.subscribe_to_sdp_req(addr.clone(), 512).await.unwrap();
+        let streaming_since = |comm: &TestComm<_>| match comm.mobiles_connected
+            [&addr]
+            .mobile_state
+        {
+            MobileDataState::ReadyToStream { streaming_since, .. } => {
+                streaming_since
+            }
+            _ => panic!("Mobile is not ready to stream"),
+        };
+        let activity = comm.mobiles_activity().unwrap();
+        assert_eq!(activity[0].activity.last_camera, None);
+
+        //the second camera is offered, the mobile streams once it answers
+        let answer = comm
+            .offer_sdp(PathBuf::from("/dev/video101"), b"v=0".to_vec())
+            .unwrap();
+        assert_eq!(published(&mut subscriber), "v=0");
+        let activity = comm.mobiles_activity().unwrap();
+        let last_camera = activity[0].activity.last_camera.as_deref();
+        assert_eq!(last_camera, Some("Mobile1-back"));
+        assert!(streaming_since(&comm).is_none());
+
+        comm.set_mobile_sdp_resp(addr.clone(), buffer("v=1")).unwrap();
+        assert_eq!(answer.await.unwrap(), "v=1");
+        assert!(streaming_since(&comm).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_evict_mobiles_stuck_before_streaming() {
+        init_logger();
+        let mut comm = test_comm().with_deadlines(StateDeadlines {
+            read_host_info: Duration::from_millis(10),
+            write_mobile_info: Duration::from_secs(60),
+            write_mobile_id: Duration::from_secs(60),
+            subscribe: Duration::from_millis(10),
+        });
+
+        //addr_1 stops reading the host info
+        comm.read_host_info("addr_1".to_string(), 23, Framing::Json).unwrap();
+        //addr_2 reads it and doesn't write its info yet
+        comm.read_host_info("addr_2".to_string(), 512, Framing::Json).unwrap();
+        //addr_3 never subscribes, addr_4 is streaming
+        for addr in ["addr_3", "addr_4"] {
+            provision(&mut comm, addr, MOBILE);
+        }
+        comm.subscribe_to_sdp_req("addr_4".to_string(), 512).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let mut evicted = comm.evict_expired();
+        evicted.sort();
+        assert_eq!(evicted, ["addr_1", "addr_3"]);
+        let mut connected = comm.connected_mobiles();
+        connected.sort();
+        assert_eq!(connected, ["addr_2", "addr_4"]);
+        assert!(comm.evict_expired().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_restart_provisioning_and_identification() {
+        init_logger();
+        let mut comm = test_comm();
+        let addr = "addr_1".to_string();
+
+        //the provisioning is abandoned for the identification of a mobile
+        //that is not registered
+        comm.read_host_info(addr.clone(), 512, Framing::Json).unwrap();
+        assert!(comm
+            .set_mobile_pnp_id(addr.clone(), buffer("mobile_1"))
+            .is_err());
+
+        //reading the host info again starts from the beginning
+        let host_info = comm.read_host_info(addr.clone(), 512, Framing::Json);
+        assert_eq!(host_info.unwrap(), buffer(&comm.host_info));
+        comm.set_register_mobile(addr.clone(), buffer(MOBILE)).unwrap();
+        comm.set_mobile_pnp_id(addr.clone(), buffer("mobile_1")).unwrap();
+
+        //an identified mobile that didn't subscribe identifies again
+        comm.set_mobile_pnp_id(addr.clone(), buffer("mobile_1")).unwrap();
+        comm.subscribe_to_sdp_req(addr.clone(), 512).await.unwrap();
+
+        //a streaming mobile can be provisioned again
+        provision(&mut comm, &addr, MOBILE);
+        comm.subscribe_to_sdp_req(addr, 512).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_forget_connected_mobile() {
+        init_logger();
+        let mut comm = test_comm();
+        let renamed = r#"{"id":"mobile_1","name":"Renamed","cameras":[]}"#;
+
+        //provisioning twice updates the registration
+        for (addr, mobile) in [("addr_1", MOBILE), ("addr_2", renamed)] {
+            provision(&mut comm, addr, mobile);
+        }
+        comm.subscribe_to_sdp_req("addr_1".to_string(), 100).await.unwrap();
+
+        let registered = comm.registered_mobiles().unwrap();
+        assert_eq!(registered.len(), 1);
+        assert_eq!(registered[0].name, "Renamed");
+
+        //a mobile in the middle of the provisioning is not affected
+        comm.read_host_info("addr_3".to_string(), 512, Framing::Json).unwrap();
+
+        comm.forget_mobile("mobile_1".to_string()).unwrap();
+        assert_eq!(comm.connected_mobiles(), ["addr_3"]);
+        assert!(comm.vdevice_index.is_empty());
+        assert!(comm.device_disconnected("addr_1".to_string()).is_err());
+        assert!(comm.forget_mobile("mobile_1".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expire_mobiles() {
+        init_logger();
+        let mut comm = test_comm();
+
+        for id in ["mobile_1", "mobile_2", "mobile_3"] {
+            let addr = format!("addr_{}", id);
+            let mobile = format!(r#"{{"id":"{}","name":"","cameras":[]}}"#, id);
+            provision(&mut comm, &addr, &mobile);
+            if id != "mobile_3" {
+                comm.device_disconnected(addr).unwrap();
+            }
+        }
+
+        //the connected mobile is kept even if it is the oldest
+        let policy =
+            RetentionPolicy { max_inactive_days: None, max_pairings: Some(1) };
+        let expired = comm.expire_mobiles(policy).unwrap();
+
+        assert_eq!(expired.len(), 2);
+        let registered = comm.registered_mobiles().unwrap();
+        assert_eq!(registered.len(), 1);
+        assert_eq!(registered[0].id, "mobile_3");
+        assert!(comm.expire_mobiles(policy).unwrap().is_empty());
+    }
+}
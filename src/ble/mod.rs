@@ -1,8 +1,13 @@
 pub mod ble_clients;
 mod ble_cmd_api;
 pub mod ble_server;
+mod frame;
 mod mobile_comm;
 
+pub use ble_cmd_api::{
+    BleApi, MobileActivityInfo, MobilesStatus, PubSubTopic, SdpOffer,
+};
 pub use mobile_comm::{
-    AppDataStore, HostProvInfo, MobileComm, VDeviceBuilderOps, VDeviceMap,
+    AppDataStore, HostProvInfo, MobileComm, StateDeadlines, VDeviceBuilderOps,
+    VDeviceMap,
 };
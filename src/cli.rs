@@ -0,0 +1,127 @@
+//! Command line interface of the daemon.
+//!
+//! Every flag that maps to a runtime setting can also be given through an
+//! environment variable, flags take precedence over the environment and both
+//! take precedence over the configuration file.
+//!
+//! Without a subcommand the daemon is started, the subcommands manage the
+//! pairing database offline and exit.
+
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Command line arguments.
+#[derive(Debug, Default, Parser)]
+#[command(name = "webcam-direct", version, about)]
+pub struct Cli {
+    /// Path to the TOML configuration file.
+    #[arg(long, env = "WEBCAM_DIRECT_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Name of the wireless interface created for the access point.
+    #[arg(long, env = "WEBCAM_DIRECT_IF_NAME")]
+    pub if_name: Option<String>,
+
+    /// SSID of the access point.
+    #[arg(long, env = "WEBCAM_DIRECT_SSID")]
+    pub ssid: Option<String>,
+
+    /// WPA2 passphrase of the access point.
+    #[arg(long, env = "WEBCAM_DIRECT_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+
+    /// First IP address leased by the DHCP server.
+    #[arg(long, env = "WEBCAM_DIRECT_DHCP_START")]
+    pub dhcp_start: Option<String>,
+
+    /// Last IP address leased by the DHCP server.
+    #[arg(long, env = "WEBCAM_DIRECT_DHCP_END")]
+    pub dhcp_end: Option<String>,
+
+    /// Path of the generated hostapd configuration file.
+    #[arg(long, env = "WEBCAM_DIRECT_HOSTAPD_CONF")]
+    pub hostapd_conf: Option<PathBuf>,
+
+    /// Directory of the hostapd control socket.
+    #[arg(long, env = "WEBCAM_DIRECT_HOSTAPD_CTRL_DIR")]
+    pub hostapd_ctrl_dir: Option<PathBuf>,
+
+    /// Directory of the pairing database.
+    #[arg(long, env = "WEBCAM_DIRECT_DB_PATH")]
+    pub db_path: Option<PathBuf>,
+
+    /// Keep the pairings in memory only, they are lost when the daemon stops.
+    #[arg(long, env = "WEBCAM_DIRECT_EPHEMERAL")]
+    pub ephemeral: bool,
+
+    /// Path of the control socket.
+    #[arg(long, env = "WEBCAM_DIRECT_CONTROL_SOCKET")]
+    pub control_socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Offline database management commands, the daemon must not be running.
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// List the registered mobiles.
+    ListMobiles,
+
+    /// Show a registered mobile.
+    ShowMobile {
+        /// Id of the mobile.
+        id: String,
+    },
+
+    /// Remove a registered mobile, it has to be provisioned again to connect.
+    Forget {
+        /// Id of the mobile.
+        id: String,
+    },
+
+    /// Show the usage history of the registered mobiles.
+    Activity,
+
+    /// List the records moved to quarantine because they can't be decoded.
+    ListQuarantined,
+
+    /// Drop a quarantined record, its data is lost.
+    DropQuarantined {
+        /// Keyspace the record was moved from.
+        keyspace: String,
+
+        /// Key of the record in its keyspace.
+        key: String,
+    },
+
+    /// Show the host information, including its id.
+    ShowHost,
+
+    /// Create a new host id, every mobile has to be provisioned again.
+    ResetHost {
+        /// Name of the host, the system hostname by default.
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Confirm that every pairing is removed.
+        #[arg(long)]
+        yes: bool,
+    },
+
+    /// Export the host id and every pairing to a JSON document.
+    Export {
+        /// File to write, the standard output by default.
+        file: Option<PathBuf>,
+    },
+
+    /// Import the pairings of a document written by `export`.
+    Import {
+        /// File to read.
+        file: PathBuf,
+
+        /// Remove the stored pairings instead of merging with them.
+        #[arg(long)]
+        replace: bool,
+    },
+}
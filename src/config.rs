@@ -0,0 +1,641 @@
+//! Runtime settings of the daemon.
+//!
+//! The settings are read from a TOML file, by default `config.toml` in the
+//! XDG config directory (`~/.config/webcam-direct/`). Missing keys take the
+//! default values, and the command line flags or their environment variables
+//! override whatever the file says. The resulting `Config` is validated before
+//! it is handed over to the components.
+//!
+//! ```toml
+//! [access_point]
+//! if_name = "wcdirect0"
+//! ssid = "WebcamDirect"
+//! password = "12345678"
+//! dhcp_start = "193.168.3.5"
+//! dhcp_end = "193.168.3.150"
+//!
+//! [database]
+//! path = "/var/lib/webcam-direct/db"
+//! ephemeral = false
+//! encrypt = true
+//! key_file = "/var/lib/webcam-direct/db.key"
+//! codec = "bincode"
+//! backend = "sled"
+//!
+//! [ble]
+//! req_buffer_size = 512
+//! read_host_info_timeout_secs = 30
+//! write_mobile_info_timeout_secs = 60
+//! write_mobile_id_timeout_secs = 30
+//! subscribe_timeout_secs = 30
+//!
+//! [daemon]
+//! follow_hostname = true
+//! hostname_check_interval_secs = 30
+//!
+//! [retention]
+//! max_inactive_days = 180
+//! max_pairings = 10
+//!
+//! [control]
+//! socket_path = "/run/webcam-direct/control.sock"
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use directories::ProjectDirs;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::access_point_ctl::{
+    dhcp_server::DhcpIpRange, wifi_manager::WifiCredentials,
+};
+use crate::app_data::{Codec, RetentionPolicy, StorageBackend};
+use crate::ble::StateDeadlines;
+use crate::cli::Cli;
+use crate::error::Result;
+
+/// Name of the configuration file inside the config directory.
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Name of the control socket inside the runtime directory.
+const CONTROL_SOCKET_NAME: &str = "control.sock";
+
+/// Directory of the database used by the older versions, it is migrated to
+/// the data directory on start up.
+pub const LEGACY_DB_PATH: &str = "/tmp";
+
+/// Returns the project directories used for the config and data files.
+pub fn project_dirs() -> Result<ProjectDirs> {
+    ProjectDirs::from("", "", "webcam-direct")
+        .ok_or_else(|| anyhow!("Unable to find the user home directory"))
+}
+
+/// Returns the directory for the persistent data of the daemon.
+///
+/// That is the `StateDirectory=` given by systemd to system services, or the
+/// XDG data directory of the user (`~/.local/share/webcam-direct/`).
+pub fn data_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("STATE_DIRECTORY") {
+        // systemd passes a colon separated list, the first one is ours
+        if let Some(dir) = dir.to_str().and_then(|d| d.split(':').next()) {
+            return PathBuf::from(dir);
+        }
+    }
+
+    match project_dirs() {
+        Ok(dirs) => dirs.data_dir().to_path_buf(),
+        Err(_) => PathBuf::from("/var/lib/webcam-direct"),
+    }
+}
+
+/// Settings of the WiFi access point.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessPointConfig {
+    pub if_name: String,
+    pub ssid: String,
+    pub password: String,
+    pub dhcp_start: String,
+    pub dhcp_end: String,
+    pub hostapd_conf: PathBuf,
+    pub hostapd_ctrl_dir: PathBuf,
+}
+
+impl Default for AccessPointConfig {
+    fn default() -> Self {
+        Self {
+            if_name: "wcdirect0".to_string(),
+            ssid: "WebcamDirect".to_string(),
+            password: "12345678".to_string(),
+            dhcp_start: "193.168.3.5".to_string(),
+            dhcp_end: "193.168.3.150".to_string(),
+            hostapd_conf: PathBuf::from("/tmp/hostapd.conf"),
+            hostapd_ctrl_dir: PathBuf::from("/tmp/hostapd"),
+        }
+    }
+}
+
+impl AccessPointConfig {
+    /// Returns the WiFi credentials of the access point.
+    pub fn creds(&self) -> WifiCredentials {
+        WifiCredentials {
+            ssid: self.ssid.clone(),
+            password: self.password.clone(),
+        }
+    }
+
+    /// Returns the IP range leased by the DHCP server.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the range is rejected by `DhcpIpRange::new`.
+    pub fn dhcp_range(&self) -> Result<DhcpIpRange> {
+        DhcpIpRange::new(&self.dhcp_start, &self.dhcp_end)
+    }
+}
+
+/// Settings of the pairing database.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Directory of the database, by default `db` in the data directory.
+    pub path: PathBuf,
+    /// Keeps the pairings in memory only, nothing is written to `path`.
+    pub ephemeral: bool,
+    /// Encrypts the records, a database once encrypted needs its key file.
+    pub encrypt: bool,
+    /// Key of the encrypted records, by default `db.key` in the data
+    /// directory, outside of the database directory.
+    pub key_file: PathBuf,
+    /// Encoding of the records, recorded when the database is created.
+    /// `json` keeps them readable with standard tools when not encrypted.
+    pub codec: Codec,
+    /// Engine storing the records, `sqlite` takes over the records of a
+    /// `sled` database in the same directory.
+    pub backend: StorageBackend,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: data_dir().join("db"),
+            ephemeral: false,
+            encrypt: true,
+            key_file: data_dir().join("db.key"),
+            codec: Codec::default(),
+            backend: StorageBackend::default(),
+        }
+    }
+}
+
+/// Settings of the BLE server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BleConfig {
+    /// Capacity of the request queue of the `BleServer`.
+    pub req_buffer_size: usize,
+    /// Seconds a mobile may take to read the host info.
+    pub read_host_info_timeout_secs: u64,
+    /// Seconds a mobile may take to write its info once it read the host's.
+    pub write_mobile_info_timeout_secs: u64,
+    /// Seconds a mobile may take to write its id.
+    pub write_mobile_id_timeout_secs: u64,
+    /// Seconds a mobile may take to subscribe to the SDP calls once it is
+    /// identified.
+    pub subscribe_timeout_secs: u64,
+}
+
+impl Default for BleConfig {
+    fn default() -> Self {
+        let deadlines = StateDeadlines::default();
+        Self {
+            req_buffer_size: 512,
+            read_host_info_timeout_secs: deadlines.read_host_info.as_secs(),
+            write_mobile_info_timeout_secs: deadlines
+                .write_mobile_info
+                .as_secs(),
+            write_mobile_id_timeout_secs: deadlines.write_mobile_id.as_secs(),
+            subscribe_timeout_secs: deadlines.subscribe.as_secs(),
+        }
+    }
+}
+
+impl BleConfig {
+    /// Returns the time a mobile may stay in each state before streaming.
+    pub fn deadlines(&self) -> StateDeadlines {
+        StateDeadlines {
+            read_host_info: Duration::from_secs(
+                self.read_host_info_timeout_secs,
+            ),
+            write_mobile_info: Duration::from_secs(
+                self.write_mobile_info_timeout_secs,
+            ),
+            write_mobile_id: Duration::from_secs(
+                self.write_mobile_id_timeout_secs,
+            ),
+            subscribe: Duration::from_secs(self.subscribe_timeout_secs),
+        }
+    }
+}
+
+/// Settings of the daemon lifecycle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Maximum time in seconds for each teardown step on shutdown.
+    pub shutdown_step_timeout_secs: u64,
+    /// Renames the host when the system hostname changes.
+    pub follow_hostname: bool,
+    /// Period in seconds of the system hostname checks.
+    pub hostname_check_interval_secs: u64,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            shutdown_step_timeout_secs: 5,
+            follow_hostname: true,
+            hostname_check_interval_secs: 30,
+        }
+    }
+}
+
+/// Settings of the automatic removal of stale pairings, disabled by default.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Removes the mobiles that haven't connected for this many days.
+    pub max_inactive_days: Option<u64>,
+    /// Keeps at most this many pairings, evicting the least recently used.
+    pub max_pairings: Option<usize>,
+    /// Period in seconds of the retention checks.
+    pub check_interval_secs: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_inactive_days: None,
+            max_pairings: None,
+            check_interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// Returns the policy applied by the daemon.
+    pub fn policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            max_inactive_days: self.max_inactive_days,
+            max_pairings: self.max_pairings,
+        }
+    }
+}
+
+/// Settings of the local control socket.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// Path of the control socket, by default `control.sock` in the runtime
+    /// directory.
+    pub socket_path: Option<PathBuf>,
+}
+
+impl ControlConfig {
+    /// Returns the path of the control socket.
+    ///
+    /// Without `XDG_RUNTIME_DIR`, as for system services, the socket is
+    /// placed in `/run/webcam-direct`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the user home directory can't be found.
+    pub fn socket_path(&self) -> Result<PathBuf> {
+        if let Some(path) = &self.socket_path {
+            return Ok(path.clone());
+        }
+
+        let runtime_dir = match project_dirs()?.runtime_dir() {
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::from("/run/webcam-direct"),
+        };
+
+        Ok(runtime_dir.join(CONTROL_SOCKET_NAME))
+    }
+}
+
+/// Runtime settings of the daemon.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub access_point: AccessPointConfig,
+    pub database: DatabaseConfig,
+    pub ble: BleConfig,
+    pub daemon: DaemonConfig,
+    pub retention: RetentionConfig,
+    pub control: ControlConfig,
+}
+
+impl Config {
+    /// Loads the configuration for the given command line.
+    ///
+    /// The file given with `--config` must exist, the default file is
+    /// optional and the default values are used when it is missing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or parsed, or if the final
+    /// settings are not valid.
+    pub fn load(cli: &Cli) -> Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => {
+                let path = project_dirs()?.config_dir().join(CONFIG_FILE_NAME);
+                if path.exists() {
+                    Self::from_file(&path)?
+                } else {
+                    info!("No config file found at {:?}, using defaults", path);
+                    Self::default()
+                }
+            }
+        };
+
+        config.apply_overrides(cli);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Reads the configuration from a TOML file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or parsed.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        info!("Loading config file {:?}", path);
+
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            anyhow!("Failed to read config file {:?}, error: {}", path, e)
+        })?;
+
+        Self::from_toml(&content)
+    }
+
+    /// Parses the configuration from a TOML string.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the string is not a valid configuration.
+    pub fn from_toml(content: &str) -> Result<Self> {
+        toml::from_str(content)
+            .map_err(|e| anyhow!("Invalid config file, error: {}", e))
+    }
+
+    /// Applies the command line flags and environment variables on top of the
+    /// values read from the file.
+    pub fn apply_overrides(&mut self, cli: &Cli) {
+        let ap = &mut self.access_point;
+
+        if let Some(if_name) = &cli.if_name {
+            ap.if_name = if_name.clone();
+        }
+        if let Some(ssid) = &cli.ssid {
+            ap.ssid = ssid.clone();
+        }
+        if let Some(password) = &cli.password {
+            ap.password = password.clone();
+        }
+        if let Some(dhcp_start) = &cli.dhcp_start {
+            ap.dhcp_start = dhcp_start.clone();
+        }
+        if let Some(dhcp_end) = &cli.dhcp_end {
+            ap.dhcp_end = dhcp_end.clone();
+        }
+        if let Some(hostapd_conf) = &cli.hostapd_conf {
+            ap.hostapd_conf = hostapd_conf.clone();
+        }
+        if let Some(hostapd_ctrl_dir) = &cli.hostapd_ctrl_dir {
+            ap.hostapd_ctrl_dir = hostapd_ctrl_dir.clone();
+        }
+        if let Some(db_path) = &cli.db_path {
+            self.database.path = db_path.clone();
+        }
+        if cli.ephemeral {
+            self.database.ephemeral = true;
+        }
+        if let Some(control_socket) = &cli.control_socket {
+            self.control.socket_path = Some(control_socket.clone());
+        }
+    }
+
+    /// Checks that the settings can be used by the components.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error describing the first invalid setting.
+    pub fn validate(&self) -> Result<()> {
+        let ap = &self.access_point;
+
+        // IFNAMSIZ is 16 including the trailing nul
+        if ap.if_name.is_empty() || ap.if_name.len() > 15 {
+            return Err(anyhow!(
+                "Interface name must be between 1 and 15 characters"
+            ));
+        }
+
+        if ap.ssid.is_empty() || ap.ssid.len() > 32 {
+            return Err(anyhow!("SSID must be between 1 and 32 bytes"));
+        }
+
+        // WPA2 passphrase, hostapd rejects anything else
+        if ap.password.len() < 8
+            || ap.password.len() > 63
+            || !ap.password.chars().all(|c| c.is_ascii() && !c.is_control())
+        {
+            return Err(anyhow!(
+                "Password must be between 8 and 63 printable ASCII characters"
+            ));
+        }
+
+        ap.dhcp_range()?;
+
+        if ap.hostapd_conf.to_str().is_none() {
+            return Err(anyhow!("Invalid hostapd config file path"));
+        }
+
+        if self.ble.req_buffer_size == 0 {
+            return Err(anyhow!("BLE request buffer size must not be zero"));
+        }
+
+        let ble = &self.ble;
+        if [
+            ble.read_host_info_timeout_secs,
+            ble.write_mobile_info_timeout_secs,
+            ble.write_mobile_id_timeout_secs,
+            ble.subscribe_timeout_secs,
+        ]
+        .contains(&0)
+        {
+            return Err(anyhow!("Mobile state timeouts must not be zero"));
+        }
+
+        if self.daemon.shutdown_step_timeout_secs == 0 {
+            return Err(anyhow!("Shutdown step timeout must not be zero"));
+        }
+
+        if self.daemon.hostname_check_interval_secs == 0 {
+            return Err(anyhow!("Hostname check interval must not be zero"));
+        }
+
+        if self.retention.max_pairings == Some(0) {
+            return Err(anyhow!("Maximum number of pairings must not be zero"));
+        }
+
+        if self.retention.check_interval_secs == 0 {
+            return Err(anyhow!("Retention check interval must not be zero"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_default_config_is_valid() {
+        init_logger();
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_from_toml_partial() {
+        init_logger();
+        let config = Config::from_toml(
+            r#"
+            [access_point]
+            ssid = "MyCam"
+
+            [database]
+            path = "/var/lib/webcam-direct"
+            codec = "json"
+            backend = "sqlite"
+
+            [ble]
+            subscribe_timeout_secs = 10
+
+            [daemon]
+            follow_hostname = false
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.access_point.ssid, "MyCam");
+        assert_eq!(config.access_point.if_name, "wcdirect0");
+        assert_eq!(
+            config.database.path,
+            PathBuf::from("/var/lib/webcam-direct")
+        );
+        assert_eq!(config.database.codec, Codec::Json);
+        assert_eq!(config.database.backend, StorageBackend::Sqlite);
+        assert_eq!(config.ble.req_buffer_size, 512);
+        assert_eq!(config.ble.deadlines().subscribe, Duration::from_secs(10));
+        assert_eq!(
+            config.ble.deadlines().read_host_info,
+            StateDeadlines::default().read_host_info
+        );
+        assert!(!config.daemon.follow_hostname);
+        assert_eq!(config.daemon.hostname_check_interval_secs, 30);
+    }
+
+    #[test]
+    fn test_from_toml_unknown_key() {
+        init_logger();
+        let config = Config::from_toml(
+            r#"
+            [access_point]
+            sid = "MyCam"
+            "#,
+        );
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        init_logger();
+        let mut config = Config::default();
+        let cli = Cli {
+            ssid: Some("Override".to_string()),
+            db_path: Some(PathBuf::from("/srv/db")),
+            ephemeral: true,
+            ..Default::default()
+        };
+
+        config.apply_overrides(&cli);
+
+        assert_eq!(config.access_point.ssid, "Override");
+        assert_eq!(config.access_point.password, "12345678");
+        assert_eq!(config.database.path, PathBuf::from("/srv/db"));
+        assert!(config.database.ephemeral);
+    }
+
+    #[test]
+    fn test_control_socket_path() {
+        init_logger();
+        let mut config = Config::default();
+        assert!(config
+            .control
+            .socket_path()
+            .unwrap()
+            .ends_with(CONTROL_SOCKET_NAME));
+
+        config.control.socket_path = Some(PathBuf::from("/tmp/wcd.sock"));
+        assert_eq!(
+            config.control.socket_path().unwrap(),
+            PathBuf::from("/tmp/wcd.sock")
+        );
+    }
+
+    #[test]
+    fn test_validate_ssid_too_long() {
+        init_logger();
+        let mut config = Config::default();
+        config.access_point.ssid = "a".repeat(33);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_password_too_short() {
+        init_logger();
+        let mut config = Config::default();
+        config.access_point.password = "1234567".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_invalid_dhcp_range() {
+        init_logger();
+        let mut config = Config::default();
+        config.access_point.dhcp_start = "193.168.3.150".to_string();
+        config.access_point.dhcp_end = "193.168.3.5".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_retention() {
+        init_logger();
+        let config = Config::from_toml(
+            r#"
+            [retention]
+            max_pairings = 3
+            "#,
+        )
+        .unwrap();
+
+        let policy = config.retention.policy();
+        assert_eq!(policy.max_pairings, Some(3));
+        assert_eq!(policy.max_inactive_days, None);
+        assert!(Config::default().retention.policy().is_disabled());
+
+        let mut config = Config::default();
+        config.retention.max_pairings = Some(0);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_zero_state_timeout() {
+        init_logger();
+        let mut config = Config::default();
+        config.ble.write_mobile_id_timeout_secs = 0;
+        assert!(config.validate().is_err());
+    }
+}
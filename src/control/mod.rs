@@ -0,0 +1,463 @@
+//! Local control socket of the daemon.
+//!
+//! The daemon listens on a Unix stream socket, by default `control.sock` in
+//! the runtime directory, for JSON-RPC 2.0 requests, one per line. Every
+//! request is forwarded to the `BleServer` through its `ServerConn` channel,
+//! so the state changes made from the socket are serialized with the ones made
+//! by the mobiles.
+//!
+//! ```text
+//! $ echo '{"jsonrpc":"2.0","id":1,"method":"connected_mobiles"}' \
+//!     | socat - UNIX-CONNECT:$XDG_RUNTIME_DIR/webcam-direct/control.sock
+//! ```
+//!
+//! | Method                | Params           | Result                        |
+//! |-----------------------|------------------|-------------------------------|
+//! | `list_mobiles`        |                  | registered mobiles            |
+//! | `connected_mobiles`   |                  | connected mobiles and devices |
+//! | `mobile_activity`     |                  | usage history of the mobiles  |
+//! | `quarantined_records` |                  | records that can't be decoded |
+//! | `ap_status`           |                  | access point creds and health |
+//! | `forget_mobile`       | `{"id": ".."}`   | `null`                        |
+//! | `rename_host`         | `{"name": ".."}` | new host info                 |
+//! | `start_wifi`          |                  | `null`                        |
+//! | `stop_wifi`           |                  | `null`                        |
+//! | `rotate_wifi_creds`   |                  | new access point creds        |
+
+mod rpc;
+
+use std::{
+    fs,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+
+use crate::ble::{
+    ble_server::{host_request, ServerConn},
+    BleApi,
+};
+use crate::error::Result;
+use rpc::{
+    RpcError, RpcRequest, RpcResponse, INVALID_PARAMS, INVALID_REQUEST,
+    METHOD_NOT_FOUND, PARSE_ERROR, SERVER_ERROR,
+};
+
+#[derive(Debug, Deserialize)]
+struct MobileIdParams {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HostNameParams {
+    name: String,
+}
+
+/// Serves the control socket until it is stopped.
+pub struct ControlServer {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl ControlServer {
+    /// Binds the control socket at `path`, readable only by the owner.
+    ///
+    /// A stale socket left by a previous run is replaced.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket can't be created or if another daemon is
+    /// already listening on it.
+    pub fn bind<P: AsRef<Path>>(
+        path: P, server_conn: ServerConn,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        if let Some(dir) = path.parent() {
+            if !dir.exists() {
+                fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(dir)?;
+            }
+        }
+
+        if path.exists() {
+            if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                return Err(anyhow!(
+                    "Control socket {:?} is in use by another daemon",
+                    path
+                ));
+            }
+            warn!("Removing stale control socket {:?}", path);
+            fs::remove_file(&path)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        info!("Control socket listening on {:?}", path);
+
+        let task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_client(stream, server_conn.clone()));
+                    }
+                    Err(e) => {
+                        error!("Failed to accept control client: {:?}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(Self { path, task })
+    }
+
+    /// Stops listening and removes the socket file.
+    pub async fn stop(self) -> Result<()> {
+        self.task.abort();
+        let _ = self.task.await;
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+async fn serve_client(stream: UnixStream, server_conn: ServerConn) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                error!("Failed to read from control client: {:?}", e);
+                break;
+            }
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let Some(resp) = handle_line(&server_conn, &line).await else {
+            continue;
+        };
+
+        let mut resp = match serde_json::to_vec(&resp) {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("Failed to serialize control response: {:?}", e);
+                break;
+            }
+        };
+        resp.push(b'\n');
+
+        if let Err(e) = writer.write_all(&resp).await {
+            error!("Failed to write to control client: {:?}", e);
+            break;
+        }
+    }
+}
+
+//returns None for notifications, they don't get a response
+async fn handle_line(
+    server_conn: &ServerConn, line: &str,
+) -> Option<RpcResponse> {
+    let value: Value = match serde_json::from_str(line) {
+        Ok(value) => value,
+        Err(e) => {
+            return Some(RpcResponse::failure(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, e.to_string()),
+            ));
+        }
+    };
+
+    let req: RpcRequest = match serde_json::from_value(value) {
+        Ok(req) => req,
+        Err(e) => {
+            return Some(RpcResponse::failure(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, e.to_string()),
+            ));
+        }
+    };
+
+    let result = if req.jsonrpc != "2.0" {
+        Err(RpcError::new(INVALID_REQUEST, "Unsupported JSON-RPC version"))
+    } else {
+        info!("Control request: {}", req.method);
+        dispatch(server_conn, &req.method, req.params).await
+    };
+
+    let id = req.id?;
+
+    Some(match result {
+        Ok(result) => RpcResponse::success(id, result),
+        Err(error) => RpcResponse::failure(id, error),
+    })
+}
+
+async fn dispatch(
+    server_conn: &ServerConn, method: &str, params: Value,
+) -> std::result::Result<Value, RpcError> {
+    match method {
+        "list_mobiles" => {
+            to_rpc(host_request(server_conn, BleApi::RegisteredMobiles).await)
+        }
+        "connected_mobiles" => {
+            to_rpc(host_request(server_conn, BleApi::MobilesInfo).await)
+        }
+        "mobile_activity" => {
+            to_rpc(host_request(server_conn, BleApi::MobilesActivity).await)
+        }
+        "quarantined_records" => {
+            to_rpc(host_request(server_conn, BleApi::QuarantinedRecords).await)
+        }
+        "ap_status" => {
+            to_rpc(host_request(server_conn, BleApi::ApStatus).await)
+        }
+        "forget_mobile" => {
+            let params: MobileIdParams = serde_json::from_value(params)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+            to_rpc(
+                host_request(server_conn, |req| {
+                    BleApi::ForgetMobile(params.id, req)
+                })
+                .await,
+            )
+        }
+        "rename_host" => {
+            let params: HostNameParams = serde_json::from_value(params)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+            to_rpc(
+                host_request(server_conn, |req| {
+                    BleApi::RenameHost(params.name, req)
+                })
+                .await,
+            )
+        }
+        "start_wifi" => {
+            to_rpc(host_request(server_conn, BleApi::StartWifi).await)
+        }
+        "stop_wifi" => {
+            to_rpc(host_request(server_conn, BleApi::StopWifi).await)
+        }
+        "rotate_wifi_creds" => {
+            to_rpc(host_request(server_conn, BleApi::RotateWifiCreds).await)
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method {}", method),
+        )),
+    }
+}
+
+fn to_rpc<T: Serialize>(
+    result: Result<T>,
+) -> std::result::Result<Value, RpcError> {
+    let result =
+        result.map_err(|e| RpcError::new(SERVER_ERROR, e.to_string()))?;
+    serde_json::to_value(result)
+        .map_err(|e| RpcError::new(SERVER_ERROR, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::{MobileActivity, MobileSchema};
+    use crate::ble::ble_server::{BleServer, MockMultiMobileCommService};
+    use crate::ble::MobileActivityInfo;
+    use mockall::predicate::eq;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn test_server() -> BleServer {
+        let mut mock_comm = MockMultiMobileCommService::new();
+
+        mock_comm.expect_registered_mobiles().returning(|| {
+            Ok(vec![MobileSchema {
+                id: "mobile_1".to_string(),
+                name: "Mobile1".to_string(),
+                ..Default::default()
+            }])
+        });
+        mock_comm
+            .expect_forget_mobile()
+            .with(eq("mobile_1".to_string()))
+            .returning(|_| Ok(()));
+        mock_comm
+            .expect_forget_mobile()
+            .returning(|_| Err(anyhow!("Mobile info not found")));
+
+        mock_comm.expect_mobiles_activity().returning(|| {
+            Ok(vec![MobileActivityInfo {
+                id: "mobile_1".to_string(),
+                activity: MobileActivity {
+                    connection_count: 2,
+                    ..Default::default()
+                },
+            }])
+        });
+
+        BleServer::new(mock_comm, None, 4)
+    }
+
+    #[tokio::test]
+    async fn test_handle_list_mobiles() {
+        init_logger();
+        let server = test_server();
+
+        let resp = handle_line(
+            &server.connection(),
+            r#"{"jsonrpc":"2.0","id":1,"method":"list_mobiles"}"#,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(resp.id, Value::from(1));
+        assert!(resp.error.is_none());
+        assert_eq!(resp.result.unwrap()[0]["id"], "mobile_1");
+    }
+
+    #[tokio::test]
+    async fn test_handle_mobile_activity() {
+        init_logger();
+        let server = test_server();
+
+        let resp = handle_line(
+            &server.connection(),
+            r#"{"jsonrpc":"2.0","id":1,"method":"mobile_activity"}"#,
+        )
+        .await
+        .unwrap();
+
+        let result = resp.result.unwrap();
+        assert_eq!(result[0]["id"], "mobile_1");
+        assert_eq!(result[0]["connection_count"], 2);
+        assert_eq!(result[0]["last_camera"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_handle_forget_mobile() {
+        init_logger();
+        let server = test_server();
+        let conn = server.connection();
+
+        let resp = handle_line(
+            &conn,
+            r#"{"jsonrpc":"2.0","id":"a","method":"forget_mobile",
+                "params":{"id":"mobile_1"}}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.result, Some(Value::Null));
+
+        let resp = handle_line(
+            &conn,
+            r#"{"jsonrpc":"2.0","id":2,"method":"forget_mobile",
+                "params":{"id":"unknown"}}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.error.unwrap().code, SERVER_ERROR);
+
+        let resp = handle_line(
+            &conn,
+            r#"{"jsonrpc":"2.0","id":3,"method":"forget_mobile"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.error.unwrap().code, INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn test_handle_errors() {
+        init_logger();
+        let server = test_server();
+        let conn = server.connection();
+
+        let resp = handle_line(&conn, "{not json").await.unwrap();
+        assert_eq!(resp.error.unwrap().code, PARSE_ERROR);
+
+        let resp = handle_line(&conn, r#"{"id":1}"#).await.unwrap();
+        assert_eq!(resp.error.unwrap().code, INVALID_REQUEST);
+
+        let resp =
+            handle_line(&conn, r#"{"jsonrpc":"2.0","id":1,"method":"nope"}"#)
+                .await
+                .unwrap();
+        assert_eq!(resp.error.unwrap().code, METHOD_NOT_FOUND);
+
+        //the host has no access point
+        let resp = handle_line(
+            &conn,
+            r#"{"jsonrpc":"2.0","id":1,"method":"ap_status"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.error.unwrap().code, SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_handle_notification() {
+        init_logger();
+        let server = test_server();
+
+        let resp = handle_line(
+            &server.connection(),
+            r#"{"jsonrpc":"2.0","method":"list_mobiles"}"#,
+        )
+        .await;
+
+        assert!(resp.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_control_socket() {
+        init_logger();
+        let server = test_server();
+        let dir = std::env::temp_dir()
+            .join(format!("wcd-control-{}", std::process::id()));
+        let path = dir.join("control.sock");
+
+        let control = ControlServer::bind(&path, server.connection()).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        //a second daemon can't take over the socket
+        assert!(ControlServer::bind(&path, server.connection()).is_err());
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(
+                b"{\"jsonrpc\":\"2.0\",\"id\":7,\"method\":\"list_mobiles\"}\n",
+            )
+            .await
+            .unwrap();
+
+        let mut lines = BufReader::new(stream).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        let resp: RpcResponse = serde_json::from_str(&line).unwrap();
+        assert_eq!(resp.id, Value::from(7));
+        assert_eq!(resp.result.unwrap()[0]["name"], "Mobile1");
+
+        control.stop().await.unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,332 @@
+//! Lifecycle helpers for running as a service.
+//!
+//! The daemon runs until it receives SIGINT or SIGTERM, then every component
+//! is torn down in a defined order with `Teardown`, which bounds each step
+//! with a timeout and remembers the failed ones so the process can exit with
+//! a non-zero code.
+//!
+//! While running, `health_task` checks the components periodically and keeps
+//! systemd informed through the watchdog and the status line,
+//! `retention_task` removes the pairings expired by the retention policy,
+//! `hostname_task` renames the host after the system hostname and
+//! `data_events_task` reacts to the changes of the pairing data.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use log::{error, info};
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    sync::watch,
+};
+
+use crate::access_point_ctl::SharedAp;
+use crate::app_data::{AppDataWatcher, AppEvent, RetentionPolicy};
+use crate::ble::{
+    ble_server::{host_request, request_status, ServerConn},
+    BleApi, MobilesStatus,
+};
+use crate::error::Result;
+use crate::systemd::SdNotifier;
+
+/// Period of the health checks when the systemd watchdog is disabled.
+const DEFAULT_HEALTH_PERIOD: Duration = Duration::from_secs(10);
+
+/// The shutdown signals of the process, SIGINT and SIGTERM.
+///
+/// The handlers are installed on creation, a signal received afterwards, even
+/// while the daemon starts, is kept for `recv` instead of killing the process.
+pub struct ShutdownSignals {
+    sigint: Signal,
+    sigterm: Signal,
+}
+
+impl ShutdownSignals {
+    /// Installs the handlers of SIGINT and SIGTERM.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the signal handlers can't be installed.
+    pub fn install() -> Result<Self> {
+        Ok(Self {
+            sigint: signal(SignalKind::interrupt())?,
+            sigterm: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// Waits until the process receives SIGINT or SIGTERM.
+    pub async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.sigint.recv() => info!("SIGINT received"),
+            _ = self.sigterm.recv() => info!("SIGTERM received"),
+        }
+    }
+}
+
+/// Runs the teardown steps one after the other, each one bounded by a timeout.
+pub struct Teardown {
+    step_timeout: Duration,
+    failed: Vec<String>,
+}
+
+impl Teardown {
+    /// Creates a new `Teardown` with the given timeout for every step.
+    pub fn new(step_timeout: Duration) -> Self {
+        Self { step_timeout, failed: Vec::new() }
+    }
+
+    /// Runs a teardown step, a step that fails or times out is recorded and
+    /// the teardown continues with the next one.
+    pub async fn step<F>(&mut self, name: &str, step: F)
+    where
+        F: Future<Output = Result<()>>,
+    {
+        info!("Teardown: {}", name);
+
+        match tokio::time::timeout(self.step_timeout, step).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                error!("Teardown step '{}' failed, error: {:?}", name, e);
+                self.failed.push(name.to_string());
+            }
+            Err(_) => {
+                error!(
+                    "Teardown step '{}' timed out after {:?}",
+                    name, self.step_timeout
+                );
+                self.failed.push(name.to_string());
+            }
+        }
+    }
+
+    /// Returns the names of the steps that failed.
+    pub fn failed_steps(&self) -> &[String] {
+        &self.failed
+    }
+}
+
+/// Checks the health of the daemon periodically.
+///
+/// On every period the `BleServer` request loop must answer a status query in
+/// time and the access point processes, if any, must be alive. While healthy,
+/// the systemd watchdog is pinged and the status of the mobiles is published,
+/// otherwise the ping is skipped so systemd can restart the daemon.
+pub async fn health_task(
+    notifier: Arc<SdNotifier>, server_conn: ServerConn, ap: Option<SharedAp>,
+    watchdog_interval: Option<Duration>,
+) {
+    //ping twice per watchdog interval, as recommended by systemd
+    let period = watchdog_interval.map_or(DEFAULT_HEALTH_PERIOD, |i| i / 2);
+    let mut ticker = tokio::time::interval(period);
+
+    loop {
+        ticker.tick().await;
+
+        match check_health(&server_conn, ap.as_ref(), period).await {
+            Ok(status) => {
+                let status = format!(
+                    "{} mobiles connected, {} streaming",
+                    status.connected, status.streaming
+                );
+
+                if let Err(e) = notifier.status(&status) {
+                    error!("Failed to notify status, error: {:?}", e);
+                }
+
+                if watchdog_interval.is_some() {
+                    if let Err(e) = notifier.watchdog() {
+                        error!("Failed to ping the watchdog, error: {:?}", e);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Health check failed, error: {:?}", e);
+                let _ = notifier.status(&format!("Unhealthy: {}", e));
+            }
+        }
+    }
+}
+
+/// Applies the retention policy on every period, logging each removed mobile.
+pub async fn retention_task(
+    server_conn: ServerConn, policy: RetentionPolicy, period: Duration,
+) {
+    let mut ticker = tokio::time::interval(period);
+
+    loop {
+        ticker.tick().await;
+
+        let expired = host_request(&server_conn, |req| {
+            BleApi::ExpireMobiles(policy, req)
+        })
+        .await;
+
+        match expired {
+            Ok(expired) => {
+                for mobile in expired {
+                    info!("Removed mobile {}, {}", mobile.id, mobile.reason);
+                }
+            }
+            Err(e) => {
+                error!("Failed to apply the retention policy, error: {:?}", e)
+            }
+        }
+    }
+}
+
+/// Renames the host when the system hostname changes, checked on every
+/// period.
+pub async fn hostname_task(server_conn: ServerConn, period: Duration) {
+    let mut ticker = tokio::time::interval(period);
+    let mut last_name = system_hostname();
+
+    loop {
+        ticker.tick().await;
+
+        let Some(name) = system_hostname() else {
+            continue;
+        };
+        if last_name.as_ref() == Some(&name) {
+            continue;
+        }
+
+        info!("System hostname changed to {}", name);
+        let renamed = host_request(&server_conn, |req| {
+            BleApi::RenameHost(name.clone(), req)
+        })
+        .await;
+
+        match renamed {
+            Ok(_) => last_name = Some(name),
+            Err(e) => error!("Failed to rename the host, error: {:?}", e),
+        }
+    }
+}
+
+/// Returns the system hostname, `None` if it can't be read or is not valid
+/// UTF-8.
+pub fn system_hostname() -> Option<String> {
+    hostname::get().ok()?.into_string().ok()
+}
+
+/// Logs the changes of the pairing data, releases the sessions of the
+/// mobiles removed from the data store and publishes the new host name to
+/// the advertising clients.
+pub async fn data_events_task(
+    server_conn: ServerConn, mut events: AppDataWatcher,
+    host_name: watch::Sender<String>,
+) {
+    while let Some(event) = events.recv().await {
+        match event {
+            AppEvent::MobileAdded(mobile) => {
+                info!("Mobile {} added, name: {}", mobile.id, mobile.name)
+            }
+            AppEvent::MobileUpdated(mobile) => {
+                info!("Mobile {} updated, name: {}", mobile.id, mobile.name)
+            }
+            AppEvent::MobileRemoved(id) => {
+                info!("Mobile {} removed", id);
+                let released = host_request(&server_conn, |req| {
+                    BleApi::ReleaseSessions(id.clone(), req)
+                })
+                .await;
+
+                match released {
+                    Ok(0) => {}
+                    Ok(n) => info!("Released {} sessions of mobile {}", n, id),
+                    Err(e) => error!(
+                        "Failed to release the mobile {}, error: {:?}",
+                        id, e
+                    ),
+                }
+            }
+            AppEvent::HostRenamed(name) => {
+                info!("Host renamed to {}", name);
+                host_name.send_replace(name);
+            }
+        }
+    }
+
+    info!("Data store closed, no more data events");
+}
+
+async fn check_health(
+    server_conn: &ServerConn, ap: Option<&SharedAp>, timeout: Duration,
+) -> Result<MobilesStatus> {
+    let status = tokio::time::timeout(timeout, request_status(server_conn))
+        .await
+        .map_err(|_| anyhow!("BLE server is not responding"))??;
+
+    if let Some(ap) = ap.cloned() {
+        //the access point may be locked by a blocking operation
+        let healthy = tokio::task::spawn_blocking(move || {
+            let mut ap =
+                ap.lock().map_err(|_| anyhow!("Access point poisoned"))?;
+            Ok::<_, anyhow::Error>(ap.is_healthy())
+        })
+        .await??;
+        if !healthy {
+            return Err(anyhow!("Access point processes are not running"));
+        }
+    }
+
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[tokio::test]
+    async fn test_signal_is_kept_until_waited_for() {
+        init_logger();
+        let mut signals = ShutdownSignals::install().unwrap();
+
+        // SAFETY: raise has no preconditions, the handler of SIGTERM is
+        // installed so the process is not terminated
+        unsafe { libc::raise(libc::SIGTERM) };
+
+        tokio::time::timeout(Duration::from_secs(1), signals.recv())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_teardown_all_steps_succeed() {
+        init_logger();
+        let mut teardown = Teardown::new(Duration::from_millis(100));
+
+        teardown.step("first", async { Ok(()) }).await;
+        teardown.step("second", async { Ok(()) }).await;
+
+        assert!(teardown.failed_steps().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_teardown_records_failures_and_continues() {
+        init_logger();
+        let mut teardown = Teardown::new(Duration::from_millis(100));
+        let mut last_step_run = false;
+
+        teardown.step("failing", async { Err(anyhow!("failed")) }).await;
+        teardown
+            .step("slow", async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(())
+            })
+            .await;
+        teardown
+            .step("last", async {
+                last_step_run = true;
+                Ok(())
+            })
+            .await;
+
+        assert!(last_step_run);
+        assert_eq!(teardown.failed_steps(), ["failing", "slow"]);
+    }
+}
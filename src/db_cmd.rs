@@ -0,0 +1,316 @@
+//! Offline management of the pairing database.
+//!
+//! The subcommands work directly on the database through `AppData`, without
+//! starting BLE or the access point. The database can't be opened while the
+//! daemon is running.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::app_data::{AppData, ImportMode, KvDbOps};
+use crate::ble::{AppDataStore, MobileActivityInfo};
+use crate::cli::Command;
+use crate::error::Result;
+use anyhow::anyhow;
+
+/// Runs a database command, writing its output to `out`.
+///
+/// # Errors
+///
+/// Returns an error if the command fails or the output can't be written.
+pub fn run<Db: KvDbOps>(
+    command: Command, app_data: &mut AppData<Db>, out: &mut impl Write,
+) -> Result<()> {
+    match command {
+        Command::ListMobiles => {
+            let mobiles = app_data.list_mobiles()?;
+            if mobiles.is_empty() {
+                writeln!(out, "No mobiles registered")?;
+            }
+            for mobile in mobiles {
+                writeln!(
+                    out,
+                    "{}\t{}\t{} cameras",
+                    mobile.id,
+                    mobile.name,
+                    mobile.cameras.len()
+                )?;
+            }
+        }
+
+        Command::ShowMobile { id } => {
+            let mobile = app_data.get_mobile(&id)?;
+            writeln!(out, "{}", serde_json::to_string_pretty(&mobile)?)?;
+        }
+
+        Command::Forget { id } => {
+            app_data.remove_mobile(&id)?;
+            writeln!(out, "Mobile {} forgotten", id)?;
+        }
+
+        Command::Activity => {
+            let activity: Vec<_> = app_data
+                .list_mobile_activity()?
+                .into_iter()
+                .map(|(id, activity)| MobileActivityInfo { id, activity })
+                .collect();
+            writeln!(out, "{}", serde_json::to_string_pretty(&activity)?)?;
+        }
+
+        Command::ListQuarantined => {
+            let quarantined = app_data.quarantined()?;
+            if quarantined.is_empty() {
+                writeln!(out, "No records quarantined")?;
+            }
+            for record in quarantined {
+                writeln!(
+                    out,
+                    "{}\t{}\t{} bytes\t{}",
+                    record.keyspace,
+                    record.key,
+                    record.record.len(),
+                    record.error
+                )?;
+            }
+        }
+
+        Command::DropQuarantined { keyspace, key } => {
+            if !app_data.drop_quarantined(&keyspace, &key)? {
+                return Err(anyhow!(
+                    "No record {} of {} quarantined",
+                    key,
+                    keyspace
+                ));
+            }
+            writeln!(
+                out,
+                "Quarantined record {} of {} dropped",
+                key, keyspace
+            )?;
+        }
+
+        Command::ShowHost => {
+            let host = app_data.get_host()?;
+            writeln!(out, "{}", serde_json::to_string_pretty(&host)?)?;
+        }
+
+        Command::ResetHost { name, yes } => {
+            if !yes {
+                return Err(anyhow!(
+                    "Run reset-host with --yes to remove every pairing"
+                ));
+            }
+
+            let name = match name {
+                Some(name) => name,
+                None => hostname::get()?
+                    .into_string()
+                    .map_err(|_| anyhow!("Invalid system hostname"))?,
+            };
+
+            let host = app_data.reset_host(&name)?;
+            writeln!(out, "Host reset, new host id {}", host.id)?;
+        }
+
+        Command::Export { file: None } => {
+            app_data.export_to(&mut *out)?;
+            writeln!(out)?;
+        }
+
+        Command::Export { file: Some(path) } => {
+            //the document allows to impersonate the host
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&path)?;
+            app_data.export_to(file)?;
+            writeln!(out, "Pairings exported to {}", path.display())?;
+        }
+
+        Command::Import { file, replace } => {
+            let mode =
+                if replace { ImportMode::Replace } else { ImportMode::Merge };
+            let host = app_data.import_from(File::open(&file)?, mode)?;
+            writeln!(
+                out,
+                "Imported host id {}, {} mobiles registered",
+                host.id,
+                host.registered_mobiles.len()
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_data::{
+        Codec, ConnectionType, DiskDb, HostInfo, MobileSchema, StorageBackend,
+    };
+
+    use std::os::unix::fs::PermissionsExt;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn reset_host() -> Command {
+        Command::ResetHost { name: None, yes: true }
+    }
+
+    fn run_to_string(
+        command: Command, app_data: &mut AppData<DiskDb>,
+    ) -> Result<String> {
+        let mut out = Vec::new();
+        run(command, app_data, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_db_commands() {
+        init_logger();
+        let dir = std::env::temp_dir()
+            .join(format!("wcd-db-cmd-{}", std::process::id()));
+
+        let db = DiskDb::open_from(&dir, StorageBackend::Sled, Codec::Bincode)
+            .unwrap();
+        let mut app_data = AppData::new(
+            db,
+            HostInfo {
+                name: "TestHost".to_string(),
+                connection_type: ConnectionType::WLAN,
+            },
+        )
+        .unwrap();
+        let host_id = app_data.get_host().unwrap().id;
+
+        app_data
+            .add_mobile(&MobileSchema {
+                id: "mobile_1".to_string(),
+                name: "Mobile1".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let out = run_to_string(Command::ListMobiles, &mut app_data).unwrap();
+        assert_eq!(out, "mobile_1\tMobile1\t0 cameras\n");
+
+        let out = run_to_string(
+            Command::ShowMobile { id: "mobile_1".to_string() },
+            &mut app_data,
+        )
+        .unwrap();
+        assert!(out.contains("\"name\": \"Mobile1\""));
+
+        let out = run_to_string(Command::Activity, &mut app_data).unwrap();
+        assert!(out.contains("\"id\": \"mobile_1\""));
+        assert!(out.contains("\"connection_count\": 0"));
+
+        let out = run_to_string(Command::ShowHost, &mut app_data).unwrap();
+        assert!(out.contains(&host_id));
+
+        let out =
+            run_to_string(Command::ListQuarantined, &mut app_data).unwrap();
+        assert_eq!(out, "No records quarantined\n");
+        assert!(run_to_string(
+            Command::DropQuarantined {
+                keyspace: "registered_mobiles".to_string(),
+                key: "mobile_1".to_string(),
+            },
+            &mut app_data,
+        )
+        .is_err());
+
+        run_to_string(
+            Command::Forget { id: "mobile_1".to_string() },
+            &mut app_data,
+        )
+        .unwrap();
+        let out = run_to_string(Command::ListMobiles, &mut app_data).unwrap();
+        assert_eq!(out, "No mobiles registered\n");
+        assert!(run_to_string(
+            Command::Forget { id: "mobile_1".to_string() },
+            &mut app_data,
+        )
+        .is_err());
+
+        //reset needs to be confirmed
+        let reset =
+            |yes| Command::ResetHost { name: Some("NewHost".to_string()), yes };
+        assert!(run_to_string(reset(false), &mut app_data).is_err());
+        assert_eq!(app_data.get_host().unwrap().id, host_id);
+
+        run_to_string(reset(true), &mut app_data).unwrap();
+        let host = app_data.get_host().unwrap();
+        assert_ne!(host.id, host_id);
+        assert_eq!(host.name, "NewHost");
+
+        drop(app_data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_import_commands() {
+        init_logger();
+        let dir = std::env::temp_dir()
+            .join(format!("wcd-db-cmd-backup-{}", std::process::id()));
+        let backup = dir.join("backup.json");
+
+        let mut app_data = AppData::new(
+            DiskDb::open_from(
+                dir.join("db"),
+                StorageBackend::Sled,
+                Codec::Bincode,
+            )
+            .unwrap(),
+            HostInfo {
+                name: "TestHost".to_string(),
+                connection_type: ConnectionType::WLAN,
+            },
+        )
+        .unwrap();
+        let host_id = app_data.get_host().unwrap().id;
+        app_data
+            .add_mobile(&MobileSchema {
+                id: "mobile_1".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let out = run_to_string(Command::Export { file: None }, &mut app_data)
+            .unwrap();
+        assert!(out.contains(&host_id));
+
+        run_to_string(
+            Command::Export { file: Some(backup.clone()) },
+            &mut app_data,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::metadata(&backup).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        run_to_string(reset_host(), &mut app_data).unwrap();
+        assert_ne!(app_data.get_host().unwrap().id, host_id);
+
+        let out = run_to_string(
+            Command::Import { file: backup, replace: true },
+            &mut app_data,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            format!("Imported host id {}, 1 mobiles registered\n", host_id)
+        );
+        assert!(app_data.get_mobile("mobile_1").is_ok());
+
+        drop(app_data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
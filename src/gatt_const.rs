@@ -7,6 +7,9 @@ pub const PROV_CHAR_HOST_INFO_UUID: Uuid =
     Uuid::from_u128(0x124ddac6b10746a0ade04ae8b2b700f5);
 pub const PROV_CHAR_MOBILE_INFO_UUID: Uuid =
     Uuid::from_u128(0x124ddac7b10746a0ade04ae8b2b700f5);
+//host info in binary frames, see ble::frame
+pub const PROV_CHAR_HOST_INFO_FRAMED_UUID: Uuid =
+    Uuid::from_u128(0x124ddacbb10746a0ade04ae8b2b700f5);
 
 //Webrtc SDP offer and answer
 pub const SDP_EXCHANGE_CHAR_UUID: Uuid =
@@ -1,20 +1,32 @@
 mod access_point_ctl;
 mod app_data;
 mod ble;
+mod cli;
+mod config;
+mod control;
+mod daemon;
+mod db_cmd;
 mod error;
 mod gatt_const;
+mod streaming;
+mod systemd;
 mod vdevice_builder;
 
 use access_point_ctl::{
-    dhcp_server::{DhcpIpRange, DnsmasqProc},
+    dhcp_server::DnsmasqProc,
     iw_link::{wdev_drv, IwLink},
     process_hdl::ProcessHdl,
-    wifi_manager::{
-        FileHdl, HostapdProc, WifiCredentials, WifiManager, WpaCtl,
-    },
-    AccessPointCtl, ApController,
+    wifi_manager::{FileHdl, HostapdProc, WifiManager, WpaCtl},
+    AccessPointCtl, ApController, SharedAp,
+};
+use app_data::{
+    AppData, AppDataWatcher, ConnectionType, DiskDb, EncryptedDb, HostInfo,
+    HostSchema, InMemoryDb, MobileActivity, MobileSchema,
 };
-use app_data::{AppData, ConnectionType, DiskBasedDb, HostInfo};
+use clap::Parser;
+use cli::Cli;
+use config::{AccessPointConfig, Config, DatabaseConfig, LEGACY_DB_PATH};
+use control::ControlServer;
 use error::Result;
 
 use ble::{
@@ -25,13 +37,26 @@ use ble::{
     ble_server::BleServer,
     AppDataStore, MobileComm,
 };
-use tokio::io::AsyncBufReadExt;
+use bluer::Adapter;
+use daemon::{ShutdownSignals, Teardown};
+use std::{
+    io,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{mpsc, watch};
 
-use log::info;
-use vdevice_builder::VDeviceBuilder;
+use log::{error, info};
+use streaming::webrtc_session::WebRtcSession;
+use systemd::SdNotifier;
+use vdevice_builder::{KernelModules, VDeviceBuilder};
 
-fn setup_access_point() -> Result<impl AccessPointCtl> {
-    let if_name = "wcdirect0";
+fn setup_access_point(
+    config: &AccessPointConfig,
+) -> Result<impl AccessPointCtl + Send + 'static> {
+    let if_name = config.if_name.as_str();
 
     //init the wireless interface handler---------
     let link = IwLink::new(wdev_drv::Nl80211Driver, if_name)?;
@@ -41,22 +66,19 @@ fn setup_access_point() -> Result<impl AccessPointCtl> {
 
     //wifi manager process
     let hostapd_proc = HostapdProc::new(
-        FileHdl::from_path("/tmp/hostapd.conf"),
+        FileHdl::from_path(&config.hostapd_conf),
         ProcessHdl::handler(),
     );
 
-    let wpactrl = WpaCtl::new("/tmp/hostapd", if_name);
+    let wpactrl = WpaCtl::new(&config.hostapd_ctrl_dir, if_name);
 
-    let creds = WifiCredentials {
-        ssid: "WebcamDirect".to_string(),
-        password: "12345678".to_string(),
-    };
-
-    let wifi_manager = WifiManager::new(&creds, hostapd_proc, wpactrl)?;
+    let wifi_manager =
+        WifiManager::new(&config.creds(), hostapd_proc, wpactrl)?;
 
-    let mut ap = ApController::new(link, dhcp_server_proc, wifi_manager);
+    let mut ap = ApController::new(link, dhcp_server_proc, wifi_manager)
+        .with_creds(config.creds());
 
-    ap.start_dhcp_server(DhcpIpRange::new("193.168.3.5", "193.168.3.150")?)?;
+    ap.start_dhcp_server(config.dhcp_range()?)?;
 
     ap.start_wifi()?;
 
@@ -64,68 +86,412 @@ fn setup_access_point() -> Result<impl AccessPointCtl> {
     Ok(ap)
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
+//open the database, migrating the one used by the older versions
+fn open_database(config: &DatabaseConfig) -> Result<DiskDb> {
+    let disk_db =
+        DiskDb::open_from(&config.path, config.backend, config.codec)?;
 
-    info!("Starting webcam direct");
+    if let Err(e) = disk_db.migrate_from(LEGACY_DB_PATH) {
+        error!("Failed to migrate the legacy database, error: {:?}", e);
+    }
 
-    //get host name
-    let mut host_info = HostInfo {
-        name: "MyPC".to_string(),
-        connection_type: ConnectionType::WLAN,
-    };
+    Ok(disk_db)
+}
 
-    if let Ok(host_name) = hostname::get()?.into_string() {
-        host_info.name = host_name;
+fn open_plain_database(config: &DatabaseConfig) -> Result<DiskDb> {
+    let disk_db = open_database(config)?;
+
+    disk_db.upgrade_keyspace::<HostSchema>()?;
+    disk_db.upgrade_keyspace::<MobileSchema>()?;
+    disk_db.upgrade_keyspace::<MobileActivity>()?;
+
+    Ok(disk_db)
+}
+
+//sealing the records also upgrades them, the plain records are only sealed
+//the first time
+fn open_encrypted_database(
+    config: &DatabaseConfig,
+) -> Result<EncryptedDb<DiskDb>> {
+    let db = EncryptedDb::open(open_database(config)?, &config.key_file)?;
+
+    db.seal_keyspace::<HostSchema>()?;
+    db.seal_keyspace::<MobileSchema>()?;
+    db.seal_keyspace::<MobileActivity>()?;
+    db.mark_sealed()?;
+
+    Ok(db)
+}
+
+//disconnect every mobile still connected to the host
+async fn disconnect_mobiles(
+    adapter: &Adapter, ble_server: &BleServer,
+) -> Result<()> {
+    let mut failed = 0;
+
+    for addr in ble_server.connected_mobiles().await? {
+        info!("Disconnecting mobile {}", addr);
+        let device = match addr.parse() {
+            Ok(addr) => adapter.device(addr),
+            Err(e) => {
+                error!("Invalid mobile address {}, error: {:?}", addr, e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        let disconnected = match device {
+            Ok(device) => device.disconnect().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = disconnected {
+            error!("Failed to disconnect mobile {}, error: {:?}", addr, e);
+            failed += 1;
+        }
     }
 
-    let ap_controller_rc = setup_access_point();
-    if ap_controller_rc.is_ok() {
-        host_info.connection_type = ConnectionType::AP;
+    if failed > 0 {
+        return Err(anyhow::anyhow!("Failed to disconnect {} mobiles", failed));
     }
 
+    Ok(())
+}
+
+//the components serving the mobiles, stopped in order on shutdown
+struct Services {
+    adapter: Adapter,
+    ble_server: BleServer,
+    control_server: Option<ControlServer>,
+    provisioner: ProvisionerClient,
+    mobile_prop_client: MobilePropClient,
+    sdp_exchanger: SdpExchangerClient,
+}
+
+//the channels feeding the background tasks of the daemon
+struct Feeds {
+    data_events: AppDataWatcher,
+    host_name_tx: watch::Sender<String>,
+    new_vdevices: mpsc::UnboundedReceiver<PathBuf>,
+}
+
+//starts every component serving the mobiles
+async fn start_services(
+    config: &Config, host_info: HostInfo, ap_controller: Option<SharedAp>,
+) -> Result<(Services, Feeds)> {
     let session = bluer::Session::new().await?;
 
     let adapter = session.default_adapter().await?;
 
     adapter.set_powered(true).await?;
 
-    //init the in disk database
-    let config_path = "/tmp";
-
-    let disk_db = DiskBasedDb::open_from(config_path)?;
+    //init the database, the ephemeral one forgets the pairings on exit
+    let mut app_data: Box<dyn AppDataStore> = if config.database.ephemeral {
+        Box::new(AppData::new(InMemoryDb::new(), host_info.clone())?)
+    } else if config.database.encrypt {
+        let db = open_encrypted_database(&config.database)?;
+        Box::new(AppData::new(db, host_info.clone())?)
+    } else {
+        let db = open_plain_database(&config.database)?;
+        Box::new(AppData::new(db, host_info.clone())?)
+    };
 
-    let app_data = AppData::new(disk_db, host_info.clone())?;
+    //the hostname may have changed while the daemon was stopped
+    if config.daemon.follow_hostname {
+        if let Some(name) = daemon::system_hostname() {
+            app_data.rename_host(&name)?;
+        }
+    }
 
     let host_prov_info = app_data.get_host_prov_info()?;
+    let data_events = app_data.watch()?;
+    let (host_name_tx, host_name) = watch::channel(host_prov_info.name.clone());
+
+    let (new_vdevices_tx, new_vdevices) = mpsc::unbounded_channel();
+    let mobile_comm = MobileComm::new(app_data, VDeviceBuilder::new())?
+        .with_streaming(new_vdevices_tx)
+        .with_deadlines(config.ble.deadlines());
+
+    let ble_server =
+        BleServer::new(mobile_comm, ap_controller, config.ble.req_buffer_size);
+
+    let control_server = match config
+        .control
+        .socket_path()
+        .and_then(|path| ControlServer::bind(path, ble_server.connection()))
+    {
+        Ok(control_server) => Some(control_server),
+        Err(e) => {
+            error!("Control socket not available, error: {:?}", e);
+            None
+        }
+    };
 
-    let mobile_comm = MobileComm::new(app_data, VDeviceBuilder::new().await?)?;
-
-    let ble_server = BleServer::new(mobile_comm, 512);
-
-    let _provisioner = ProvisionerClient::new(
+    let provisioner = ProvisionerClient::new(
         adapter.clone(),
         ble_server.connection(),
-        host_prov_info.name.clone(),
+        host_name.clone(),
     );
 
-    let _mobile_prop_client =
+    let mobile_prop_client =
         MobilePropClient::new(adapter.clone(), ble_server.connection());
 
-    let _sdp_exchanger = SdpExchangerClient::new(
+    let sdp_exchanger = SdpExchangerClient::new(
         adapter.clone(),
         ble_server.connection(),
-        host_prov_info.name.clone(),
+        host_name,
         host_prov_info.id,
     );
 
-    info!("Service ready. Press enter to quit.");
-    let stdin = tokio::io::BufReader::new(tokio::io::stdin());
-    let mut lines = stdin.lines();
-    let _ = lines.next_line().await;
+    let services = Services {
+        adapter,
+        ble_server,
+        control_server,
+        provisioner,
+        mobile_prop_client,
+        sdp_exchanger,
+    };
+
+    Ok((services, Feeds { data_events, host_name_tx, new_vdevices }))
+}
+
+//reports the daemon ready and runs the background tasks until a shutdown
+//signal is received
+async fn serve(
+    config: &Config, notifier: &Arc<SdNotifier>, services: &mut Services,
+    ap_controller: Option<SharedAp>, feeds: Feeds,
+    signals: &mut ShutdownSignals,
+) -> Result<()> {
+    //only report ready once every client is serving
+    let ready = async {
+        services.provisioner.wait_ready().await?;
+        services.sdp_exchanger.wait_ready().await?;
+        services.mobile_prop_client.wait_ready().await
+    };
+    tokio::select! {
+        ready = ready => ready?,
+        _ = signals.recv() => return Ok(()),
+    }
+
+    info!("Service ready");
+    notifier.ready()?;
+
+    let ble_server = &services.ble_server;
+    let mut tasks = vec![
+        tokio::spawn(daemon::health_task(
+            notifier.clone(),
+            ble_server.connection(),
+            ap_controller,
+            systemd::watchdog_interval(),
+        )),
+        tokio::spawn(daemon::data_events_task(
+            ble_server.connection(),
+            feeds.data_events,
+            feeds.host_name_tx,
+        )),
+        tokio::spawn(streaming::streaming_task(
+            ble_server.connection(),
+            feeds.new_vdevices,
+            |vdevice| async move { WebRtcSession::new(&vdevice).await },
+        )),
+    ];
+
+    let policy = config.retention.policy();
+    if !policy.is_disabled() {
+        tasks.push(tokio::spawn(daemon::retention_task(
+            ble_server.connection(),
+            policy,
+            Duration::from_secs(config.retention.check_interval_secs),
+        )));
+    }
+
+    if config.daemon.follow_hostname {
+        tasks.push(tokio::spawn(daemon::hostname_task(
+            ble_server.connection(),
+            Duration::from_secs(config.daemon.hostname_check_interval_secs),
+        )));
+    }
 
-    info!("webcam direct stopped stopped");
+    signals.recv().await;
+    for task in tasks {
+        task.abort();
+    }
 
     Ok(())
 }
+
+//stops the components serving the mobiles
+async fn stop_services(services: Services, teardown: &mut Teardown) {
+    let Services {
+        adapter,
+        ble_server,
+        control_server,
+        provisioner,
+        mobile_prop_client,
+        sdp_exchanger,
+    } = services;
+
+    if let Some(control_server) = control_server {
+        teardown.step("stop control socket", control_server.stop()).await;
+    }
+    teardown.step("stop provisioner advertising", provisioner.stop()).await;
+    teardown.step("stop sdp exchanger advertising", sdp_exchanger.stop()).await;
+    teardown
+        .step("disconnect mobiles", disconnect_mobiles(&adapter, &ble_server))
+        .await;
+    teardown.step("stop mobile watcher", mobile_prop_client.stop()).await;
+    teardown.step("release virtual devices", ble_server.shutdown()).await;
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    env_logger::init();
+
+    let cli = Cli::parse();
+    let config = Config::load(&cli)?;
+
+    if let Some(command) = cli.command {
+        if config.database.ephemeral {
+            return Err(anyhow::anyhow!(
+                "Database commands can't run on an ephemeral database"
+            ));
+        }
+        if config.database.encrypt {
+            let db = open_encrypted_database(&config.database)?;
+            db_cmd::run(command, &mut AppData::open(db), &mut io::stdout())?;
+        } else {
+            let db = open_plain_database(&config.database)?;
+            db_cmd::run(command, &mut AppData::open(db), &mut io::stdout())?;
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    //a signal received while starting leads to the teardown as well
+    let mut signals = ShutdownSignals::install()?;
+    let notifier = Arc::new(SdNotifier::from_env()?);
+
+    info!("Starting webcam direct");
+
+    //get host name
+    let mut host_info = HostInfo {
+        name: "MyPC".to_string(),
+        connection_type: ConnectionType::WLAN,
+    };
+
+    if let Ok(host_name) = hostname::get()?.into_string() {
+        host_info.name = host_name;
+    }
+
+    let ap_controller: Option<SharedAp> =
+        match setup_access_point(&config.access_point) {
+            Ok(ap) => {
+                host_info.connection_type = ConnectionType::AP;
+                Some(Arc::new(Mutex::new(ap)))
+            }
+            Err(e) => {
+                error!("Access point not available, error: {:?}", e);
+                None
+            }
+        };
+
+    //from here on every failure goes through the teardown, the access point
+    //and the kernel modules are left behind otherwise
+    let mut kernel_modules = None;
+    let mut failed = false;
+
+    //the modules are loaded before the start up can be interrupted, a module
+    //loaded meanwhile would be left behind
+    let started = match KernelModules::load().await {
+        Ok(modules) => {
+            kernel_modules = Some(modules);
+            tokio::select! {
+                started = start_services(
+                    &config,
+                    host_info,
+                    ap_controller.clone(),
+                ) => Some(started),
+                _ = signals.recv() => None,
+            }
+        }
+        Err(e) => Some(Err(e)),
+    };
+
+    let services = match started {
+        Some(Ok((mut services, feeds))) => {
+            let served = serve(
+                &config,
+                &notifier,
+                &mut services,
+                ap_controller.clone(),
+                feeds,
+                &mut signals,
+            )
+            .await;
+            if let Err(e) = served {
+                error!("webcam direct failed, error: {:?}", e);
+                failed = true;
+            }
+            Some(services)
+        }
+        Some(Err(e)) => {
+            error!("Failed to start webcam direct, error: {:?}", e);
+            failed = true;
+            None
+        }
+        None => {
+            info!("Start up interrupted");
+            None
+        }
+    };
+
+    info!("Stopping webcam direct");
+    if let Err(e) = notifier.stopping() {
+        error!("Failed to notify stopping, error: {:?}", e);
+    }
+
+    let mut teardown = Teardown::new(Duration::from_secs(
+        config.daemon.shutdown_step_timeout_secs,
+    ));
+
+    if let Some(services) = services {
+        stop_services(services, &mut teardown).await;
+    }
+
+    if let Some(ap_controller) = ap_controller {
+        teardown
+            .step("shut down access point", async move {
+                tokio::task::spawn_blocking(move || {
+                    ap_controller
+                        .lock()
+                        .map_err(|_| anyhow::anyhow!("Access point poisoned"))?
+                        .shutdown()
+                })
+                .await?
+            })
+            .await;
+    }
+
+    if let Some(mut kernel_modules) = kernel_modules {
+        teardown
+            .step("unload kernel modules", async move {
+                tokio::task::spawn_blocking(move || kernel_modules.unload())
+                    .await?
+            })
+            .await;
+    }
+
+    if failed {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    if !teardown.failed_steps().is_empty() {
+        error!(
+            "webcam direct stopped with failed teardown steps: {}",
+            teardown.failed_steps().join(", ")
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+
+    info!("webcam direct stopped");
+
+    Ok(ExitCode::SUCCESS)
+}
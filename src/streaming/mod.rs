@@ -0,0 +1,217 @@
+//! Streaming sessions of the virtual devices.
+//!
+//! Once a mobile is ready to stream, `streaming_task` receives the path of
+//! each of its virtual devices and starts a session for it. The host makes
+//! the SDP offer, the BLE server publishes it to the mobile of the virtual
+//! device and the answer the mobile writes back is handed to the session that
+//! made the offer. A session lives until its connection is closed.
+
+pub mod webrtc_session;
+
+use std::{future::Future, path::PathBuf, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use log::{error, info};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinSet,
+};
+
+#[cfg(test)]
+use mockall::automock;
+
+use crate::ble::{ble_server::ServerConn, BleApi, PubSubTopic, SdpOffer};
+use crate::error::Result;
+
+/// Time given to a mobile to answer an offer.
+pub const ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A streaming session of one virtual device.
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub trait StreamSession: Send + Sync + 'static {
+    /// Creates the SDP offer sent to the mobile.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the offer can't be created.
+    async fn create_offer(&self) -> Result<String>;
+
+    /// Starts streaming with the SDP answer of the mobile.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the answer is not valid for the offer.
+    async fn accept_answer(&self, answer: String) -> Result<()>;
+
+    /// Waits until the session is closed.
+    async fn closed(&self);
+}
+
+/// Offers the session to the mobile of the virtual device and accepts its
+/// answer.
+///
+/// # Errors
+///
+/// Returns an error if the offer can't be published, if the mobile doesn't
+/// answer within `timeout` or if its answer is rejected by the session.
+pub async fn offer_to_mobile(
+    server_conn: &ServerConn, vdevice: PathBuf, session: &dyn StreamSession,
+    timeout: Duration,
+) -> Result<()> {
+    let offer = session.create_offer().await?;
+
+    let (resp, rx) = oneshot::channel();
+    server_conn
+        .send(BleApi::Publish(
+            PubSubTopic::SdpCall,
+            SdpOffer { vdevice, payload: offer.into_bytes(), resp },
+        ))
+        .await
+        .map_err(|_| anyhow!("BleServer task is not running"))?;
+    let answer = rx.await??;
+
+    let answer = tokio::time::timeout(timeout, answer)
+        .await
+        .map_err(|_| anyhow!("The mobile didn't answer in {:?}", timeout))?
+        .map_err(|_| anyhow!("The mobile left before answering"))?;
+
+    session.accept_answer(answer).await
+}
+
+/// Starts a streaming session for every virtual device received, sessions are
+/// dropped when they are closed or when the task is aborted.
+pub async fn streaming_task<S, F, Fut>(
+    server_conn: ServerConn,
+    mut new_vdevices: mpsc::UnboundedReceiver<PathBuf>, new_session: F,
+) where
+    S: StreamSession,
+    F: Fn(PathBuf) -> Fut,
+    Fut: Future<Output = Result<S>>,
+{
+    let mut sessions = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            vdevice = new_vdevices.recv() => {
+                let Some(vdevice) = vdevice else {
+                    break;
+                };
+
+                let session = match new_session(vdevice.clone()).await {
+                    Ok(session) => session,
+                    Err(e) => {
+                        error!(
+                            "Failed to create a session for {:?}, error: {:?}",
+                            vdevice, e
+                        );
+                        continue;
+                    }
+                };
+
+                let server_conn = server_conn.clone();
+                sessions.spawn(async move {
+                    if let Err(e) = offer_to_mobile(
+                        &server_conn,
+                        vdevice.clone(),
+                        &session,
+                        ANSWER_TIMEOUT,
+                    )
+                    .await
+                    {
+                        error!(
+                            "Failed to start streaming {:?}, error: {:?}",
+                            vdevice, e
+                        );
+                        return;
+                    }
+
+                    info!("Streaming {:?}", vdevice);
+                    session.closed().await;
+                    info!("Streaming session of {:?} closed", vdevice);
+                });
+            }
+
+            Some(_) = sessions.join_next() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::ble_server::{BleServer, MockMultiMobileCommService};
+    use mockall::predicate::eq;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[tokio::test]
+    async fn test_offer_to_mobile_accepts_answer() {
+        init_logger();
+        let mut mock_comm = MockMultiMobileCommService::new();
+        mock_comm
+            .expect_offer_sdp()
+            .with(eq(PathBuf::from("/dev/video2")), eq(b"offer".to_vec()))
+            .times(1)
+            .returning(|_, _| {
+                let (tx, answer) = oneshot::channel();
+                tx.send("answer".to_string()).unwrap();
+                Ok(answer)
+            });
+        let ble_server = BleServer::new(mock_comm, None, 4);
+
+        let mut session = MockStreamSession::new();
+        session
+            .expect_create_offer()
+            .times(1)
+            .returning(|| Ok("offer".to_string()));
+        session
+            .expect_accept_answer()
+            .with(eq("answer".to_string()))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        offer_to_mobile(
+            &ble_server.connection(),
+            PathBuf::from("/dev/video2"),
+            &session,
+            ANSWER_TIMEOUT,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_offer_to_mobile_times_out_without_answer() {
+        init_logger();
+        let (answer_tx, answer) = oneshot::channel::<String>();
+        let mut answer = Some(answer);
+        let mut mock_comm = MockMultiMobileCommService::new();
+        mock_comm
+            .expect_offer_sdp()
+            .times(1)
+            .returning(move |_, _| Ok(answer.take().unwrap()));
+        let ble_server = BleServer::new(mock_comm, None, 4);
+
+        let mut session = MockStreamSession::new();
+        session
+            .expect_create_offer()
+            .times(1)
+            .returning(|| Ok("offer".to_string()));
+        session.expect_accept_answer().never();
+
+        let res = offer_to_mobile(
+            &ble_server.connection(),
+            PathBuf::from("/dev/video2"),
+            &session,
+            Duration::from_millis(50),
+        )
+        .await;
+
+        assert!(res.is_err());
+        drop(answer_tx);
+    }
+}
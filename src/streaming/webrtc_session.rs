@@ -0,0 +1,203 @@
+//! WebRTC session writing the H264 video of the mobile to a virtual device.
+
+use std::{fs::OpenOptions, path::Path, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use log::{error, info};
+use tokio::sync::{watch, Mutex};
+use v4l::{video::Output, Device, Format, FourCC};
+use webrtc::{
+    api::{
+        interceptor_registry::register_default_interceptors,
+        media_engine::{MediaEngine, MIME_TYPE_H264},
+        APIBuilder,
+    },
+    interceptor::registry::Registry,
+    media::io::{h264_writer::H264Writer, Writer},
+    peer_connection::{
+        configuration::RTCConfiguration,
+        peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
+    rtp_transceiver::{
+        rtp_codec::{
+            RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
+        },
+        rtp_transceiver_direction::RTCRtpTransceiverDirection,
+        RTCRtpTransceiverInit,
+    },
+    track::track_remote::TrackRemote,
+};
+
+use super::StreamSession;
+use crate::error::Result;
+
+/// Payload type of the H264 codec offered to the mobile.
+const H264_PAYLOAD_TYPE: u8 = 102;
+
+/// Period of the keyframe requests sent to the mobile.
+const PLI_INTERVAL: Duration = Duration::from_secs(3);
+
+type SharedWriter = Arc<Mutex<dyn Writer + Send + Sync>>;
+
+/// Receives the video of the mobile and writes it to a virtual device.
+pub struct WebRtcSession {
+    peer_connection: Arc<RTCPeerConnection>,
+    closed: watch::Receiver<bool>,
+}
+
+impl WebRtcSession {
+    /// Creates a session receiving an H264 video track into `vdevice`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the virtual device can't be opened or if the peer
+    /// connection can't be created.
+    pub async fn new(vdevice: &Path) -> Result<Self> {
+        let device = Device::with_path(vdevice)?;
+        device.set_format(&Format::new(640, 480, FourCC::new(b"H264")))?;
+
+        let file = OpenOptions::new().write(true).open(vdevice)?;
+        let writer: SharedWriter = Arc::new(Mutex::new(H264Writer::new(file)));
+
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_H264.to_owned(),
+                    clock_rate: 90000,
+                    ..Default::default()
+                },
+                payload_type: H264_PAYLOAD_TYPE,
+                ..Default::default()
+            },
+            RTPCodecType::Video,
+        )?;
+
+        let registry =
+            register_default_interceptors(Registry::new(), &mut media_engine)?;
+
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .build();
+
+        let peer_connection = Arc::new(
+            api.new_peer_connection(RTCConfiguration::default()).await?,
+        );
+
+        peer_connection
+            .add_transceiver_from_kind(
+                RTPCodecType::Video,
+                Some(RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Recvonly,
+                    send_encodings: vec![],
+                }),
+            )
+            .await?;
+
+        let pc = Arc::downgrade(&peer_connection);
+        peer_connection.on_track(Box::new(move |track, _, _| {
+            let pc = pc.clone();
+            let writer = writer.clone();
+            Box::pin(async move {
+                let mime_type = track.codec().capability.mime_type;
+                if !mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+                    error!("Ignoring track with codec {}", mime_type);
+                    return;
+                }
+
+                //ask for keyframes so the video can be decoded from any point
+                let media_ssrc = track.ssrc();
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(PLI_INTERVAL).await;
+                        let Some(pc) = pc.upgrade() else {
+                            break;
+                        };
+                        let pli = PictureLossIndication {
+                            sender_ssrc: 0,
+                            media_ssrc,
+                        };
+                        if pc.write_rtcp(&[Box::new(pli)]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+
+                tokio::spawn(write_track(writer, track));
+            })
+        }));
+
+        let (closed_tx, closed) = watch::channel(false);
+        peer_connection.on_peer_connection_state_change(Box::new(
+            move |state: RTCPeerConnectionState| {
+                info!("Peer connection state changed to {}", state);
+
+                if matches!(
+                    state,
+                    RTCPeerConnectionState::Failed
+                        | RTCPeerConnectionState::Disconnected
+                        | RTCPeerConnectionState::Closed
+                ) {
+                    closed_tx.send_replace(true);
+                }
+                Box::pin(async {})
+            },
+        ));
+
+        Ok(Self { peer_connection, closed })
+    }
+}
+
+//write the rtp packets of the track until it ends
+async fn write_track(writer: SharedWriter, track: Arc<TrackRemote>) {
+    while let Ok((packet, _)) = track.read_rtp().await {
+        if let Err(e) = writer.lock().await.write_rtp(&packet) {
+            error!("Failed to write the video, error: {:?}", e);
+            break;
+        }
+    }
+
+    if let Err(e) = writer.lock().await.close() {
+        error!("Failed to close the video writer, error: {:?}", e);
+    }
+}
+
+#[async_trait]
+impl StreamSession for WebRtcSession {
+    async fn create_offer(&self) -> Result<String> {
+        let offer = self.peer_connection.create_offer(None).await?;
+
+        //the candidates are sent within the offer, there is no trickle ICE
+        let mut gathering_complete =
+            self.peer_connection.gathering_complete_promise().await;
+        self.peer_connection.set_local_description(offer).await?;
+        let _ = gathering_complete.recv().await;
+
+        let offer = self
+            .peer_connection
+            .local_description()
+            .await
+            .ok_or_else(|| anyhow!("The offer has no local description"))?;
+
+        Ok(serde_json::to_string(&offer)?)
+    }
+
+    async fn accept_answer(&self, answer: String) -> Result<()> {
+        let answer = serde_json::from_str::<RTCSessionDescription>(&answer)?;
+        self.peer_connection.set_remote_description(answer).await?;
+        Ok(())
+    }
+
+    async fn closed(&self) {
+        let mut closed = self.closed.clone();
+        let _ = closed.wait_for(|closed| *closed).await;
+
+        if let Err(e) = self.peer_connection.close().await {
+            error!("Failed to close the peer connection, error: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Minimal implementation of the systemd notification protocol.
+//!
+//! When the daemon runs as a `Type=notify` unit, systemd passes the path of a
+//! datagram socket in `NOTIFY_SOCKET` and, if the watchdog is enabled, the
+//! expected ping interval in `WATCHDOG_USEC`. Outside of systemd every
+//! notification is silently skipped.
+
+use std::{
+    env,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    path::Path,
+    time::Duration,
+};
+
+use log::{debug, info};
+
+use crate::error::Result;
+
+/// Sends state notifications to the service manager.
+pub struct SdNotifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+}
+
+impl SdNotifier {
+    /// Creates a notifier from the `NOTIFY_SOCKET` environment variable.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the variable is set but the socket can't be used.
+    pub fn from_env() -> Result<Self> {
+        match env::var_os("NOTIFY_SOCKET") {
+            Some(path) => {
+                info!("systemd notify socket: {:?}", path);
+                Self::with_socket_path(path)
+            }
+            None => Ok(Self { socket: None }),
+        }
+    }
+
+    /// Creates a notifier that sends to the given socket path, a leading `@`
+    /// refers to the abstract namespace.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket can't be created.
+    pub fn with_socket_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+
+        let addr = match path.to_str().and_then(|p| p.strip_prefix('@')) {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+
+        Ok(Self { socket: Some((UnixDatagram::unbound()?, addr)) })
+    }
+
+    /// Sends a raw notification, a no-op when not running under systemd.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the datagram can't be sent.
+    pub fn notify(&self, state: &str) -> Result<()> {
+        if let Some((socket, addr)) = &self.socket {
+            debug!("systemd notify: {}", state);
+            socket.send_to_addr(state.as_bytes(), addr)?;
+        }
+        Ok(())
+    }
+
+    /// Tells systemd that the start up is finished.
+    pub fn ready(&self) -> Result<()> {
+        self.notify("READY=1")
+    }
+
+    /// Tells systemd that the daemon is shutting down.
+    pub fn stopping(&self) -> Result<()> {
+        self.notify("STOPPING=1")
+    }
+
+    /// Updates the free form status shown by `systemctl status`.
+    pub fn status(&self, status: &str) -> Result<()> {
+        self.notify(&format!("STATUS={}", status))
+    }
+
+    /// Pings the systemd watchdog.
+    pub fn watchdog(&self) -> Result<()> {
+        self.notify("WATCHDOG=1")
+    }
+}
+
+/// Returns the interval in which systemd expects a watchdog ping, if the
+/// watchdog is enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog_interval(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+//the watchdog settings only apply to the process in WATCHDOG_PID, if set
+fn parse_watchdog_interval(
+    usec: Option<&str>, pid: Option<&str>, own_pid: u32,
+) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+
+    match usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_without_socket() {
+        let notifier = SdNotifier { socket: None };
+        assert!(notifier.ready().is_ok());
+    }
+
+    #[test]
+    fn test_notify_sends_state() {
+        let dir =
+            env::temp_dir().join(format!("sd-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let notifier = SdNotifier::with_socket_path(&path).unwrap();
+
+        notifier.ready().unwrap();
+        notifier.status("2 mobiles connected, 1 streaming").unwrap();
+
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STATUS=2 mobiles connected, 1 streaming");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_watchdog_interval() {
+        assert_eq!(
+            parse_watchdog_interval(Some("2000000"), None, 10),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            parse_watchdog_interval(Some("2000000"), Some("10"), 10),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            parse_watchdog_interval(Some("2000000"), Some("11"), 10),
+            None
+        );
+        assert_eq!(parse_watchdog_interval(Some("0"), None, 10), None);
+        assert_eq!(parse_watchdog_interval(None, None, 10), None);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::app_data::MobileSchema;
 use crate::ble::{VDeviceBuilderOps, VDeviceMap};
 use crate::error::Result;
+use anyhow::anyhow;
 use async_trait::async_trait;
 use log::{error, info};
 use std::path::{Path, PathBuf};
@@ -12,29 +13,82 @@ mod vdevice;
 
 pub use vdevice::VDevice;
 
-pub struct VDeviceBuilder {
-    //flags to set up the system at beginning and tear down at the end
-    is_v4l2loopback_loaded: bool,
-    is_videodev_loaded: bool,
+/// Kernel modules required by the virtual devices.
+///
+/// Only the modules loaded by this instance are unloaded, modules that were
+/// already loaded by the system are left untouched.
+pub struct KernelModules {
+    //modules loaded by us, in load order
+    loaded: Vec<&'static str>,
 }
 
-impl VDeviceBuilder {
-    pub async fn new() -> Result<Self> {
-        let mut is_v4l2loopback_loaded = false;
-        let mut is_videodev_loaded = false;
+impl KernelModules {
+    /// Loads the `videodev` and `v4l2loopback` modules if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a module can't be loaded.
+    pub async fn load() -> Result<Self> {
+        let mut modules = Self { loaded: Vec::new() };
+
         //check for videodev module
         if !is_kmodule_loaded("/proc/modules", "videodev").await? {
-            is_v4l2loopback_loaded = true;
             load_kmodule("videodev", None).await?;
+            modules.loaded.push("videodev");
         }
 
         //check for v4l2loopback module
         if !is_kmodule_loaded("/proc/modules", "v4l2loopback").await? {
-            is_videodev_loaded = true;
             load_kmodule("v4l2loopback", Some(&["exclusive_caps=1"])).await?;
+            modules.loaded.push("v4l2loopback");
+        }
+
+        Ok(modules)
+    }
+
+    /// Unloads the modules loaded by `load`, in reverse order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a module can't be unloaded, the remaining modules
+    /// are still unloaded.
+    pub fn unload(&mut self) -> Result<()> {
+        let mut failed = Vec::new();
+
+        while let Some(module) = self.loaded.pop() {
+            info!("Unloading kernel module {}", module);
+            if unload_kmodule(module).is_err() {
+                error!("Failed to unload {} module", module);
+                failed.push(module);
+            }
+        }
+
+        if !failed.is_empty() {
+            return Err(anyhow!(
+                "Failed to unload kernel modules: {}",
+                failed.join(", ")
+            ));
         }
 
-        Ok(Self { is_v4l2loopback_loaded, is_videodev_loaded })
+        Ok(())
+    }
+}
+
+impl Drop for KernelModules {
+    fn drop(&mut self) {
+        //unload the modules if not done explicitly
+        if let Err(e) = self.unload() {
+            error!("{}", e);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct VDeviceBuilder;
+
+impl VDeviceBuilder {
+    pub fn new() -> Self {
+        Self
     }
 }
 
@@ -63,21 +117,6 @@ impl VDeviceBuilderOps for VDeviceBuilder {
     }
 }
 
-impl Drop for VDeviceBuilder {
-    fn drop(&mut self) {
-        //unload the modules
-        if self.is_v4l2loopback_loaded
-            && unload_kmodule("v4l2loopback").is_err()
-        {
-            error!("Failed to unload v4l2loopback module");
-        }
-
-        if self.is_videodev_loaded && unload_kmodule("videodev").is_err() {
-            error!("Failed to unload videodev module");
-        }
-    }
-}
-
 //utility function to check if a kernel module is loaded
 async fn is_kmodule_loaded<P>(
     reg_module_file: P, module_name: &str,